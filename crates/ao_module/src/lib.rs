@@ -1,5 +1,5 @@
 mod model;
 
-pub use model::ArweaveTimestamp;
+pub use model::{ArweaveAddress, ArweaveTimestamp, Message};
 
 // TODO: Message Parsing, replying etc
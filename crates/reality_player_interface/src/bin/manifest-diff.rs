@@ -0,0 +1,54 @@
+// Prints the differences between two `PlayerInterfaceManifest` JSON files
+// Exits with a non-zero status if the running manifest is not compatible with the expected one
+//
+// Usage: manifest-diff <expected.json> <running.json>
+
+use std::process::ExitCode;
+
+use reality_player_interface::{ManifestHandshake, PlayerInterfaceManifest};
+use serde_json::Value;
+
+fn read_manifest(path: &str) -> Result<PlayerInterfaceManifest<Value, Value>, String> {
+    let file = std::fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+    serde_json::from_str(&file).map_err(|err| format!("failed to parse {path}: {err}"))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, expected_path, running_path] = args.as_slice() else {
+        eprintln!("Usage: manifest-diff <expected.json> <running.json>");
+        return ExitCode::from(2);
+    };
+
+    let (expected, running) = match (read_manifest(expected_path), read_manifest(running_path)) {
+        (Ok(expected), Ok(running)) => (expected, running),
+        (Err(err), _) | (_, Err(err)) => {
+            eprintln!("{err}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let handshake = match ManifestHandshake::negotiate(&expected, &running) {
+        Ok(handshake) => handshake,
+        Err(err) => {
+            eprintln!("failed to compare the manifests: {err}");
+            return ExitCode::from(2);
+        }
+    };
+    print!("{}", handshake.diff());
+
+    match handshake {
+        ManifestHandshake::Accepted(_) => {
+            println!("compatible");
+            ExitCode::SUCCESS
+        }
+        ManifestHandshake::Degraded(_) => {
+            println!("breaking changes, degraded");
+            ExitCode::FAILURE
+        }
+        ManifestHandshake::Refused(_) => {
+            println!("incompatible");
+            ExitCode::FAILURE
+        }
+    }
+}
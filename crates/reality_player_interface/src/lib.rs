@@ -3,12 +3,21 @@ mod model;
 mod system;
 mod plugin;
 
-pub use model::keyboard::{KeyCode, KeyboardConfig};
+pub use model::player::PlayerId;
+pub use model::keyboard::{KeyCode, KeyboardConfig, KeyboardConfigs};
 pub use model::custom_types::{CustomGameTrait, GameAction, GameEvent};
 pub use model::manifest::{ActionDescriptor, EventDescriptor, PlayerInterfaceManifest};
-pub use model::game_update::{UpdateInfo, GameUpdateEventTimed, MediaType, MediaRef, PlayerInterfaceGameUpdate};
+pub use model::compatibility::{ManifestChange, ManifestDiff, ManifestHandshake, ManifestHandshakeRequest, ManifestHandshakeResult};
+pub use model::game_update::{UpdateInfo, GameUpdateEventTimed, MediaType, MediaRef, PlayerInterfaceGameUpdate, RoutedGameUpdate, UpdateRecipient};
 pub use model::player_update::{GameInputEvent, InputEventType};
+pub use model::validation::{ActionRejection, ActionRejectionReason, ActionValidator, GameInputRequest};
+pub use model::rate_limit::InputRateLimiter;
+pub use model::text_frame::{LatestTextFrame, TextFrame, TextFrameConfig, TextFrameGlyph, TextFrameProjection};
 
 pub use system::bevy_keycode_to_action::bevy_keycode_to_action;
+pub use system::render_text_frame::render_text_frame;
+pub use system::validate_game_input::{log_action_rejections, negotiate_manifests, observe_game_updates, validate_game_input};
 
 pub use plugin::reality_input::RealityInputPlugin;
+pub use plugin::text_frame::TextFramePlugin;
+pub use plugin::action_validation::ActionValidationPlugin;
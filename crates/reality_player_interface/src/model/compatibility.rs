@@ -0,0 +1,428 @@
+use std::fmt;
+use bevy::prelude::Event;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::custom_types::{GameAction, GameEvent};
+use super::manifest::PlayerInterfaceManifest;
+use super::player::PlayerId;
+
+// A single difference between an expected manifest (e.g. the one an agent was built against)
+// and the manifest of the running game
+// Actions and events are compared by their serialized form
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ManifestChange {
+    NameChanged { expected: String, running: String },
+    VersionChanged { expected: u32, running: u32 },
+    // Actions or events changed, but the version did not
+    VersionNotBumped { version: u32 },
+    TickRateChanged { expected: u32, running: u32 },
+    ActionAdded(Value),
+    ActionRemoved(Value),
+    // An action was removed and another added with the same `hint_text`
+    ActionRenamed { from: Value, to: Value },
+    EventAdded(Value),
+    EventRemoved(Value),
+    EventRenamed { from: Value, to: Value },
+}
+
+impl ManifestChange {
+    // Whether a client using the expected manifest can no longer play the running game as-is
+    pub fn is_breaking(&self) -> bool {
+        match self {
+            ManifestChange::NameChanged { .. } => true,
+            ManifestChange::ActionRemoved(_) => true,
+            ManifestChange::ActionRenamed { .. } => true,
+            // A client waiting for a removed event never gets it, and a rename is a removal plus an addition
+            ManifestChange::EventRemoved(_) => true,
+            ManifestChange::EventRenamed { .. } => true,
+            // Inputs timed for the expected rate would be rate limited (a `tick_rate` of 0 means no limit)
+            ManifestChange::TickRateChanged { expected, running } => {
+                *running != 0 && (*expected == 0 || running < expected)
+            }
+            // New actions are optional, and unknown events can be ignored
+            ManifestChange::ActionAdded(_) => false,
+            ManifestChange::EventAdded(_) => false,
+            ManifestChange::VersionChanged { .. } => false,
+            ManifestChange::VersionNotBumped { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for ManifestChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestChange::NameChanged { expected, running } => write!(f, "name: {expected:?} -> {running:?}"),
+            ManifestChange::VersionChanged { expected, running } => write!(f, "version: {expected} -> {running}"),
+            ManifestChange::VersionNotBumped { version } => write!(f, "version: {version} (unchanged despite changes)"),
+            ManifestChange::TickRateChanged { expected, running } => write!(f, "tick_rate: {expected} -> {running}"),
+            ManifestChange::ActionAdded(action) => write!(f, "action added: {action}"),
+            ManifestChange::ActionRemoved(action) => write!(f, "action removed: {action}"),
+            ManifestChange::ActionRenamed { from, to } => write!(f, "action renamed: {from} -> {to}"),
+            ManifestChange::EventAdded(event) => write!(f, "event added: {event}"),
+            ManifestChange::EventRemoved(event) => write!(f, "event removed: {event}"),
+            ManifestChange::EventRenamed { from, to } => write!(f, "event renamed: {from} -> {to}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestDiff {
+    pub changes: Vec<ManifestChange>,
+}
+
+impl ManifestDiff {
+    pub fn new<GE, GA>(
+        expected: &PlayerInterfaceManifest<GE, GA>,
+        running: &PlayerInterfaceManifest<GE, GA>,
+    ) -> Result<Self, serde_json::Error> where GE: GameEvent + Serialize, GA: GameAction + Serialize {
+        let mut changes = vec![];
+
+        if expected.name != running.name {
+            changes.push(ManifestChange::NameChanged {
+                expected: expected.name.clone(),
+                running: running.name.clone(),
+            });
+        }
+        if expected.tick_rate != running.tick_rate {
+            changes.push(ManifestChange::TickRateChanged {
+                expected: expected.tick_rate,
+                running: running.tick_rate,
+            });
+        }
+
+        let (removed, added, renamed) = diff_descriptors(
+            expected.actions_global.iter().map(|d| Ok((to_value(&d.action)?, &d.hint_text))).collect::<Result<_, _>>()?,
+            running.actions_global.iter().map(|d| Ok((to_value(&d.action)?, &d.hint_text))).collect::<Result<_, _>>()?,
+        );
+        changes.extend(removed.into_iter().map(ManifestChange::ActionRemoved));
+        changes.extend(added.into_iter().map(ManifestChange::ActionAdded));
+        changes.extend(renamed.into_iter().map(|(from, to)| ManifestChange::ActionRenamed { from, to }));
+
+        let (removed, added, renamed) = diff_descriptors(
+            expected.events_global.iter().map(|d| Ok((to_value(&d.event)?, &d.hint_text))).collect::<Result<_, _>>()?,
+            running.events_global.iter().map(|d| Ok((to_value(&d.event)?, &d.hint_text))).collect::<Result<_, _>>()?,
+        );
+        changes.extend(removed.into_iter().map(ManifestChange::EventRemoved));
+        changes.extend(added.into_iter().map(ManifestChange::EventAdded));
+        changes.extend(renamed.into_iter().map(|(from, to)| ManifestChange::EventRenamed { from, to }));
+
+        let contents_changed = changes
+            .iter()
+            .any(|change| !matches!(change, ManifestChange::NameChanged { .. } | ManifestChange::TickRateChanged { .. }));
+        if expected.version != running.version {
+            changes.insert(0, ManifestChange::VersionChanged {
+                expected: expected.version,
+                running: running.version,
+            });
+        } else if contents_changed {
+            changes.insert(0, ManifestChange::VersionNotBumped { version: expected.version });
+        }
+
+        Ok(ManifestDiff { changes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn is_breaking(&self) -> bool {
+        self.changes.iter().any(ManifestChange::is_breaking)
+    }
+
+    pub fn breaking_changes(&self) -> impl Iterator<Item = &ManifestChange> {
+        self.changes.iter().filter(|change| change.is_breaking())
+    }
+}
+
+impl fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "manifests are identical");
+        }
+        for change in &self.changes {
+            let marker = if change.is_breaking() { "!" } else { " " };
+            writeln!(f, "{marker} {change}")?;
+        }
+        Ok(())
+    }
+}
+
+// Result of negotiating between the manifest a client expects and the running game's manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ManifestHandshake {
+    // No breaking changes, the client can play as-is
+    Accepted(ManifestDiff),
+    // Breaking changes, but the client can still use the remaining actions
+    // Actions it expected that are no longer available will be rejected
+    Degraded(ManifestDiff),
+    // A different game, or none of the expected actions are left
+    Refused(ManifestDiff),
+}
+
+impl ManifestHandshake {
+    pub fn negotiate<GE, GA>(
+        expected: &PlayerInterfaceManifest<GE, GA>,
+        running: &PlayerInterfaceManifest<GE, GA>,
+    ) -> Result<Self, serde_json::Error> where GE: GameEvent + Serialize, GA: GameAction + Serialize {
+        let diff = ManifestDiff::new(expected, running)?;
+
+        if !diff.is_breaking() {
+            return Ok(ManifestHandshake::Accepted(diff));
+        }
+
+        let name_changed = diff
+            .changes
+            .iter()
+            .any(|change| matches!(change, ManifestChange::NameChanged { .. }));
+        let running_actions = running
+            .actions_global
+            .iter()
+            .map(|d| to_value(&d.action))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut any_action_left = false;
+        for d in &expected.actions_global {
+            any_action_left |= running_actions.contains(&to_value(&d.action)?);
+        }
+
+        if name_changed || !any_action_left {
+            Ok(ManifestHandshake::Refused(diff))
+        } else {
+            Ok(ManifestHandshake::Degraded(diff))
+        }
+    }
+
+    pub fn is_refused(&self) -> bool {
+        matches!(self, ManifestHandshake::Refused(_))
+    }
+
+    pub fn diff(&self) -> &ManifestDiff {
+        match self {
+            ManifestHandshake::Accepted(diff) => diff,
+            ManifestHandshake::Degraded(diff) => diff,
+            ManifestHandshake::Refused(diff) => diff,
+        }
+    }
+}
+
+// Sent by a client (e.g. an agent) to check the manifest it expects against the running game's
+// The `ActionValidationPlugin` answers with a `ManifestHandshakeResult`, and from then on only accepts
+// the player's actions that both manifests share
+#[derive(Debug, Clone, Serialize, Deserialize, Event)]
+pub struct ManifestHandshakeRequest<GE, GA> where GE: GameEvent, GA: GameAction {
+    pub player: PlayerId,
+    pub expected: PlayerInterfaceManifest<GE, GA>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Event)]
+pub struct ManifestHandshakeResult {
+    pub player: PlayerId,
+    pub handshake: ManifestHandshake,
+}
+
+// Game actions and events MUST be serializable (see `KeyboardConfig`)
+// but can still fail to convert, e.g. maps with non-string keys
+fn to_value<T>(value: &T) -> Result<Value, serde_json::Error> where T: Serialize {
+    serde_json::to_value(value)
+}
+
+// Returns the (removed, added, renamed) entries between two lists of (key, hint_text)
+#[allow(clippy::type_complexity)]
+fn diff_descriptors(
+    expected: Vec<(Value, &Option<String>)>,
+    running: Vec<(Value, &Option<String>)>,
+) -> (Vec<Value>, Vec<Value>, Vec<(Value, Value)>) {
+    let mut removed: Vec<(Value, &Option<String>)> = expected
+        .iter()
+        .filter(|(key, _)| !running.iter().any(|(other, _)| other == key))
+        .cloned()
+        .collect();
+    let mut added: Vec<(Value, &Option<String>)> = running
+        .iter()
+        .filter(|(key, _)| !expected.iter().any(|(other, _)| other == key))
+        .cloned()
+        .collect();
+
+    // Pair up removed and added entries that share a description
+    let mut renamed = vec![];
+    removed.retain(|(from, hint_text)| {
+        let Some(hint_text) = hint_text else {
+            return true;
+        };
+        let Some(index) = added.iter().position(|(_, other)| other.as_ref() == Some(hint_text)) else {
+            return true;
+        };
+        let (to, _) = added.remove(index);
+        renamed.push((from.clone(), to));
+        false
+    });
+
+    (
+        removed.into_iter().map(|(key, _)| key).collect(),
+        added.into_iter().map(|(key, _)| key).collect(),
+        renamed,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::json;
+
+    use super::*;
+    use crate::model::manifest::{ActionDescriptor, EventDescriptor};
+
+    fn manifest(version: u32, actions: &[(&'static str, &str)]) -> PlayerInterfaceManifest<&'static str, &'static str> {
+        PlayerInterfaceManifest {
+            name: "game".to_string(),
+            version,
+            tick_rate: 10,
+            hint_text: None,
+            events_global: vec![EventDescriptor::new("scored")],
+            actions_global: actions
+                .iter()
+                .map(|(action, hint_text)| ActionDescriptor::new(*action).desc(hint_text.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn identical_manifests_are_accepted() {
+        let expected = manifest(1, &[("jump", "Jump up")]);
+        let handshake = ManifestHandshake::negotiate(&expected, &expected).unwrap();
+        assert_eq!(handshake, ManifestHandshake::Accepted(ManifestDiff::default()));
+    }
+
+    #[test]
+    fn same_hint_text_is_a_rename() {
+        let expected = manifest(1, &[("jump", "Jump up"), ("duck", "Duck down")]);
+        let running = manifest(2, &[("leap", "Jump up"), ("duck", "Duck down")]);
+        let diff = ManifestDiff::new(&expected, &running).unwrap();
+        assert_eq!(diff.changes, vec![
+            ManifestChange::VersionChanged { expected: 1, running: 2 },
+            ManifestChange::ActionRenamed { from: json!("jump"), to: json!("leap") },
+        ]);
+    }
+
+    #[test]
+    fn different_hint_text_is_a_removal_and_addition() {
+        let expected = manifest(1, &[("jump", "Jump up"), ("duck", "Duck down")]);
+        let running = manifest(2, &[("leap", "Leap forward"), ("duck", "Duck down")]);
+        let diff = ManifestDiff::new(&expected, &running).unwrap();
+        assert_eq!(diff.changes, vec![
+            ManifestChange::VersionChanged { expected: 1, running: 2 },
+            ManifestChange::ActionRemoved(json!("jump")),
+            ManifestChange::ActionAdded(json!("leap")),
+        ]);
+    }
+
+    #[test]
+    fn missing_hint_text_is_never_a_rename() {
+        let mut expected = manifest(1, &[]);
+        expected.actions_global.push(ActionDescriptor::new("jump"));
+        let mut running = manifest(1, &[]);
+        running.actions_global.push(ActionDescriptor::new("leap"));
+        let diff = ManifestDiff::new(&expected, &running).unwrap();
+        assert_eq!(diff.changes, vec![
+            ManifestChange::VersionNotBumped { version: 1 },
+            ManifestChange::ActionRemoved(json!("jump")),
+            ManifestChange::ActionAdded(json!("leap")),
+        ]);
+    }
+
+    #[test]
+    fn breaking_changes() {
+        let value = json!("jump");
+        assert!(ManifestChange::NameChanged { expected: "a".into(), running: "b".into() }.is_breaking());
+        assert!(ManifestChange::ActionRemoved(value.clone()).is_breaking());
+        assert!(ManifestChange::ActionRenamed { from: value.clone(), to: value.clone() }.is_breaking());
+        assert!(ManifestChange::EventRenamed { from: value.clone(), to: value.clone() }.is_breaking());
+        assert!(ManifestChange::EventRemoved(value.clone()).is_breaking());
+        assert!(!ManifestChange::ActionAdded(value.clone()).is_breaking());
+        assert!(!ManifestChange::EventAdded(value).is_breaking());
+        assert!(!ManifestChange::VersionChanged { expected: 1, running: 2 }.is_breaking());
+        assert!(!ManifestChange::VersionNotBumped { version: 1 }.is_breaking());
+    }
+
+    #[test]
+    fn lower_tick_rate_is_breaking() {
+        assert!(ManifestChange::TickRateChanged { expected: 20, running: 10 }.is_breaking());
+        assert!(!ManifestChange::TickRateChanged { expected: 10, running: 20 }.is_breaking());
+        // 0 means no limit
+        assert!(ManifestChange::TickRateChanged { expected: 0, running: 10 }.is_breaking());
+        assert!(!ManifestChange::TickRateChanged { expected: 10, running: 0 }.is_breaking());
+    }
+
+    #[test]
+    fn removed_or_renamed_event_is_degraded() {
+        let expected = manifest(1, &[("jump", "Jump up")]);
+        let mut running = manifest(2, &[("jump", "Jump up")]);
+        running.events_global = vec![];
+        let handshake = ManifestHandshake::negotiate(&expected, &running).unwrap();
+        assert_eq!(handshake.diff().changes[1], ManifestChange::EventRemoved(json!("scored")));
+        assert!(matches!(handshake, ManifestHandshake::Degraded(_)));
+
+        let mut expected = expected;
+        expected.events_global = vec![EventDescriptor::new("scored").desc("A point was scored".to_string())];
+        running.events_global = vec![EventDescriptor::new("goal").desc("A point was scored".to_string())];
+        let handshake = ManifestHandshake::negotiate(&expected, &running).unwrap();
+        assert_eq!(handshake.diff().changes[1], ManifestChange::EventRenamed { from: json!("scored"), to: json!("goal") });
+        assert!(matches!(handshake, ManifestHandshake::Degraded(_)));
+    }
+
+    #[test]
+    fn higher_tick_rate_is_accepted() {
+        let expected = manifest(1, &[("jump", "Jump up")]);
+        let mut running = expected.clone();
+        running.tick_rate = 20;
+        let handshake = ManifestHandshake::negotiate(&expected, &running).unwrap();
+        assert!(matches!(handshake, ManifestHandshake::Accepted(_)));
+
+        running.tick_rate = 5;
+        let handshake = ManifestHandshake::negotiate(&expected, &running).unwrap();
+        assert!(matches!(handshake, ManifestHandshake::Degraded(_)));
+    }
+
+    #[test]
+    fn added_action_is_accepted() {
+        let expected = manifest(1, &[("jump", "Jump up")]);
+        let running = manifest(2, &[("jump", "Jump up"), ("duck", "Duck down")]);
+        let handshake = ManifestHandshake::negotiate(&expected, &running).unwrap();
+        assert!(matches!(handshake, ManifestHandshake::Accepted(_)));
+        assert!(!handshake.diff().is_empty());
+    }
+
+    #[test]
+    fn removed_action_is_degraded() {
+        let expected = manifest(1, &[("jump", "Jump up"), ("duck", "Duck down")]);
+        let running = manifest(2, &[("jump", "Jump up")]);
+        let handshake = ManifestHandshake::negotiate(&expected, &running).unwrap();
+        assert!(matches!(handshake, ManifestHandshake::Degraded(_)));
+    }
+
+    #[test]
+    fn no_shared_action_or_other_game_is_refused() {
+        let expected = manifest(1, &[("jump", "Jump up")]);
+        let running = manifest(2, &[("duck", "Duck down")]);
+        assert!(ManifestHandshake::negotiate(&expected, &running).unwrap().is_refused());
+
+        let mut running = expected.clone();
+        running.name = "other game".to_string();
+        assert!(ManifestHandshake::negotiate(&expected, &running).unwrap().is_refused());
+    }
+
+    #[test]
+    fn unserializable_actions_are_an_error() {
+        let action = HashMap::from([((1, 2), 3)]);
+        let expected = PlayerInterfaceManifest::<&str, HashMap<(u8, u8), u8>> {
+            name: "game".to_string(),
+            version: 1,
+            tick_rate: 10,
+            hint_text: None,
+            events_global: vec![],
+            actions_global: vec![ActionDescriptor::new(action)],
+        };
+        assert!(ManifestDiff::new(&expected, &expected).is_err());
+        assert!(ManifestHandshake::negotiate(&expected, &expected).is_err());
+    }
+}
@@ -1,6 +1,8 @@
+use bevy::prelude::Event;
 use serde::{Deserialize, Serialize};
 
 use crate::model::custom_types::{GameAction, GameEvent};
+use crate::model::player::PlayerId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UpdateInfo<GE> where GE: GameEvent {
@@ -26,6 +28,8 @@ pub enum MediaType {
     FrameSet,
     Video,
     Audio,
+    // A plain-text (ASCII) rendering of the scene, for agents without a GPU
+    TextFrame,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,3 +57,38 @@ pub struct PlayerInterfaceGameUpdate<GE, GA> where GE: GameEvent, GA: GameAction
     // A reference to media representing the game state
     pub media_refs: Option<Vec<MediaRef>>,
 }
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UpdateRecipient {
+    // Broadcast to every player
+    All,
+    Player(PlayerId),
+}
+
+impl UpdateRecipient {
+    pub fn includes(&self, player: &PlayerId) -> bool {
+        match self {
+            UpdateRecipient::All => true,
+            UpdateRecipient::Player(recipient) => recipient == player,
+        }
+    }
+}
+
+// A `PlayerInterfaceGameUpdate` addressed to one or all players
+#[derive(Debug, Clone, Serialize, Deserialize, Event)]
+pub struct RoutedGameUpdate<GE, GA> where GE: GameEvent, GA: GameAction
+{
+    pub recipient: UpdateRecipient,
+    pub update: PlayerInterfaceGameUpdate<GE, GA>,
+}
+
+impl<GE, GA> RoutedGameUpdate<GE, GA> where GE: GameEvent, GA: GameAction
+{
+    pub fn all(update: PlayerInterfaceGameUpdate<GE, GA>) -> Self {
+        RoutedGameUpdate { recipient: UpdateRecipient::All, update }
+    }
+
+    pub fn player(player: PlayerId, update: PlayerInterfaceGameUpdate<GE, GA>) -> Self {
+        RoutedGameUpdate { recipient: UpdateRecipient::Player(player), update }
+    }
+}
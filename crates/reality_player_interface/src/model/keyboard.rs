@@ -3,6 +3,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 use super::custom_types::GameAction;
+use super::player::PlayerId;
 
 // Based on https://w3c.github.io/uievents-code/#code-value-tables
 // These come from winit
@@ -184,14 +185,26 @@ pub enum KeyCode {
 // Binding Key to Arbitrary GameAction
 // GameAction is defined by the library user
 // It MUST be serializable to string
-#[derive(Debug, Clone, Serialize, Deserialize, Resource)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyboardConfig<GA> where GA: GameAction {
+    // The player the bound actions are sent for, e.g. for split-screen
+    #[serde(default)]
+    player: PlayerId,
     bindings: HashMap<KeyCode, Vec<GA>>,
 }
 
 impl<GA> KeyboardConfig<GA> where GA: GameAction {
     pub fn new(bindings: HashMap<KeyCode, Vec<GA>>) -> KeyboardConfig<GA> {
-        KeyboardConfig { bindings }
+        KeyboardConfig { player: PlayerId::default(), bindings }
+    }
+
+    pub fn for_player(mut self, player: PlayerId) -> Self {
+        self.player = player;
+        self
+    }
+
+    pub fn player(&self) -> &PlayerId {
+        &self.player
     }
 
     pub fn get_actions(&self, key_code: &KeyCode) -> Option<&Vec<GA>> {
@@ -199,6 +212,12 @@ impl<GA> KeyboardConfig<GA> where GA: GameAction {
     }
 }
 
+// The `KeyboardConfig` of every local player
+#[derive(Debug, Clone, Resource)]
+pub struct KeyboardConfigs<GA> where GA: GameAction {
+    pub configs: Vec<KeyboardConfig<GA>>,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum KeyEvent {
     Press,
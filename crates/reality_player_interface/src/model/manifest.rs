@@ -1,3 +1,4 @@
+use bevy::prelude::Resource;
 use serde::{Deserialize, Serialize};
 
 use crate::model::custom_types::{GameAction, GameEvent};
@@ -40,12 +41,14 @@ impl<GA> ActionDescriptor<GA> where GA: GameAction {
     }   
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Inserted as a resource by the `ActionValidationPlugin`, to negotiate `ManifestHandshakeRequest`s
+#[derive(Debug, Clone, Serialize, Deserialize, Resource)]
 pub struct PlayerInterfaceManifest<GE, GA> where GE: GameEvent, GA: GameAction
 {
     // Name of the game
     pub name: String,
     // Version of the game/manifest
+    // Bump whenever actions or events change, clients use `ManifestHandshake` to check compatibility
     pub version: u32,
     // The maximum rate that the game can process actions
     pub tick_rate: u32,
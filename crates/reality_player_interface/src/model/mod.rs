@@ -1,5 +1,10 @@
+pub mod player;
 pub mod keyboard;
 pub mod custom_types;
 pub mod manifest;
 pub mod game_update;
 pub mod player_update;
+pub mod text_frame;
+pub mod validation;
+pub mod compatibility;
+pub mod rate_limit;
@@ -0,0 +1,33 @@
+use std::fmt;
+use bevy::prelude::Reflect;
+use serde::{Deserialize, Serialize};
+
+// Identifies which player produced an input or should receive an update
+#[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize, Reflect)]
+pub enum PlayerId {
+    // A local input device or agent slot, e.g. for split-screen
+    Local(u32),
+    // A remote player, e.g. the AO sender `ArweaveAddress` on the server
+    Remote(String),
+}
+
+impl PlayerId {
+    pub fn from_address(address: &str) -> Self {
+        PlayerId::Remote(address.to_string())
+    }
+}
+
+impl Default for PlayerId {
+    fn default() -> Self {
+        PlayerId::Local(0)
+    }
+}
+
+impl fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerId::Local(slot) => write!(f, "local:{slot}"),
+            PlayerId::Remote(address) => write!(f, "remote:{address}"),
+        }
+    }
+}
@@ -1,25 +1,29 @@
 // For Agents to asynchronously sed actions to the Game Server
 use std::fmt::Debug;
-use bevy::prelude::Event;
+use bevy::prelude::{Event, Reflect};
 use serde::{Deserialize, Serialize};
 
 use super::custom_types::GameAction;
+use super::player::PlayerId;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Reflect)]
 pub enum InputEventType {
     Begin,
     End,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Event)]
-pub struct GameInputEvent<GA> where GA: GameAction {   
+// Reflected when `GA` is, so scripts can read it, see `register_lua_event` in `reality_scripting`
+#[derive(Debug, Clone, Serialize, Deserialize, Event, Reflect)]
+pub struct GameInputEvent<GA> where GA: GameAction {
+    // The player that produced the input
+    pub player: PlayerId,
     pub action: GA,
     pub event_type: InputEventType,
 }
 
 impl<GA> GameInputEvent<GA> where GA: GameAction {
-    pub fn new(action: GA, event_type: InputEventType) -> Self {
-        GameInputEvent { action, event_type }
+    pub fn new(player: PlayerId, action: GA, event_type: InputEventType) -> Self {
+        GameInputEvent { player, action, event_type }
     }
 }
 
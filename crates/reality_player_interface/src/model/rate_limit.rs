@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use bevy::prelude::Resource;
+
+use super::custom_types::{GameAction, GameEvent};
+use super::game_update::{RoutedGameUpdate, UpdateRecipient};
+use super::player::PlayerId;
+use super::player_update::{GameInputEvent, InputEventType};
+use super::validation::ActionRejectionReason;
+
+#[derive(Debug, Clone)]
+struct PlayerLimiter<GA> where GA: GameAction + PartialEq {
+    // Token bucket, refilled at `tick_rate` tokens per second
+    tokens: f64,
+    last_refill: f64,
+    // Actions that have begun and not yet ended
+    active: Vec<GA>,
+    // Actions whose Begin was dropped, so their End must be dropped too
+    dropped: Vec<GA>,
+    dropped_count: u32,
+    coalesced_count: u32,
+}
+
+// Per-player limiter enforcing the manifest's `tick_rate`
+// Begin/End pairs are kept balanced: an End is only let through if its Begin was
+#[derive(Debug, Clone, Resource)]
+pub struct InputRateLimiter<GA> where GA: GameAction + PartialEq {
+    tick_rate: u32,
+    players: HashMap<PlayerId, PlayerLimiter<GA>>,
+    // Game ticks counted by `tick`, for apps without a `Time` resource
+    ticks: u64,
+}
+
+impl<GA> InputRateLimiter<GA> where GA: GameAction + PartialEq {
+    // A `tick_rate` of 0 means there is no limit
+    pub fn new(tick_rate: u32) -> Self {
+        InputRateLimiter { tick_rate, players: HashMap::new(), ticks: 0 }
+    }
+
+    // Counts a game tick and returns the time in seconds it stands for, assuming `tick_rate` ticks per second
+    // Used instead of `Time` where there's no wall clock, e.g. on the AO server, so replays limit the same way
+    pub fn tick(&mut self) -> f64 {
+        self.ticks += 1;
+        if self.tick_rate == 0 {
+            return 0.0;
+        }
+        self.ticks as f64 / self.tick_rate as f64
+    }
+
+    // Returns `Ok(true)` if the input should be passed on to the game, `Ok(false)` if it was merged into
+    // an earlier input (a repeated Begin, or the End of a dropped Begin), and an error if it was dropped
+    // `now` is the current time in seconds
+    pub fn allow(&mut self, input: &GameInputEvent<GA>, now: f64) -> Result<bool, ActionRejectionReason> {
+        let tick_rate = self.tick_rate as f64;
+        let limiter = self.players.entry(input.player.clone()).or_insert_with(|| PlayerLimiter {
+            tokens: tick_rate,
+            last_refill: now,
+            active: vec![],
+            dropped: vec![],
+            dropped_count: 0,
+            coalesced_count: 0,
+        });
+
+        match input.event_type {
+            InputEventType::Begin => {
+                // Repeated Begins (e.g. key repeat) are merged into the first one
+                if limiter.active.contains(&input.action) || limiter.dropped.contains(&input.action) {
+                    limiter.coalesced_count += 1;
+                    return Ok(false);
+                }
+                if self.tick_rate > 0 {
+                    limiter.tokens = (limiter.tokens + (now - limiter.last_refill) * tick_rate).min(tick_rate);
+                    limiter.last_refill = now;
+                    if limiter.tokens < 1.0 {
+                        limiter.dropped.push(input.action.clone());
+                        limiter.dropped_count += 1;
+                        return Err(ActionRejectionReason::RateLimited { tick_rate: self.tick_rate });
+                    }
+                    limiter.tokens -= 1.0;
+                }
+                limiter.active.push(input.action.clone());
+                Ok(true)
+            }
+            InputEventType::End => {
+                if let Some(index) = limiter.active.iter().position(|action| *action == input.action) {
+                    limiter.active.remove(index);
+                    return Ok(true);
+                }
+                if let Some(index) = limiter.dropped.iter().position(|action| *action == input.action) {
+                    limiter.dropped.remove(index);
+                }
+                // Either the pair was dropped, or there was no Begin at all
+                Ok(false)
+            }
+        }
+    }
+
+    // Forgets players with nothing in flight, nothing left to report and a full bucket,
+    // so the map doesn't keep every player that ever sent an input
+    pub fn evict_idle(&mut self, now: f64) {
+        let tick_rate = self.tick_rate as f64;
+        self.players.retain(|_, limiter| {
+            let refilled = tick_rate == 0.0
+                || limiter.tokens + (now - limiter.last_refill) * tick_rate >= tick_rate;
+            let idle = limiter.active.is_empty()
+                && limiter.dropped.is_empty()
+                && limiter.dropped_count == 0
+                && limiter.coalesced_count == 0;
+            !(idle && refilled)
+        });
+    }
+
+    pub fn tracked_players(&self) -> usize {
+        self.players.len()
+    }
+
+    // Describes (and resets) the inputs of `recipient` dropped since the last call, if any
+    pub fn take_report(&mut self, recipient: &UpdateRecipient) -> Option<String> {
+        let mut players = self
+            .players
+            .iter_mut()
+            .filter(|(player, _)| recipient.includes(player))
+            .filter(|(_, limiter)| limiter.dropped_count > 0 || limiter.coalesced_count > 0)
+            .map(|(player, limiter)| {
+                let report = format!(
+                    "{player}: {} dropped, {} coalesced",
+                    limiter.dropped_count, limiter.coalesced_count
+                );
+                limiter.dropped_count = 0;
+                limiter.coalesced_count = 0;
+                report
+            })
+            .collect::<Vec<_>>();
+        if players.is_empty() {
+            return None;
+        }
+        players.sort();
+        Some(format!(
+            "Some actions were ignored for exceeding the tick rate of {} per second ({})",
+            self.tick_rate,
+            players.join("; ")
+        ))
+    }
+
+    // Appends the report of the recipient's dropped inputs to the update's `hint_text`
+    pub fn annotate_update<GE>(&mut self, routed: &mut RoutedGameUpdate<GE, GA>) where GE: GameEvent {
+        let Some(report) = self.take_report(&routed.recipient) else {
+            return;
+        };
+        let update = &mut routed.update;
+        update.hint_text = Some(match update.hint_text.take() {
+            Some(hint_text) => format!("{hint_text}\n{report}"),
+            None => report,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn begin(player: u32, action: &'static str) -> GameInputEvent<&'static str> {
+        GameInputEvent::new(PlayerId::Local(player), action, InputEventType::Begin)
+    }
+
+    fn end(player: u32, action: &'static str) -> GameInputEvent<&'static str> {
+        GameInputEvent::new(PlayerId::Local(player), action, InputEventType::End)
+    }
+
+    const RATE_LIMITED: Result<bool, ActionRejectionReason> = Err(ActionRejectionReason::RateLimited { tick_rate: 1 });
+
+    #[test]
+    fn begin_and_end_pass_through() {
+        let mut limiter = InputRateLimiter::new(10);
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.0), Ok(true));
+    }
+
+    #[test]
+    fn repeated_begin_is_coalesced() {
+        let mut limiter = InputRateLimiter::new(10);
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.1), Ok(false));
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.2), Ok(true));
+        // Only one End for the coalesced pair
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.3), Ok(false));
+    }
+
+    #[test]
+    fn end_of_dropped_begin_is_dropped() {
+        let mut limiter = InputRateLimiter::new(1);
+        assert_eq!(limiter.allow(&begin(0, "left"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "right"), 0.1), RATE_LIMITED);
+        // Repeats of the dropped Begin don't spend tokens or get reported twice
+        assert_eq!(limiter.allow(&begin(0, "right"), 0.2), Ok(false));
+        assert_eq!(limiter.allow(&end(0, "right"), 0.3), Ok(false));
+        assert_eq!(limiter.allow(&end(0, "left"), 0.4), Ok(true));
+    }
+
+    #[test]
+    fn end_without_begin_is_dropped() {
+        let mut limiter = InputRateLimiter::<&str>::new(1);
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.0), Ok(false));
+    }
+
+    #[test]
+    fn ticks_refill_one_token_each() {
+        let mut limiter = InputRateLimiter::new(2);
+        let now = limiter.tick();
+        assert_eq!(now, 0.5);
+        assert_eq!(limiter.allow(&begin(0, "left"), now), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "right"), now), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "up"), now), Err(ActionRejectionReason::RateLimited { tick_rate: 2 }));
+        let now = limiter.tick();
+        assert_eq!(now, 1.0);
+        assert_eq!(limiter.allow(&begin(0, "down"), now), Ok(true));
+    }
+
+    #[test]
+    fn ticks_without_a_limit_stay_at_zero() {
+        let mut limiter = InputRateLimiter::<&str>::new(0);
+        assert_eq!(limiter.tick(), 0.0);
+        assert_eq!(limiter.tick(), 0.0);
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut limiter = InputRateLimiter::new(1);
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.5), RATE_LIMITED);
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.5), Ok(false));
+        assert_eq!(limiter.allow(&begin(0, "jump"), 1.0), Ok(true));
+    }
+
+    #[test]
+    fn players_have_separate_buckets() {
+        let mut limiter = InputRateLimiter::new(1);
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "duck"), 0.0), RATE_LIMITED);
+        assert_eq!(limiter.allow(&begin(1, "duck"), 0.0), Ok(true));
+    }
+
+    #[test]
+    fn zero_tick_rate_is_unlimited() {
+        let mut limiter = InputRateLimiter::new(0);
+        for _ in 0..100 {
+            assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+            assert_eq!(limiter.allow(&end(0, "jump"), 0.0), Ok(true));
+        }
+    }
+
+    #[test]
+    fn idle_players_are_evicted() {
+        let mut limiter = InputRateLimiter::new(1);
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+        limiter.evict_idle(5.0);
+        // The action is still held
+        assert_eq!(limiter.tracked_players(), 1);
+        assert_eq!(limiter.allow(&end(0, "jump"), 5.0), Ok(true));
+        limiter.evict_idle(5.0);
+        assert_eq!(limiter.tracked_players(), 0);
+    }
+
+    #[test]
+    fn players_with_pending_reports_are_kept() {
+        let mut limiter = InputRateLimiter::new(1);
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.0), Ok(true));
+        assert_eq!(limiter.allow(&begin(0, "jump"), 0.0), RATE_LIMITED);
+        assert_eq!(limiter.allow(&end(0, "jump"), 0.0), Ok(false));
+        limiter.evict_idle(5.0);
+        assert_eq!(limiter.tracked_players(), 1);
+        let report = limiter.take_report(&UpdateRecipient::All).unwrap();
+        assert!(report.contains("local:0: 1 dropped, 0 coalesced"), "{report}");
+        limiter.evict_idle(5.0);
+        assert_eq!(limiter.tracked_players(), 0);
+    }
+}
@@ -0,0 +1,229 @@
+use bevy::prelude::{Component, Resource, Vec2};
+use serde::{Deserialize, Serialize};
+
+use super::game_update::{MediaRef, MediaType};
+
+// Which world axes are mapped onto the columns/rows of the frame
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TextFrameProjection {
+    // x -> column, y -> row (y up), for 2D sprite games
+    Front,
+    // x -> column, z -> row (z towards the viewer), for 3D games seen from above
+    TopDown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Resource)]
+pub struct TextFrameConfig {
+    // Size of the frame in characters
+    pub width: usize,
+    pub height: usize,
+    // The region of the world (in projected coordinates) covered by the frame
+    pub min: Vec2,
+    pub max: Vec2,
+    pub projection: TextFrameProjection,
+    // Character used for cells without any glyph
+    pub background: char,
+}
+
+impl Default for TextFrameConfig {
+    fn default() -> Self {
+        TextFrameConfig {
+            width: 32,
+            height: 16,
+            min: Vec2::new(-8.0, -8.0),
+            max: Vec2::new(8.0, 8.0),
+            projection: TextFrameProjection::TopDown,
+            background: '.',
+        }
+    }
+}
+
+impl TextFrameConfig {
+    // Map a projected world position to a (column, row) cell, if it is inside the frame
+    pub fn cell(&self, position: Vec2) -> Option<(usize, usize)> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let size = self.max - self.min;
+        if size.x <= 0.0 || size.y <= 0.0 {
+            return None;
+        }
+        let normalized = (position - self.min) / size;
+        if !(0.0..=1.0).contains(&normalized.x) || !(0.0..=1.0).contains(&normalized.y) {
+            return None;
+        }
+        let column = ((normalized.x * self.width as f32) as usize).min(self.width - 1);
+        let row = match self.projection {
+            // Row 0 is the top of the frame, so flip y
+            TextFrameProjection::Front => ((1.0 - normalized.y) * self.height as f32) as usize,
+            TextFrameProjection::TopDown => (normalized.y * self.height as f32) as usize,
+        }
+        .min(self.height - 1);
+        Some((column, row))
+    }
+}
+
+// Marks an entity to be drawn into the text frame with the given character
+#[derive(Debug, Clone, Copy, Component)]
+pub struct TextFrameGlyph {
+    pub glyph: char,
+    // Higher layers are drawn over lower ones when sharing a cell
+    pub layer: i32,
+}
+
+impl TextFrameGlyph {
+    pub fn new(glyph: char) -> Self {
+        TextFrameGlyph { glyph, layer: 0 }
+    }
+
+    pub fn layer(mut self, layer: i32) -> Self {
+        self.layer = layer;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextFrame {
+    pub width: usize,
+    pub height: usize,
+    // `width * height` characters, row by row from the top
+    pub cells: Vec<char>,
+}
+
+impl TextFrame {
+    pub fn new(width: usize, height: usize, background: char) -> Self {
+        TextFrame {
+            width,
+            height,
+            cells: vec![background; width * height],
+        }
+    }
+
+    // Cells outside the frame are ignored
+    pub fn set(&mut self, column: usize, row: usize, glyph: char) {
+        if column < self.width && row < self.height {
+            self.cells[row * self.width + column] = glyph;
+        }
+    }
+
+    pub fn get(&self, column: usize, row: usize) -> Option<char> {
+        (column < self.width && row < self.height).then(|| self.cells[row * self.width + column])
+    }
+
+    pub fn to_text(&self) -> String {
+        if self.width == 0 {
+            return vec![""; self.height].join("\n");
+        }
+        self.cells
+            .chunks(self.width)
+            .map(|row| row.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Embed the frame in a `data:` URI so it can be sent without any media hosting
+    pub fn to_media_ref(&self, ts: u64) -> MediaRef {
+        MediaRef {
+            ts,
+            media_type: MediaType::TextFrame,
+            uri: format!("data:text/plain;charset=utf-8,{}", percent_encode(&self.to_text())),
+        }
+    }
+}
+
+// The most recently rendered frame, updated by `render_text_frame`
+#[derive(Debug, Clone, Default, Resource)]
+pub struct LatestTextFrame(pub Option<TextFrame>);
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(projection: TextFrameProjection) -> TextFrameConfig {
+        TextFrameConfig {
+            width: 4,
+            height: 2,
+            min: Vec2::new(0.0, 0.0),
+            max: Vec2::new(4.0, 2.0),
+            projection,
+            background: '.',
+        }
+    }
+
+    #[test]
+    fn top_down_rows_grow_with_z() {
+        let config = config(TextFrameProjection::TopDown);
+        assert_eq!(config.cell(Vec2::new(0.0, 0.0)), Some((0, 0)));
+        assert_eq!(config.cell(Vec2::new(1.5, 1.5)), Some((1, 1)));
+        // The far edges still map to the last cell
+        assert_eq!(config.cell(Vec2::new(4.0, 2.0)), Some((3, 1)));
+    }
+
+    #[test]
+    fn front_rows_are_flipped() {
+        let config = config(TextFrameProjection::Front);
+        assert_eq!(config.cell(Vec2::new(0.0, 0.0)), Some((0, 1)));
+        assert_eq!(config.cell(Vec2::new(3.5, 1.5)), Some((3, 0)));
+        assert_eq!(config.cell(Vec2::new(0.0, 2.0)), Some((0, 0)));
+    }
+
+    #[test]
+    fn positions_outside_the_region_have_no_cell() {
+        let config = config(TextFrameProjection::TopDown);
+        assert_eq!(config.cell(Vec2::new(-0.1, 1.0)), None);
+        assert_eq!(config.cell(Vec2::new(1.0, 2.1)), None);
+
+        let empty = TextFrameConfig { width: 0, ..config.clone() };
+        assert_eq!(empty.cell(Vec2::new(1.0, 1.0)), None);
+        let flat = TextFrameConfig { max: Vec2::new(4.0, 0.0), ..config };
+        assert_eq!(flat.cell(Vec2::new(1.0, 0.0)), None);
+    }
+
+    #[test]
+    fn set_writes_one_cell() {
+        let mut frame = TextFrame::new(3, 2, '.');
+        frame.set(1, 0, '@');
+        frame.set(2, 1, 'é');
+        // Outside the frame
+        frame.set(3, 0, '#');
+        frame.set(0, 2, '#');
+        assert_eq!(frame.get(1, 0), Some('@'));
+        assert_eq!(frame.get(3, 0), None);
+        assert_eq!(frame.to_text(), ".@.\n..é");
+    }
+
+    #[test]
+    fn empty_frames_have_empty_rows() {
+        assert_eq!(TextFrame::new(0, 2, '.').to_text(), "\n");
+        assert_eq!(TextFrame::new(2, 0, '.').to_text(), "");
+    }
+
+    #[test]
+    fn media_ref_is_a_percent_encoded_data_uri() {
+        let mut frame = TextFrame::new(2, 2, '.');
+        frame.set(0, 0, '#');
+        frame.set(1, 1, 'é');
+        let media_ref = frame.to_media_ref(7);
+        assert_eq!(media_ref.ts, 7);
+        assert_eq!(media_ref.uri, "data:text/plain;charset=utf-8,%23.%0A.%C3%A9");
+    }
+
+    #[test]
+    fn unreserved_characters_are_not_encoded() {
+        assert_eq!(percent_encode("aZ09-_.~"), "aZ09-_.~");
+        assert_eq!(percent_encode("a b/c"), "a%20b%2Fc");
+    }
+}
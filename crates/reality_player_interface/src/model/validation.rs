@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use bevy::prelude::{Event, Resource};
+use serde::{Deserialize, Serialize};
+
+use super::compatibility::ManifestHandshake;
+use super::custom_types::{GameAction, GameEvent};
+use super::game_update::{RoutedGameUpdate, UpdateRecipient};
+use super::manifest::PlayerInterfaceManifest;
+use super::player::PlayerId;
+use super::player_update::{GameInputEvent, InputEventType};
+
+// An input from an untrusted source (e.g. a remote agent), to be validated
+// before it is injected into the game as a `GameInputEvent`
+#[derive(Debug, Clone, Serialize, Deserialize, Event)]
+pub struct GameInputRequest<GA> where GA: GameAction {
+    pub input: GameInputEvent<GA>,
+}
+
+impl<GA> GameInputRequest<GA> where GA: GameAction {
+    pub fn new(input: GameInputEvent<GA>) -> Self {
+        GameInputRequest { input }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ActionRejectionReason {
+    // The action is not listed in the manifest's `actions_global`
+    NotInManifest,
+    // The action is not in the latest advertised `actions_current`
+    NotCurrentlyAvailable,
+    // The player's expected manifest was refused by the `ManifestHandshake`
+    ManifestRefused,
+    // The player sent more actions than the manifest's `tick_rate` allows (see `InputRateLimiter`)
+    RateLimited { tick_rate: u32 },
+}
+
+// Sent when a `GameInputRequest` is rejected
+// Serializable so it can be returned to the sender
+#[derive(Debug, Clone, Serialize, Deserialize, Event)]
+pub struct ActionRejection<GA> where GA: GameAction {
+    pub input: GameInputEvent<GA>,
+    pub reason: ActionRejectionReason,
+}
+
+#[derive(Debug, Clone, Resource)]
+pub struct ActionValidator<GA> where GA: GameAction + PartialEq {
+    actions_global: Vec<GA>,
+    // `None` means every action in `actions_global` is currently available
+    actions_current: Option<Vec<GA>>,
+    // Overrides `actions_current` for players that were sent their own update
+    player_actions_current: HashMap<PlayerId, Vec<GA>>,
+    // Restrictions from each player's `ManifestHandshake`
+    // `None` if it was refused, otherwise the actions their manifest shares with the running one
+    player_actions_known: HashMap<PlayerId, Option<Vec<GA>>>,
+}
+
+impl<GA> ActionValidator<GA> where GA: GameAction + PartialEq {
+    pub fn from_manifest<GE>(manifest: &PlayerInterfaceManifest<GE, GA>) -> Self where GE: GameEvent {
+        ActionValidator {
+            actions_global: manifest
+                .actions_global
+                .iter()
+                .map(|action_descriptor| action_descriptor.action.clone())
+                .collect(),
+            actions_current: None,
+            player_actions_current: HashMap::new(),
+            player_actions_known: HashMap::new(),
+        }
+    }
+
+    pub fn set_actions_current(&mut self, actions_current: Option<Vec<GA>>) {
+        self.actions_current = actions_current;
+        self.player_actions_current.clear();
+    }
+
+    // Restricts the player to what the handshake of their `expected` manifest allows
+    pub fn apply_handshake<GE>(
+        &mut self,
+        player: PlayerId,
+        expected: &PlayerInterfaceManifest<GE, GA>,
+        handshake: &ManifestHandshake,
+    ) where GE: GameEvent {
+        match handshake {
+            ManifestHandshake::Accepted(_) => {
+                self.player_actions_known.remove(&player);
+            }
+            ManifestHandshake::Degraded(_) => {
+                let actions_known = expected
+                    .actions_global
+                    .iter()
+                    .map(|action_descriptor| action_descriptor.action.clone())
+                    .filter(|action| self.actions_global.contains(action))
+                    .collect();
+                self.player_actions_known.insert(player, Some(actions_known));
+            }
+            ManifestHandshake::Refused(_) => {
+                self.player_actions_known.insert(player, None);
+            }
+        }
+    }
+
+    // Keep track of the actions advertised to players
+    pub fn observe_update<GE>(&mut self, routed: &RoutedGameUpdate<GE, GA>) where GE: GameEvent {
+        let Some(actions_current) = &routed.update.actions_current else {
+            return;
+        };
+        match &routed.recipient {
+            UpdateRecipient::All => self.set_actions_current(Some(actions_current.clone())),
+            UpdateRecipient::Player(player) => {
+                self.player_actions_current.insert(player.clone(), actions_current.clone());
+            }
+        }
+    }
+
+    // Rate limiting is left to the per-player `InputRateLimiter`
+    pub fn validate(&self, input: &GameInputEvent<GA>) -> Result<(), ActionRejectionReason> {
+        if !self.actions_global.contains(&input.action) {
+            return Err(ActionRejectionReason::NotInManifest);
+        }
+        match self.player_actions_known.get(&input.player) {
+            Some(None) => return Err(ActionRejectionReason::ManifestRefused),
+            Some(Some(actions_known)) if !actions_known.contains(&input.action) => {
+                return Err(ActionRejectionReason::NotInManifest);
+            }
+            _ => {}
+        }
+
+        // Always allow ending an action, so one that began while it was available can't get stuck
+        if input.event_type == InputEventType::End {
+            return Ok(());
+        }
+
+        let actions_current = self
+            .player_actions_current
+            .get(&input.player)
+            .or(self.actions_current.as_ref());
+        if let Some(actions_current) = actions_current {
+            if !actions_current.contains(&input.action) {
+                return Err(ActionRejectionReason::NotCurrentlyAvailable);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::compatibility::ManifestDiff;
+    use crate::model::manifest::ActionDescriptor;
+
+    fn manifest(actions: &[&'static str]) -> PlayerInterfaceManifest<&'static str, &'static str> {
+        PlayerInterfaceManifest {
+            name: "game".to_string(),
+            version: 1,
+            tick_rate: 10,
+            hint_text: None,
+            events_global: vec![],
+            actions_global: actions.iter().map(|action| ActionDescriptor::new(*action)).collect(),
+        }
+    }
+
+    fn begin(player: u32, action: &'static str) -> GameInputEvent<&'static str> {
+        GameInputEvent::new(PlayerId::Local(player), action, InputEventType::Begin)
+    }
+
+    #[test]
+    fn rejects_actions_outside_the_manifest() {
+        let validator = ActionValidator::from_manifest(&manifest(&["jump"]));
+        assert_eq!(validator.validate(&begin(0, "jump")), Ok(()));
+        assert_eq!(validator.validate(&begin(0, "fly")), Err(ActionRejectionReason::NotInManifest));
+    }
+
+    #[test]
+    fn degraded_handshake_limits_the_player_to_shared_actions() {
+        let mut validator = ActionValidator::from_manifest(&manifest(&["jump", "duck"]));
+        let handshake = ManifestHandshake::Degraded(ManifestDiff::default());
+        validator.apply_handshake(PlayerId::Local(0), &manifest(&["jump", "roll"]), &handshake);
+        assert_eq!(validator.validate(&begin(0, "jump")), Ok(()));
+        assert_eq!(validator.validate(&begin(0, "duck")), Err(ActionRejectionReason::NotInManifest));
+        // Other players are unaffected
+        assert_eq!(validator.validate(&begin(1, "duck")), Ok(()));
+    }
+
+    #[test]
+    fn refused_handshake_rejects_everything_until_accepted() {
+        let mut validator = ActionValidator::from_manifest(&manifest(&["jump"]));
+        let expected = manifest(&["jump"]);
+        validator.apply_handshake(PlayerId::Local(0), &expected, &ManifestHandshake::Refused(ManifestDiff::default()));
+        assert_eq!(validator.validate(&begin(0, "jump")), Err(ActionRejectionReason::ManifestRefused));
+        validator.apply_handshake(PlayerId::Local(0), &expected, &ManifestHandshake::Accepted(ManifestDiff::default()));
+        assert_eq!(validator.validate(&begin(0, "jump")), Ok(()));
+    }
+}
@@ -0,0 +1,39 @@
+// Bevy plugin for validating and rate limiting `GameInputRequest`s before they become `GameInputEvent`s.
+
+use bevy::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    model::{
+        compatibility::{ManifestHandshakeRequest, ManifestHandshakeResult},
+        custom_types::{GameAction, GameEvent},
+        game_update::RoutedGameUpdate,
+        manifest::PlayerInterfaceManifest,
+        player_update::GameInputEvent,
+        rate_limit::InputRateLimiter,
+        validation::{ActionRejection, ActionValidator, GameInputRequest},
+    },
+    system::validate_game_input::{log_action_rejections, negotiate_manifests, observe_game_updates, validate_game_input},
+};
+
+pub struct ActionValidationPlugin<GE, GA> where GE: GameEvent + Serialize, GA: GameAction + PartialEq + Serialize {
+    pub manifest: PlayerInterfaceManifest<GE, GA>,
+}
+
+impl<GE, GA> Plugin for ActionValidationPlugin<GE, GA> where GE: GameEvent + Serialize, GA: GameAction + PartialEq + Serialize {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(ActionValidator::from_manifest(&self.manifest))
+            .insert_resource(InputRateLimiter::<GA>::new(self.manifest.tick_rate))
+            .insert_resource(self.manifest.clone())
+            .add_event::<GameInputRequest<GA>>()
+            .add_event::<GameInputEvent<GA>>()
+            .add_event::<ActionRejection<GA>>()
+            .add_event::<RoutedGameUpdate<GE, GA>>()
+            .add_event::<ManifestHandshakeRequest<GE, GA>>()
+            .add_event::<ManifestHandshakeResult>()
+            // Negotiate first, so the restrictions apply to inputs sent at the same time
+            .add_systems(PreUpdate, (negotiate_manifests::<GE, GA>, validate_game_input::<GA>).chain())
+            .add_systems(PostUpdate, (observe_game_updates::<GE, GA>, log_action_rejections::<GA>));
+    }
+}
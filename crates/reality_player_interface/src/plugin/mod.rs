@@ -1 +1,3 @@
 pub mod reality_input;
+pub mod text_frame;
+pub mod action_validation;
@@ -3,16 +3,17 @@
 
 use bevy::prelude::*;
 
-use crate::{model::{custom_types::GameAction, player_update::GameInputEvent}, system::bevy_keycode_to_action::bevy_keycode_to_action, KeyboardConfig};
+use crate::{model::{custom_types::GameAction, keyboard::KeyboardConfigs, player_update::GameInputEvent}, system::bevy_keycode_to_action::bevy_keycode_to_action, KeyboardConfig};
 
 pub struct RealityInputPlugin<GA> where GA: GameAction {
-    pub keyboard_config: KeyboardConfig<GA>
+    // One config per local player
+    pub keyboard_configs: Vec<KeyboardConfig<GA>>
 }
 
 impl<GA> Plugin for RealityInputPlugin<GA> where GA: GameAction {
     fn build(&self, app: &mut App) {
         app
-            .insert_resource(self.keyboard_config.clone())
+            .insert_resource(KeyboardConfigs { configs: self.keyboard_configs.clone() })
             .add_event::<GameInputEvent<GA>>()
             .add_systems(PreUpdate, bevy_keycode_to_action::<GA>);
     }
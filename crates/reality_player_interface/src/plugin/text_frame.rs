@@ -0,0 +1,19 @@
+// Bevy plugin for rendering the scene as a `TextFrame`.
+
+use bevy::prelude::*;
+
+use crate::{model::text_frame::{LatestTextFrame, TextFrameConfig}, system::render_text_frame::render_text_frame};
+
+#[derive(Default)]
+pub struct TextFramePlugin {
+    pub config: TextFrameConfig,
+}
+
+impl Plugin for TextFramePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.config.clone())
+            .init_resource::<LatestTextFrame>()
+            .add_systems(PostUpdate, render_text_frame.after(TransformSystem::TransformPropagate));
+    }
+}
@@ -3,21 +3,25 @@
 use bevy::input::keyboard::KeyboardInput;
 use bevy::input::ButtonState;
 use bevy::prelude::*;
-use crate::model::keyboard::{KeyCode as RKeyCode, KeyboardConfig};
+use crate::model::keyboard::{KeyCode as RKeyCode, KeyboardConfigs};
 use crate::model::custom_types::GameAction;
 use crate::model::player_update::{GameInputEvent, InputEventType};
 
 pub fn bevy_keycode_to_action<GA>(
     mut evr_kbd: EventReader<KeyboardInput>,
     mut game_action_events: EventWriter<GameInputEvent<GA>>,
-    config: Res<KeyboardConfig<GA>>,
+    configs: Res<KeyboardConfigs<GA>>,
 ) where GA: GameAction {
     for ev in evr_kbd.read() {
         let Some(key) = bevy_keycode_to_keyboard_config_key(ev.key_code) else {
             continue;
         };
 
-        if let Some(game_actions) = config.clone().get_actions(&key) {
+        // Several players can share a keyboard, each with their own bindings
+        for config in configs.configs.iter() {
+            let Some(game_actions) = config.get_actions(&key) else {
+                continue;
+            };
             for game_action in game_actions {
                 let action_type = match ev.state {
                     ButtonState::Pressed => InputEventType::Begin,
@@ -25,13 +29,14 @@ pub fn bevy_keycode_to_action<GA>(
                 };
 
                 debug!(
-                    "keycode: {:?}, state: {:?}, game action: {:?}, action type: {:?}",
+                    "keycode: {:?}, state: {:?}, player: {}, game action: {:?}, action type: {:?}",
                     ev.key_code,
                     ev.state,
+                    config.player(),
                     game_action,
                     action_type
                 );
-                game_action_events.send(GameInputEvent::new(game_action.clone(), action_type));
+                game_action_events.send(GameInputEvent::new(config.player().clone(), game_action.clone(), action_type));
             }
         }
     }
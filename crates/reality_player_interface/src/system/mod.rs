@@ -1 +1,3 @@
 pub mod bevy_keycode_to_action;
+pub mod render_text_frame;
+pub mod validate_game_input;
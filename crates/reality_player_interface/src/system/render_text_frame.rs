@@ -0,0 +1,31 @@
+// A bevy system that rasterizes `TextFrameGlyph` entities into a `TextFrame`, without any GPU
+
+use bevy::prelude::*;
+use crate::model::text_frame::{LatestTextFrame, TextFrame, TextFrameConfig, TextFrameGlyph, TextFrameProjection};
+
+pub fn render_text_frame(
+    query: Query<(&GlobalTransform, &TextFrameGlyph)>,
+    config: Res<TextFrameConfig>,
+    mut latest: ResMut<LatestTextFrame>,
+) {
+    let mut frame = TextFrame::new(config.width, config.height, config.background);
+    let mut layers = vec![i32::MIN; config.width * config.height];
+
+    for (transform, glyph) in query.iter() {
+        let translation = transform.translation();
+        let position = match config.projection {
+            TextFrameProjection::Front => translation.xy(),
+            TextFrameProjection::TopDown => translation.xz(),
+        };
+        let Some((column, row)) = config.cell(position) else {
+            continue;
+        };
+        let index = row * config.width + column;
+        if glyph.layer >= layers[index] {
+            layers[index] = glyph.layer;
+            frame.set(column, row, glyph.glyph);
+        }
+    }
+
+    latest.0 = Some(frame);
+}
@@ -0,0 +1,94 @@
+// Bevy systems that validate `GameInputRequest`s against the `ActionValidator`
+// and limit their rate per player with the `InputRateLimiter`
+
+use bevy::prelude::*;
+use serde::Serialize;
+use crate::model::compatibility::{ManifestHandshake, ManifestHandshakeRequest, ManifestHandshakeResult};
+use crate::model::custom_types::{GameAction, GameEvent};
+use crate::model::manifest::PlayerInterfaceManifest;
+use crate::model::game_update::RoutedGameUpdate;
+use crate::model::player_update::GameInputEvent;
+use crate::model::rate_limit::InputRateLimiter;
+use crate::model::validation::{ActionRejection, ActionValidator, GameInputRequest};
+
+pub fn validate_game_input<GA>(
+    mut evr_requests: EventReader<GameInputRequest<GA>>,
+    mut game_action_events: EventWriter<GameInputEvent<GA>>,
+    mut rejection_events: EventWriter<ActionRejection<GA>>,
+    validator: Res<ActionValidator<GA>>,
+    mut limiter: ResMut<InputRateLimiter<GA>>,
+    time: Option<Res<Time>>,
+) where GA: GameAction + PartialEq {
+    // The AO server has no `Time`, each run of the system counts as a game tick there
+    let now = match time {
+        Some(time) => time.elapsed_secs_f64(),
+        None => limiter.tick(),
+    };
+    for request in evr_requests.read() {
+        // Validate first, so the limiter only tracks Begins that could reach the game
+        let result = validator
+            .validate(&request.input)
+            .and_then(|()| limiter.allow(&request.input, now));
+        match result {
+            Ok(true) => {
+                game_action_events.send(request.input.clone());
+            }
+            // Merged into an earlier input
+            Ok(false) => {}
+            Err(reason) => {
+                rejection_events.send(ActionRejection {
+                    input: request.input.clone(),
+                    reason,
+                });
+            }
+        }
+    }
+    limiter.evict_idle(now);
+}
+
+// Negotiates the manifests clients expect, restricting their actions accordingly
+pub fn negotiate_manifests<GE, GA>(
+    mut evr_requests: EventReader<ManifestHandshakeRequest<GE, GA>>,
+    mut result_events: EventWriter<ManifestHandshakeResult>,
+    mut validator: ResMut<ActionValidator<GA>>,
+    manifest: Res<PlayerInterfaceManifest<GE, GA>>,
+) where GE: GameEvent + Serialize, GA: GameAction + PartialEq + Serialize {
+    for request in evr_requests.read() {
+        let handshake = match ManifestHandshake::negotiate(&request.expected, &manifest) {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                error!("failed to compare the manifest of {}: {err}", request.player);
+                continue;
+            }
+        };
+        validator.apply_handshake(request.player.clone(), &request.expected, &handshake);
+        result_events.send(ManifestHandshakeResult {
+            player: request.player.clone(),
+            handshake,
+        });
+    }
+}
+
+// Tracks the `actions_current` advertised to each player
+pub fn observe_game_updates<GE, GA>(
+    mut evr_updates: EventReader<RoutedGameUpdate<GE, GA>>,
+    mut validator: ResMut<ActionValidator<GA>>,
+) where GE: GameEvent, GA: GameAction + PartialEq {
+    for routed in evr_updates.read() {
+        validator.observe_update(routed);
+    }
+}
+
+pub fn log_action_rejections<GA>(
+    mut evr_rejections: EventReader<ActionRejection<GA>>,
+) where GA: GameAction {
+    for rejection in evr_rejections.read() {
+        warn!(
+            "rejected game action from {}: {:?}, action type: {:?}, reason: {:?}",
+            rejection.input.player,
+            rejection.input.action,
+            rejection.input.event_type,
+            rejection.reason
+        );
+    }
+}
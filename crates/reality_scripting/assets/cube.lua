@@ -1,22 +1,26 @@
 local app = ...
 
+-- persisted, so it survives hot reloads and is saved with the game
+local state = app:persist("cube", { frames = 0 })
+
 local move_system_params = {
     Commands,
     {
-        cube.CubeMarker.ref,
-        bevy_transform.components.transform.Transform.mut,
+        Transform.mut,
+        -- filters don't yield a value, they only restrict the matched entities
+        CubeMarker.with,
     },
-    bevy_time.time["Time<()>"].ref
+    Time.ref
 }
 function move_system(commands, query, time_res)
     local e = time_res:elapsed_secs()
     local d = time_res:delta_secs()
-    for _, transform in query:iter() do
-        print(e, d, transform.translation)
-        transform.translation.x = transform.translation.x + 0.01 -- * d
-        transform.translation.y = transform.translation.y + 0.005 -- * d
-        transform.translation.z = transform.translation.z + 0.0002 -- * d
+    state.frames = state.frames + 1
+    for transform in query:iter() do
+        print(state.frames, e, d, transform.translation)
+        transform.translation = transform.translation + Vec3.new(0.01, 0.005, 0.0002) -- * d
     end
 end
 
 app:register_system(move_system, move_system_params)
+
@@ -0,0 +1,21 @@
+-- behaviour script: each entity with `BluaScript(spin.lua)` gets its own copy of these globals
+turns = 0
+
+function on_spawn()
+    print("spinning", self)
+end
+
+function on_update(dt)
+    local transform = self:get(Transform.mut)
+    transform.rotation = transform.rotation * Quat.from_rotation_y(dt)
+    turns = turns + dt / (2 * math.pi)
+end
+
+-- the new version of the script keeps counting
+function on_reload(old_globals)
+    turns = old_globals.turns
+end
+
+function on_despawn()
+    print("stopped after", turns, "turns")
+end
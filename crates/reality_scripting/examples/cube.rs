@@ -6,7 +6,7 @@ use bevy::prelude::{Commands, Component, Reflect, Res, Startup, Transform, Vec3}
 use bevy::reflect::func::{ArgList, Return};
 use bevy::DefaultPlugins;
 use reality_scripting::asset_loader::LuaScript;
-use reality_scripting::{AppExtensionFunctionRegisterTrait, BluaScript, LuaPlugin};
+use reality_scripting::{BluaScript, LuaPlugin};
 use std::any::{Any, TypeId};
 use std::ops::Add;
 
@@ -19,16 +19,6 @@ fn main() {
     .add_plugins(LuaPlugin);
     app.register_type::<CubeMarker>();
     app.register_type::<Transform>();
-    app.register_object_function::<Time<()>>(
-        Time::<()>::elapsed_secs
-            .into_function()
-            .with_name("elapsed_secs"),
-    );
-    app.register_object_function::<Time<()>>(
-        Time::<()>::delta_secs
-            .into_function()
-            .with_name("delta_secs"),
-    );
     app.add_systems(Startup, setup);
     app.run();
 }
@@ -66,6 +56,8 @@ fn setup(
         CubeMarker {
             a: 10.0
         },
+        // a behaviour script, run for this entity
+        BluaScript(asset_server.load("spin.lua")),
     ));
     // light
     commands.spawn((
@@ -81,7 +73,9 @@ fn setup(
         Transform::from_xyz(-2.5, 4.5, 9.0).looking_at(Vec3::ZERO, Vec3::Y),
     ));
 
-    commands.spawn(BluaScript(asset_server.load("cube.lua")));
+    commands.spawn(HandleHolder {
+        handle: asset_server.load("cube.lua"),
+    });
 }
 
 #[derive(Component)]
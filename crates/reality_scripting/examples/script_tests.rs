@@ -0,0 +1,46 @@
+use bevy::prelude::*;
+use reality_scripting::testing::ScriptTest;
+use reality_scripting::BluaScript;
+
+const MATH_TESTS: &str = r#"
+local function clamp(x, lo, hi)
+    return math.max(lo, math.min(hi, x))
+end
+
+function test_clamp()
+    assert(clamp(5, 0, 1) == 1)
+    assert(clamp(-5, 0, 1) == 0)
+end
+
+function test_vectors()
+    local v = Vec3.new(1, 2, 3) + Vec3.new(1, 1, 1)
+    assert(v.x == 2 and v.y == 3 and v.z == 4, "unexpected " .. tostring(v))
+end
+"#;
+
+// runs headless, exits with an error if a check fails
+fn main() {
+    let mut test = ScriptTest::new();
+    test.app.register_type::<Transform>();
+
+    // spin.lua turns its entity by `dt` radians every update
+    let spinning = test.world_mut().spawn(Transform::default()).id();
+    let spin = test.load("spin.lua").expect("spin.lua should load");
+    test.world_mut().entity_mut(spinning).insert(BluaScript(spin));
+    test.run(60);
+    test.assert_no_errors();
+    let rotation = test.component::<Transform>(spinning).rotation;
+    assert!(
+        rotation.angle_between(Quat::IDENTITY) > 0.9,
+        "expected about a radian of rotation, got {rotation}"
+    );
+
+    let math_tests = test
+        .load_str("math_tests.lua", MATH_TESTS)
+        .expect("math_tests.lua should load");
+    let report = test.run_tests(&math_tests);
+    println!("{report}");
+    if report.failed() > 0 {
+        std::process::exit(1);
+    }
+}
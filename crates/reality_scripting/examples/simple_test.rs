@@ -21,11 +21,6 @@ fn main() {
         Stretch::get_sum_with
             .into_function(),
     );
-    app.register_object_function::<Time<()>>(
-        Time::<()>::elapsed_secs
-            .into_function()
-            .with_name("elapsed_secs"),
-    );
     app.run();
 }
 
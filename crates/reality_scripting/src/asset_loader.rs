@@ -1,13 +1,28 @@
+use crate::callback::ScriptLifetime;
+use crate::module::{load_modules, resolve_requires, LuaModule};
 use crate::reflect::LuaSystem;
 use bevy::asset::io::Reader;
 use bevy::asset::{AssetLoader, AssetPath, LoadContext, UntypedAssetId, VisitAssetDependencies};
 use bevy::prelude::*;
 use flume::{Receiver, Sender};
+use piccolo::StashedTable;
 use send_wrapper::SendWrapper;
+use std::collections::HashMap;
 
 pub struct LuaAssetLoader {
-    pub lua_script_rx: Receiver<LuaScript>,
-    pub lua_script_bytes_tx: Sender<(Vec<u8>, AssetPath<'static>)>,
+    /// Errors while running the script are reported as `ScriptError`s, and fail the load
+    pub lua_script_rx: Receiver<Result<LuaScript, anyhow::Error>>,
+    pub lua_script_bytes_tx: Sender<LuaSource>,
+}
+
+/// A script to run, with the modules it requires
+pub struct LuaSource {
+    pub path: AssetPath<'static>,
+    pub bytes: Vec<u8>,
+    /// What the script's `require` calls resolve to
+    pub requires: HashMap<String, AssetPath<'static>>,
+    /// Dependencies come before the modules requiring them
+    pub modules: Vec<LuaModule>,
 }
 
 impl AssetLoader for LuaAssetLoader {
@@ -23,29 +38,66 @@ impl AssetLoader for LuaAssetLoader {
     ) -> Result<Self::Asset, Self::Error> {
         let mut bytes = vec![];
         reader.read_to_end(&mut bytes).await?;
+        let path = load_context.asset_path().clone();
+        let requires = resolve_requires(&path, &bytes)?;
+        let modules = load_modules(load_context, &requires).await?;
+        let module_handles = modules
+            .iter()
+            .map(|module| load_context.load::<LuaModule>(module.path.clone()))
+            .collect();
         self.lua_script_bytes_tx
-            .send((bytes, load_context.asset_path().clone()))
-            .unwrap();
-        let lua_script = self.lua_script_rx.recv_async().await?;
+            .send(LuaSource {
+                path,
+                bytes,
+                requires,
+                modules,
+            })
+            .map_err(|_| anyhow::anyhow!("lua scripts are no longer being loaded"))?;
+        let mut lua_script = self.lua_script_rx.recv_async().await??;
+        lua_script.modules = module_handles;
         Ok(lua_script)
     }
 }
 
 #[derive(TypePath)]
 pub struct LuaScript {
+    pub path: AssetPath<'static>,
+    /// Changes every time the script is loaded, so behaviour script instances know to reload
+    pub version: u64,
+    pub source: Vec<u8>,
+    /// What the script's `require` calls resolve to
+    pub requires: HashMap<String, AssetPath<'static>>,
     pub systems: SendWrapper<Vec<LuaSystem>>,
+    /// The table the script returned, handed to `on_reload` of the next version
+    pub state: SendWrapper<Option<StashedTable>>,
+    /// The script's own globals
+    pub env: SendWrapper<Option<StashedTable>>,
+    /// Modules the script requires, directly or through other modules
+    pub modules: Vec<Handle<LuaModule>>,
+    /// Invalidates the `LuaCallback`s the script created once it's dropped
+    pub lifetime: ScriptLifetime,
+}
+
+impl Drop for LuaScript {
+    fn drop(&mut self) {
+        self.lifetime.end();
+    }
 }
 
 impl VisitAssetDependencies for LuaScript {
-    fn visit_dependencies(&self, _visit: &mut impl FnMut(UntypedAssetId)) {}
+    fn visit_dependencies(&self, visit: &mut impl FnMut(UntypedAssetId)) {
+        for module in &self.modules {
+            visit(module.id().untyped());
+        }
+    }
 }
 
 impl Asset for LuaScript {}
 
 #[derive(Resource)]
 pub struct LuaAssetCommunicator {
-    pub lua_script_tx: Sender<LuaScript>,
-    pub lua_script_bytes_rx: Receiver<(Vec<u8>, AssetPath<'static>)>,
+    pub lua_script_tx: Sender<Result<LuaScript, anyhow::Error>>,
+    pub lua_script_bytes_rx: Receiver<LuaSource>,
 }
 
 impl FromWorld for LuaAssetLoader {
@@ -1,11 +1,302 @@
-// Wraps bevy things like Commands, Asset handling and Resources
+// Wraps bevy things like Time, Input, Asset handling, States and logging for scripts
 
+use crate::convert::{registration_by_name, type_registry};
+use crate::entity::EntityHandle;
+use crate::event::ReflectLuaEvent;
+use crate::owned_to_lua;
+use crate::reflect::{ObjectFunctionRegistry, ReflectPtr};
+use crate::userdata::{UserDataPtr, ValueExt};
+use crate::{AppExtensionFunctionRegisterTrait, LuaVm};
+use anyhow::anyhow;
+use bevy::asset::{AssetPath, LoadState, ReflectHandle};
 use bevy::prelude::*;
+use bevy::reflect::{FromType, GetTypeRegistration, PartialReflect, Typed};
+use bevy::state::state::FreelyMutableState;
+use piccolo::{Callback, CallbackReturn, Context, Table, Value, Variadic};
+use std::cell::RefCell;
+use std::rc::Rc;
 
+/// Registers the functions and globals making up the scripting API for bevy's own types, added by `LuaPlugin`
 pub struct BevyWrapperPlugin;
 
+/// Lets scripts load assets of this type with `assets.load(path, "TypeName")`,
+/// added by `app.register_lua_asset::<A>()`
+#[derive(Clone)]
+pub struct ReflectLuaAsset {
+    /// Loads the asset, returning its reflected `Handle<A>`
+    pub load: fn(&AssetServer, AssetPath<'static>) -> Box<dyn PartialReflect>,
+}
+
+impl<A: Asset> FromType<A> for ReflectLuaAsset {
+    fn from_type() -> Self {
+        Self {
+            load: |asset_server, path| Box::new(asset_server.load::<A>(path)),
+        }
+    }
+}
+
+pub trait AppExtensionBevyWrapperTrait {
+    /// Lets scripts load assets of this type, and pass their handles around
+    fn register_lua_asset<A: Asset + Reflect + FromReflect + GetTypeRegistration>(
+        &mut self,
+    ) -> &mut Self;
+    /// Lets scripts read and send events of a type that can't have `#[reflect(LuaEvent)]`,
+    /// like `GameInputEvent<MyAction>` from another crate. Also adds the event
+    fn register_lua_event<E: Event + Reflect + FromReflect + TypePath + GetTypeRegistration>(
+        &mut self,
+    ) -> &mut Self;
+    /// Lets scripts read `State<S>` and change it through `NextState<S>:set("Variant")`
+    fn register_lua_state<S: FreelyMutableState + FromReflect + GetTypeRegistration + Typed>(
+        &mut self,
+    ) -> &mut Self;
+}
+
+impl AppExtensionBevyWrapperTrait for App {
+    fn register_lua_asset<A: Asset + Reflect + FromReflect + GetTypeRegistration>(
+        &mut self,
+    ) -> &mut Self {
+        self.register_asset_reflect::<A>()
+            .register_type_data::<A, ReflectLuaAsset>()
+            .register_type_data::<Handle<A>, ReflectHandle>()
+    }
+
+    fn register_lua_event<E: Event + Reflect + FromReflect + TypePath + GetTypeRegistration>(
+        &mut self,
+    ) -> &mut Self {
+        self.add_event::<E>()
+            .register_type::<E>()
+            .register_type_data::<E, ReflectLuaEvent>()
+    }
+
+    fn register_lua_state<S: FreelyMutableState + FromReflect + GetTypeRegistration + Typed>(
+        &mut self,
+    ) -> &mut Self {
+        self.register_type::<S>()
+            .register_type::<State<S>>()
+            .register_type::<NextState<S>>();
+        self.register_object_function::<NextState<S>>(
+            NextState::<S>::set.into_function().with_name("set"),
+        );
+        self.register_object_function::<NextState<S>>(
+            NextState::<S>::reset.into_function().with_name("reset"),
+        );
+        self
+    }
+}
+
+macro_rules! register_time_functions {
+    ($app:ident, $($ty:ty),*) => {
+        $(
+            $app.register_type::<$ty>();
+            $app.register_object_function::<$ty>(
+                <$ty>::elapsed_secs.into_function().with_name("elapsed_secs"),
+            );
+            $app.register_object_function::<$ty>(
+                <$ty>::elapsed_secs_f64.into_function().with_name("elapsed_secs_f64"),
+            );
+            $app.register_object_function::<$ty>(
+                <$ty>::delta_secs.into_function().with_name("delta_secs"),
+            );
+            $app.register_object_function::<$ty>(
+                <$ty>::delta_secs_f64.into_function().with_name("delta_secs_f64"),
+            );
+        )*
+    };
+}
+
+macro_rules! register_input_functions {
+    ($app:ident, $($ty:ty),*) => {
+        $(
+            $app.register_type::<$ty>().register_type::<ButtonInput<$ty>>();
+            $app.register_object_function::<ButtonInput<$ty>>(
+                ButtonInput::<$ty>::pressed.into_function().with_name("pressed"),
+            );
+            $app.register_object_function::<ButtonInput<$ty>>(
+                ButtonInput::<$ty>::just_pressed
+                    .into_function()
+                    .with_name("just_pressed"),
+            );
+            $app.register_object_function::<ButtonInput<$ty>>(
+                ButtonInput::<$ty>::just_released
+                    .into_function()
+                    .with_name("just_released"),
+            );
+        )*
+    };
+}
+
+fn fixed_timestep_secs(time: &Time<Fixed>) -> f32 {
+    time.timestep().as_secs_f32()
+}
+
 impl Plugin for BevyWrapperPlugin {
     fn build(&self, app: &mut App) {
-        todo!()
+        register_time_functions!(app, Time<()>, Time<Real>, Time<Virtual>, Time<Fixed>);
+        app.register_object_function::<Time<Virtual>>(
+            Time::<Virtual>::pause.into_function().with_name("pause"),
+        );
+        app.register_object_function::<Time<Virtual>>(
+            Time::<Virtual>::unpause.into_function().with_name("unpause"),
+        );
+        app.register_object_function::<Time<Virtual>>(
+            Time::<Virtual>::is_paused.into_function().with_name("is_paused"),
+        );
+        app.register_object_function::<Time<Virtual>>(
+            Time::<Virtual>::relative_speed
+                .into_function()
+                .with_name("relative_speed"),
+        );
+        app.register_object_function::<Time<Virtual>>(
+            Time::<Virtual>::set_relative_speed
+                .into_function()
+                .with_name("set_relative_speed"),
+        );
+        app.register_object_function::<Time<Fixed>>(
+            fixed_timestep_secs.into_function().with_name("timestep_secs"),
+        );
+        app.register_object_function::<Time<Fixed>>(
+            Time::<Fixed>::overstep_fraction
+                .into_function()
+                .with_name("overstep_fraction"),
+        );
+
+        // keys and buttons are passed by name, e.g. `keys:pressed("KeyW")`
+        register_input_functions!(app, KeyCode, MouseButton);
+
+        app.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
+        let function_registry = app
+            .world()
+            .non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
+            .clone();
+        let asset_server = app.world().get_resource::<AssetServer>().cloned();
+        app.init_non_send_resource::<LuaVm>();
+        let mut lua = app.world_mut().non_send_resource_mut::<LuaVm>();
+        let result = lua.try_enter(|ctx| {
+            ctx.set_global("log", log_table(ctx)?);
+            if let Some(asset_server) = asset_server {
+                ctx.set_global("assets", assets_table(ctx, asset_server, function_registry)?);
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("couldn't expose the bevy wrapper to lua: {err}");
+        }
     }
 }
+
+/// `log.info(...)` etc., logging the arguments like `print` through `bevy::log`
+fn log_table<'gc>(ctx: Context<'gc>) -> Result<Table<'gc>, anyhow::Error> {
+    let t = Table::new(&ctx);
+    macro_rules! level {
+        ($name:literal, $macro:ident) => {
+            t.set(
+                ctx,
+                $name,
+                Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+                    let values: Variadic<Vec<Value>> = stack.consume(ctx)?;
+                    $macro!(target: "lua", "{}", log_message(&values)?);
+                    Ok(CallbackReturn::Return)
+                }),
+            )?;
+        };
+    }
+    level!("trace", trace);
+    level!("debug", debug);
+    level!("info", info);
+    level!("warn", warn);
+    level!("error", error);
+    Ok(t)
+}
+
+/// The values separated by tabs, with reflected values and entities formatted like `tostring`
+fn log_message(values: &[Value]) -> Result<String, anyhow::Error> {
+    let parts = values
+        .iter()
+        .map(|value| {
+            if let Ok(reflect_ptr) = value.as_static_user_data::<ReflectPtr>() {
+                return Ok(reflect_ptr.lua_to_string());
+            }
+            if let Ok(entity_handle) = value.as_static_user_data::<EntityHandle>() {
+                return Ok(entity_handle.lua_to_string());
+            }
+            Ok(match value {
+                Value::Nil => "nil".to_string(),
+                Value::Boolean(boolean) => boolean.to_string(),
+                Value::Integer(integer) => integer.to_string(),
+                Value::Number(number) => number.to_string(),
+                Value::String(string) => string.to_str()?.to_string(),
+                value => value.type_name().to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    Ok(parts.join("\t"))
+}
+
+/// `assets.load(path, "TypeName")`, `assets.load_state(handle)` and `assets.is_loaded(handle)`
+fn assets_table<'gc>(
+    ctx: Context<'gc>,
+    asset_server: AssetServer,
+    function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Result<Table<'gc>, anyhow::Error> {
+    let t = Table::new(&ctx);
+    let server = asset_server.clone();
+    t.set(
+        ctx,
+        "load",
+        Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let (path, type_name): (piccolo::String, piccolo::String) = stack.consume(ctx)?;
+            let type_name = type_name.to_str()?;
+            let handle = {
+                let registry = type_registry(ctx)?.read();
+                let load = registration_by_name(&registry, type_name)
+                    .and_then(|registration| registration.data::<ReflectLuaAsset>())
+                    .ok_or_else(|| {
+                        anyhow!("{type_name} can't be loaded from lua, register it with `app.register_lua_asset`")
+                    })?
+                    .load;
+                load(&server, AssetPath::from(path.to_str()?.to_string()))
+            };
+            stack.replace(ctx, owned_to_lua(ctx, handle, function_registry.clone())?);
+            Ok(CallbackReturn::Return)
+        }),
+    )?;
+    let server = asset_server.clone();
+    t.set(
+        ctx,
+        "load_state",
+        Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let handle: Value = stack.consume(ctx)?;
+            let state = match server.load_state(untyped_handle(ctx, handle)?.id()) {
+                LoadState::NotLoaded => "NotLoaded",
+                LoadState::Loading => "Loading",
+                LoadState::Loaded => "Loaded",
+                LoadState::Failed(_) => "Failed",
+            };
+            stack.replace(ctx, piccolo::String::from_slice(&ctx, state));
+            Ok(CallbackReturn::Return)
+        }),
+    )?;
+    let server = asset_server;
+    t.set(
+        ctx,
+        "is_loaded",
+        Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let handle: Value = stack.consume(ctx)?;
+            let loaded = server.is_loaded_with_dependencies(untyped_handle(ctx, handle)?.id());
+            stack.replace(ctx, loaded);
+            Ok(CallbackReturn::Return)
+        }),
+    )?;
+    Ok(t)
+}
+
+/// A reflected `Handle<A>` of an asset registered with `register_lua_asset`
+fn untyped_handle<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Result<UntypedHandle, anyhow::Error> {
+    let reflect_ptr = value.as_static_user_data::<ReflectPtr>()?;
+    let handle = reflect_ptr.get_field_value_ref()?;
+    let registry = type_registry(ctx)?.read();
+    registry
+        .get_type_data::<ReflectHandle>(handle.as_any().type_id())
+        .and_then(|reflect_handle| reflect_handle.downcast_handle_untyped(handle.as_any()))
+        .ok_or_else(|| anyhow!("expected an asset handle, got {}", handle.reflect_type_path()))
+}
@@ -0,0 +1,248 @@
+// Lua functions passed to registered Rust functions, to be called back later
+
+use crate::convert::{reflect_from_lua, reflect_to_lua, type_registry};
+use crate::instance::with_system_context;
+use crate::reflect::ObjectFunctionRegistry;
+use crate::sandbox::{self, LuaSandbox};
+use crate::userdata::ValueExt;
+use crate::{concrete_value, owned_to_lua, LuaVm};
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy::reflect::{PartialReflect, Typed};
+use piccolo::{Context, Executor, Function, Table, UserData, Value, Variadic};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Global holding the `ScriptLifetime` of the script that's currently running
+const CURRENT_SCRIPT_GLOBAL: &str = "__current_script";
+
+/// Global holding the `CallbackRegistry`
+const CALLBACK_REGISTRY_GLOBAL: &str = "__callback_registry";
+
+/// Global table of the functions `LuaCallback`s refer to, by id
+const CALLBACKS_GLOBAL: &str = "__callbacks";
+
+/// Ends when the version of the script it belongs to is dropped, e.g. replaced by a hot reload
+#[derive(Clone, Debug)]
+pub struct ScriptLifetime(Arc<AtomicBool>);
+
+impl ScriptLifetime {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn end(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Default for ScriptLifetime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marks the script functions passed to Rust belong to, until the next call
+pub fn set_current_script(lua: &mut LuaVm, lifetime: &ScriptLifetime) {
+    swap_current_script(lua, Some(lifetime.clone()));
+}
+
+/// Sets the current script, returning the previous one so it can be put back
+fn swap_current_script(lua: &mut LuaVm, lifetime: Option<ScriptLifetime>) -> Option<ScriptLifetime> {
+    lua.enter(|ctx| {
+        let previous = current_script(ctx);
+        match lifetime {
+            Some(lifetime) => ctx.set_global(CURRENT_SCRIPT_GLOBAL, UserData::new_static(&ctx, lifetime)),
+            None => ctx.set_global(CURRENT_SCRIPT_GLOBAL, Value::Nil),
+        };
+        previous
+    })
+}
+
+fn current_script(ctx: Context) -> Option<ScriptLifetime> {
+    ctx.globals()
+        .get::<_, Value>(ctx, CURRENT_SCRIPT_GLOBAL)
+        .ok()
+        .and_then(|value| value.as_static_user_data::<ScriptLifetime>().ok().cloned())
+}
+
+/// Hands out the ids of `LuaCallback`s, and learns which ones were dropped.
+/// Lives in the lua vm, so only the main thread touches the functions
+struct CallbackRegistry {
+    next_id: Cell<u64>,
+    dropped_tx: flume::Sender<u64>,
+    dropped_rx: flume::Receiver<u64>,
+}
+
+impl CallbackRegistry {
+    fn get(ctx: Context<'_>) -> Result<&CallbackRegistry, anyhow::Error> {
+        if let Ok(registry) = ctx
+            .globals()
+            .get::<_, Value>(ctx, CALLBACK_REGISTRY_GLOBAL)?
+            .as_static_user_data::<CallbackRegistry>()
+        {
+            return Ok(registry);
+        }
+        let (dropped_tx, dropped_rx) = flume::unbounded();
+        let registry = CallbackRegistry {
+            next_id: Cell::new(0),
+            dropped_tx,
+            dropped_rx,
+        };
+        ctx.set_global(CALLBACK_REGISTRY_GLOBAL, UserData::new_static(&ctx, registry));
+        ctx.set_global(CALLBACKS_GLOBAL, Table::new(&ctx));
+        ctx.globals()
+            .get::<_, Value>(ctx, CALLBACK_REGISTRY_GLOBAL)?
+            .as_static_user_data::<CallbackRegistry>()
+            .map_err(|err| anyhow!("{err}"))
+    }
+
+    /// The table of functions, without the ones whose callbacks were dropped since the last call
+    fn functions<'gc>(&self, ctx: Context<'gc>) -> Result<Table<'gc>, anyhow::Error> {
+        let Value::Table(functions) = ctx.globals().get::<_, Value>(ctx, CALLBACKS_GLOBAL)? else {
+            return Err(anyhow!("the lua callbacks were removed"));
+        };
+        for id in self.dropped_rx.try_iter() {
+            functions.set(ctx, id as i64, Value::Nil)?;
+        }
+        Ok(functions)
+    }
+}
+
+/// Removes the function from the registry once the last clone of its `LuaCallback` is dropped,
+/// from whichever thread that happens on
+#[derive(Debug)]
+struct CallbackHandle {
+    id: u64,
+    dropped: flume::Sender<u64>,
+}
+
+impl Drop for CallbackHandle {
+    fn drop(&mut self) {
+        let _ = self.dropped.send(self.id);
+    }
+}
+
+/// A lua function, taken as an argument by registered functions like `fn on_timer(callback: LuaCallback)`.
+/// It can be kept and called later from Rust systems, until its script is unloaded.
+/// It only holds an id, so it can be stored in components and resources, and cloned or dropped on any thread
+#[derive(Reflect, Clone, Default, Debug)]
+pub struct LuaCallback {
+    #[reflect(ignore)]
+    handle: Option<Arc<CallbackHandle>>,
+    #[reflect(ignore)]
+    lifetime: Option<ScriptLifetime>,
+}
+
+impl LuaCallback {
+    pub fn new<'gc>(ctx: Context<'gc>, function: Function<'gc>) -> Self {
+        let handle = CallbackRegistry::get(ctx).and_then(|registry| {
+            let id = registry.next_id.get();
+            registry.next_id.set(id + 1);
+            registry.functions(ctx)?.set(ctx, id as i64, function)?;
+            Ok(Arc::new(CallbackHandle {
+                id,
+                dropped: registry.dropped_tx.clone(),
+            }))
+        });
+        if let Err(err) = &handle {
+            error!("couldn't keep a lua callback: {err}");
+        }
+        Self {
+            handle: handle.ok(),
+            lifetime: current_script(ctx),
+        }
+    }
+
+    /// False once the script that passed the function was unloaded or reloaded
+    pub fn is_valid(&self) -> bool {
+        self.handle.is_some() && self.lifetime.as_ref().is_none_or(ScriptLifetime::is_alive)
+    }
+
+    /// Calls the function with copies of the arguments, with entities and `Commands` usable like in a system.
+    /// Can't be called while lua is running, e.g. from a function called by a script
+    pub fn call(&self, world: &mut World, args: &[&dyn PartialReflect]) -> Result<(), anyhow::Error> {
+        self.call_lua(world, args, |_ctx, _value, _registry| Ok(()))
+    }
+
+    /// Like `call`, converting the value the function returned to `R`
+    pub fn call_with_result<R: FromReflect + Typed>(
+        &self,
+        world: &mut World,
+        args: &[&dyn PartialReflect],
+    ) -> Result<R, anyhow::Error> {
+        self.call_lua(world, args, |_ctx, value, registry| {
+            let reflect = reflect_from_lua(value, R::type_info(), registry)?;
+            R::from_reflect(reflect.as_ref())
+                .ok_or_else(|| anyhow!("lua callback didn't return a {}", R::type_path()))
+        })
+    }
+
+    fn call_lua<R>(
+        &self,
+        world: &mut World,
+        args: &[&dyn PartialReflect],
+        convert: impl for<'gc> FnOnce(Context<'gc>, Value<'gc>, &bevy::reflect::TypeRegistry) -> Result<R, anyhow::Error>,
+    ) -> Result<R, anyhow::Error> {
+        let (Some(handle), true) = (&self.handle, self.is_valid()) else {
+            return Err(anyhow!("lua callback's script was unloaded"));
+        };
+        let mut lua = world
+            .remove_non_send_resource::<LuaVm>()
+            .ok_or_else(|| anyhow!("lua callbacks can't be called while lua is running"))?;
+        let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
+        world.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
+        let function_registry = world
+            .non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
+            .clone();
+        let previous_script = self
+            .lifetime
+            .as_ref()
+            .map(|lifetime| swap_current_script(&mut lua, Some(lifetime.clone())));
+        let result = with_system_context(world, &mut lua, |_world, lua| {
+            let exec = lua
+                .try_enter(|ctx| {
+                    let registry = type_registry(ctx)?.read();
+                    let args = args
+                        .iter()
+                        .map(|arg| match reflect_to_lua(ctx, *arg) {
+                            Some(value) => Ok(value),
+                            None => owned_to_lua(
+                                ctx,
+                                concrete_value(*arg, &registry),
+                                function_registry.clone(),
+                            ),
+                        })
+                        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+                    let function = match CallbackRegistry::get(ctx)?
+                        .functions(ctx)?
+                        .get::<_, Value>(ctx, handle.id as i64)?
+                    {
+                        Value::Function(function) => function,
+                        _ => return Err(anyhow!("lua callback's function is gone").into()),
+                    };
+                    Ok(ctx.stash(Executor::start(ctx, function, Variadic(args))))
+                })
+                .map_err(|err| anyhow!("{err}"))?;
+            sandbox::finish(lua, &exec, &sandbox)?;
+            lua.try_enter(|ctx| {
+                let value = ctx.fetch(&exec).take_result::<Value>(ctx)??;
+                let registry = type_registry(ctx)?.read();
+                Ok(convert(ctx, value, &registry)?)
+            })
+            .map_err(|err| anyhow!("{err}"))
+        });
+        // callbacks can be called from inside other scripts' hooks, which still expect to be current
+        if let Some(previous_script) = previous_script {
+            swap_current_script(&mut lua, previous_script);
+        }
+        world.insert_non_send_resource(lua);
+        result
+    }
+}
@@ -0,0 +1,853 @@
+// Converts between lua values and reflected values, driven by their `TypeInfo`
+
+use crate::entity::EntityHandle;
+use crate::reflect::ReflectPtr;
+use crate::userdata::{UserDataPtr, ValueExt};
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy::reflect::{
+    DynamicEnum, DynamicList, DynamicMap, DynamicStruct, DynamicTuple, DynamicVariant, Enum,
+    EnumInfo, PartialReflect, ReflectMut, ReflectRef, TypeInfo, TypeRegistration, TypeRegistry,
+    VariantInfo, VariantType,
+};
+use piccolo::{Context, Table, Value};
+use std::any::TypeId;
+use std::collections::BTreeMap;
+
+/// Global holding the `AppTypeRegistry`, for conversions that need to build new values
+pub const TYPE_REGISTRY_GLOBAL: &str = "__type_registry";
+
+pub fn type_registry<'gc>(ctx: Context<'gc>) -> Result<&'gc AppTypeRegistry, anyhow::Error> {
+    Ok(ctx
+        .globals()
+        .get::<_, Value>(ctx, TYPE_REGISTRY_GLOBAL)?
+        .as_static_user_data::<AppTypeRegistry>()?)
+}
+
+/// Finds a registered type by its short (`Transform`) or full type path
+pub fn registration_by_name<'a>(
+    registry: &'a TypeRegistry,
+    name: &str,
+) -> Option<&'a TypeRegistration> {
+    registry
+        .get_with_short_type_path(name)
+        .or_else(|| registry.get_with_type_path(name))
+}
+
+/// Builds a value of the registered type from its `Default`, with the fields in `table` applied
+pub fn reflect_from_table(
+    registration: &TypeRegistration,
+    table: Table,
+    registry: &TypeRegistry,
+) -> Result<Box<dyn PartialReflect>, anyhow::Error> {
+    let type_path = registration.type_info().type_path();
+    reflect_from_lua(Value::Table(table), registration.type_info(), registry)
+        .map_err(|err| anyhow!("couldn't build {type_path}: {err}"))
+}
+
+/// Reads a table of components, either reflected values or `{TypeName = {field = value}}` entries
+pub fn components_from_table(
+    table: Table,
+    registry: &TypeRegistry,
+) -> Result<Vec<Box<dyn PartialReflect>>, anyhow::Error> {
+    components_from_entries(table, registry)
+}
+
+pub fn components_from_entries<'gc>(
+    entries: impl IntoIterator<Item = (Value<'gc>, Value<'gc>)>,
+    registry: &TypeRegistry,
+) -> Result<Vec<Box<dyn PartialReflect>>, anyhow::Error> {
+    let mut components = vec![];
+    for (key, value) in entries {
+        if let Ok(reflect_ptr) = value.as_static_user_data::<ReflectPtr>() {
+            components.push(reflect_ptr.get_field_value_ref()?.clone_value());
+            continue;
+        }
+        let (Value::String(name), Value::Table(fields)) = (key, value) else {
+            return Err(anyhow!(
+                "expected a reflected value or a `TypeName = {{ ... }}` entry"
+            ));
+        };
+        let name = name.to_str()?;
+        let registration = registration_by_name(registry, name)
+            .ok_or_else(|| anyhow!("no registered type named {name}"))?;
+        components.push(reflect_from_table(registration, fields, registry)?);
+    }
+    Ok(components)
+}
+
+/// Builds a value of the given type from a lua value
+pub fn reflect_from_lua(
+    value: Value,
+    type_info: &'static TypeInfo,
+    registry: &TypeRegistry,
+) -> Result<Box<dyn PartialReflect>, anyhow::Error> {
+    let mut reflect = default_value(type_info, registry)?;
+    apply_lua_value(reflect.as_mut(), value, registry)?;
+    Ok(reflect)
+}
+
+/// Converts values that have a lua equivalent (numbers, strings, unit variants, `None`, `()`)
+/// Everything else stays a reflected value, so it can still be modified in place
+pub fn reflect_to_lua<'gc>(ctx: Context<'gc>, reflect: &dyn PartialReflect) -> Option<Value<'gc>> {
+    macro_rules! integer {
+        ($($ty:ty),*) => {
+            $(
+                if let Some(value) = reflect.try_downcast_ref::<$ty>() {
+                    return i64::try_from(*value)
+                        .map(Value::Integer)
+                        .ok()
+                        .or(Some(Value::Number(*value as f64)));
+                }
+            )*
+        };
+    }
+    integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+    // functions without a return value
+    if reflect.try_downcast_ref::<()>().is_some() {
+        return Some(Value::Nil);
+    }
+    if let Some(value) = reflect.try_downcast_ref::<f32>() {
+        return Some(Value::Number(*value as f64));
+    }
+    if let Some(value) = reflect.try_downcast_ref::<f64>() {
+        return Some(Value::Number(*value));
+    }
+    if let Some(value) = reflect.try_downcast_ref::<bool>() {
+        return Some(Value::Boolean(*value));
+    }
+    if let Some(value) = reflect.try_downcast_ref::<String>() {
+        return Some(Value::String(piccolo::String::from_slice(&ctx, value)));
+    }
+    if let Some(value) = reflect.try_downcast_ref::<char>() {
+        return Some(Value::String(piccolo::String::from_slice(
+            &ctx,
+            value.to_string(),
+        )));
+    }
+    if let Some(entity) = reflect.try_downcast_ref::<Entity>() {
+        return Some(EntityHandle::new(*entity).into_value(&ctx));
+    }
+    let ReflectRef::Enum(enum_value) = reflect.reflect_ref() else {
+        return None;
+    };
+    if is_option(enum_value) {
+        return match enum_value.field_at(0) {
+            Some(inner) => reflect_to_lua(ctx, inner),
+            None => Some(Value::Nil),
+        };
+    }
+    match enum_value.variant_type() {
+        VariantType::Unit => Some(Value::String(piccolo::String::from_slice(
+            &ctx,
+            enum_value.variant_name(),
+        ))),
+        _ => None,
+    }
+}
+
+/// Whether `reflect` is an `Option` holding a value, which paths step into implicitly
+pub fn is_some(reflect: &dyn PartialReflect) -> bool {
+    match reflect.reflect_ref() {
+        ReflectRef::Enum(enum_value) => is_option(enum_value) && enum_value.field_len() == 1,
+        _ => false,
+    }
+}
+
+fn is_option(enum_value: &dyn Enum) -> bool {
+    enum_value
+        .get_represented_enum_info()
+        .is_some_and(is_option_info)
+}
+
+pub(crate) fn is_option_info(enum_info: &EnumInfo) -> bool {
+    enum_info.type_path_table().ident() == Some("Option")
+        && enum_info.contains_variant("Some")
+        && enum_info.contains_variant("None")
+}
+
+/// Looks up a field, element, map entry or variant field by a lua key
+/// Indices start at 1, like lua tables
+pub fn field_ref<'a>(
+    reflect: &'a dyn PartialReflect,
+    key: &str,
+) -> Result<&'a dyn PartialReflect, anyhow::Error> {
+    let field = match reflect.reflect_ref() {
+        ReflectRef::Struct(value) => value.field(key),
+        ReflectRef::TupleStruct(value) => value.field(index(key)?),
+        ReflectRef::Tuple(value) => value.field(index(key)?),
+        ReflectRef::List(value) => value.get(index(key)?),
+        ReflectRef::Array(value) => value.get(index(key)?),
+        ReflectRef::Map(value) => {
+            let key_info = value.get_represented_map_info().and_then(|info| info.key_info());
+            value.get(map_key(key, key_info)?.as_ref())
+        }
+        ReflectRef::Enum(value) => match value.variant_type() {
+            VariantType::Struct => value.field(key),
+            _ => value.field_at(index(key)?),
+        },
+        _ => None,
+    };
+    field.ok_or_else(|| no_field(reflect, key))
+}
+
+pub fn field_mut<'a>(
+    reflect: &'a mut dyn PartialReflect,
+    key: &str,
+) -> Result<&'a mut dyn PartialReflect, anyhow::Error> {
+    let type_path = reflect.reflect_type_path().to_string();
+    let field = match reflect.reflect_mut() {
+        ReflectMut::Struct(value) => value.field_mut(key),
+        ReflectMut::TupleStruct(value) => value.field_mut(index(key)?),
+        ReflectMut::Tuple(value) => value.field_mut(index(key)?),
+        ReflectMut::List(value) => value.get_mut(index(key)?),
+        ReflectMut::Array(value) => value.get_mut(index(key)?),
+        ReflectMut::Map(value) => {
+            let key_info = value.get_represented_map_info().and_then(|info| info.key_info());
+            value.get_mut(map_key(key, key_info)?.as_ref())
+        }
+        ReflectMut::Enum(value) => match value.variant_type() {
+            VariantType::Struct => value.field_mut(key),
+            _ => value.field_at_mut(index(key)?),
+        },
+        _ => None,
+    };
+    field.ok_or_else(|| anyhow!("{type_path} has no field {key}"))
+}
+
+fn no_field(reflect: &dyn PartialReflect, key: &str) -> anyhow::Error {
+    anyhow!("{} has no field {key}", reflect.reflect_type_path())
+}
+
+fn index(key: &str) -> Result<usize, anyhow::Error> {
+    key.parse::<usize>()
+        .ok()
+        .and_then(|index| index.checked_sub(1))
+        .ok_or_else(|| anyhow!("expected an index starting at 1, got {key}"))
+}
+
+/// Map keys arrive as strings, so parse them back into the key type
+fn map_key(
+    key: &str,
+    key_info: Option<&'static TypeInfo>,
+) -> Result<Box<dyn PartialReflect>, anyhow::Error> {
+    let type_id = key_info.map(|info| info.type_id());
+    macro_rules! parse_as {
+        ($($ty:ty),*) => {
+            $(
+                if type_id == Some(TypeId::of::<$ty>()) {
+                    let key = key
+                        .parse::<$ty>()
+                        .map_err(|_| anyhow!("expected a {} key, got {key}", stringify!($ty)))?;
+                    return Ok(Box::new(key));
+                }
+            )*
+        };
+    }
+    parse_as!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, bool, char);
+    if type_id.is_none() || type_id == Some(TypeId::of::<String>()) {
+        return Ok(Box::new(key.to_string()));
+    }
+    Err(anyhow!(
+        "can't use {key} as a key of type {}",
+        key_info.map_or("unknown", |info| info.type_path())
+    ))
+}
+
+/// A starting value to apply a lua value onto
+fn default_value(
+    type_info: &'static TypeInfo,
+    registry: &TypeRegistry,
+) -> Result<Box<dyn PartialReflect>, anyhow::Error> {
+    if let Some(reflect_default) = registry.get_type_data::<ReflectDefault>(type_info.type_id()) {
+        return Ok(reflect_default.default().into_partial_reflect());
+    }
+    // collections and options don't reflect `Default`, but start out empty
+    match type_info {
+        TypeInfo::List(_) => {
+            let mut list = DynamicList::default();
+            list.set_represented_type(Some(type_info));
+            Ok(Box::new(list))
+        }
+        TypeInfo::Map(_) => {
+            let mut map = DynamicMap::default();
+            map.set_represented_type(Some(type_info));
+            Ok(Box::new(map))
+        }
+        TypeInfo::Enum(enum_info) if is_option_info(enum_info) => {
+            let mut none = DynamicEnum::new("None", DynamicVariant::Unit);
+            none.set_represented_type(Some(type_info));
+            Ok(Box::new(none))
+        }
+        _ => Err(anyhow!(
+            "{} doesn't reflect Default, so it can't be built from lua",
+            type_info.type_path()
+        )),
+    }
+}
+
+/// Sets `target` from a lua value
+pub fn apply_lua_value(
+    target: &mut dyn PartialReflect,
+    value: Value,
+    registry: &TypeRegistry,
+) -> Result<(), anyhow::Error> {
+    if let ReflectMut::Enum(enum_value) = target.reflect_mut() {
+        if is_option(enum_value) {
+            return apply_option(enum_value, value, registry);
+        }
+    }
+    match value {
+        Value::Table(table) => apply_table(target, table, registry),
+        Value::Boolean(boolean) => set(target, boolean),
+        Value::Integer(integer) => set_integer(target, integer),
+        Value::Number(number) => set_number(target, number),
+        Value::String(string) => set_string(target, string.to_str()?, registry),
+        Value::UserData(_) => {
+            if let Ok(entity_handle) = value.as_static_user_data::<EntityHandle>() {
+                return set(target, entity_handle.entity);
+            }
+            let reflect_ptr = value.as_static_user_data::<ReflectPtr>()?;
+            let value = reflect_ptr.get_field_value_ref()?.clone_value();
+            Ok(target.try_apply(value.as_ref())?)
+        }
+        _ => Err(anyhow!(
+            "can't convert a lua {} to {}",
+            value.type_name(),
+            target.reflect_type_path()
+        )),
+    }
+}
+
+/// `nil` sets `None`, anything else is applied to the `Some` value
+fn apply_option(
+    target: &mut dyn Enum,
+    value: Value,
+    registry: &TypeRegistry,
+) -> Result<(), anyhow::Error> {
+    if let Value::Nil = value {
+        return set_variant(target, "None", None, registry);
+    }
+    match target.field_at_mut(0) {
+        Some(inner) => apply_lua_value(inner, value, registry),
+        None => set_variant(target, "Some", Some(value), registry),
+    }
+}
+
+fn apply_table(
+    target: &mut dyn PartialReflect,
+    table: Table,
+    registry: &TypeRegistry,
+) -> Result<(), anyhow::Error> {
+    let type_path = target.reflect_type_path().to_string();
+    if matches!(
+        target.reflect_ref(),
+        ReflectRef::Struct(_) | ReflectRef::TupleStruct(_) | ReflectRef::Tuple(_)
+    ) {
+        for (key, value) in table {
+            apply_lua_value(field_mut(target, &table_key(key)?)?, value, registry)?;
+        }
+        return Ok(());
+    }
+    match target.reflect_mut() {
+        ReflectMut::List(list) => {
+            let item_info = list
+                .get_represented_list_info()
+                .and_then(|info| info.item_info())
+                .ok_or_else(|| anyhow!("{type_path} has no known item type"))?;
+            // assigning a table replaces the whole list
+            let items = sequence(table)?;
+            let len = items.len();
+            for (index, value) in items.into_iter().enumerate() {
+                match list.get_mut(index) {
+                    Some(item) => apply_lua_value(item, value, registry)?,
+                    None => list.push(reflect_from_lua(value, item_info, registry)?),
+                }
+            }
+            while list.len() > len {
+                list.pop();
+            }
+            Ok(())
+        }
+        ReflectMut::Array(array) => {
+            for (index, value) in sequence(table)?.into_iter().enumerate() {
+                let item = array
+                    .get_mut(index)
+                    .ok_or_else(|| anyhow!("{type_path} has no index {}", index + 1))?;
+                apply_lua_value(item, value, registry)?;
+            }
+            Ok(())
+        }
+        ReflectMut::Map(map) => {
+            let map_info = map
+                .get_represented_map_info()
+                .ok_or_else(|| anyhow!("{type_path} has no known key and value types"))?;
+            let (Some(key_info), Some(value_info)) = (map_info.key_info(), map_info.value_info())
+            else {
+                return Err(anyhow!("{type_path} has no known key and value types"));
+            };
+            // entries are merged in, like struct fields
+            for (key, value) in table {
+                let key = reflect_from_lua(key, key_info, registry)?;
+                match map.get_mut(key.as_ref()) {
+                    Some(existing) => apply_lua_value(existing, value, registry)?,
+                    None => {
+                        map.insert_boxed(key, reflect_from_lua(value, value_info, registry)?);
+                    }
+                }
+            }
+            Ok(())
+        }
+        ReflectMut::Enum(enum_value) => {
+            // `{Variant = fields}` switches variant, anything else updates the current one
+            let mut entries = table.into_iter();
+            if let (Some((Value::String(name), value)), None) = (entries.next(), entries.next()) {
+                let name = name.to_str()?;
+                let is_variant = enum_value
+                    .get_represented_enum_info()
+                    .is_some_and(|info| info.contains_variant(name));
+                if is_variant {
+                    return set_variant(enum_value, name, Some(value), registry);
+                }
+            }
+            apply_variant_fields(enum_value, table, registry)
+        }
+        _ => Err(anyhow!("can't build {type_path} from a table")),
+    }
+}
+
+/// Reads the array part of a table, which has to start at 1 without gaps
+fn sequence(table: Table) -> Result<Vec<Value>, anyhow::Error> {
+    let mut items = BTreeMap::new();
+    for (key, value) in table {
+        let Value::Integer(index) = key else {
+            return Err(anyhow!("expected a list, got a {} key", key.type_name()));
+        };
+        items.insert(index, value);
+    }
+    if items.keys().copied().ne(1..=items.len() as i64) {
+        return Err(anyhow!("list indices have to start at 1 without gaps"));
+    }
+    Ok(items.into_values().collect())
+}
+
+fn table_key(key: Value) -> Result<String, anyhow::Error> {
+    match key {
+        Value::String(name) => Ok(name.to_str()?.to_string()),
+        Value::Integer(index) => Ok(index.to_string()),
+        _ => Err(anyhow!("can't use a {} as a field name", key.type_name())),
+    }
+}
+
+fn apply_variant_fields(
+    target: &mut dyn Enum,
+    table: Table,
+    registry: &TypeRegistry,
+) -> Result<(), anyhow::Error> {
+    let variant_name = target.variant_name().to_string();
+    for (key, value) in table {
+        let key = table_key(key)?;
+        let field = match target.variant_type() {
+            VariantType::Struct => target.field_mut(&key),
+            _ => target.field_at_mut(index(&key)?),
+        };
+        let field = field.ok_or_else(|| anyhow!("variant {variant_name} has no field {key}"))?;
+        apply_lua_value(field, value, registry)?;
+    }
+    Ok(())
+}
+
+/// Switches `target` to the named variant, with its fields set from `value`
+/// A variant with a single field can take its value directly, e.g. `{Some = 1}`
+fn set_variant(
+    target: &mut dyn Enum,
+    name: &str,
+    value: Option<Value>,
+    registry: &TypeRegistry,
+) -> Result<(), anyhow::Error> {
+    let enum_info = target
+        .get_represented_enum_info()
+        .ok_or_else(|| anyhow!("{} has no known variants", target.reflect_type_path()))?;
+    let variant_info = enum_info
+        .variant(name)
+        .ok_or_else(|| anyhow!("{} has no variant {name}", enum_info.type_path()))?;
+
+    let field_info = |type_info: Option<&'static TypeInfo>| {
+        type_info.ok_or_else(|| anyhow!("variant {name} has a field of unknown type"))
+    };
+    let variant = match variant_info {
+        VariantInfo::Unit(_) => DynamicVariant::Unit,
+        VariantInfo::Tuple(tuple_info) => {
+            let mut tuple = DynamicTuple::default();
+            for field in tuple_info.iter() {
+                tuple.insert_boxed(default_value(field_info(field.type_info())?, registry)?);
+            }
+            DynamicVariant::Tuple(tuple)
+        }
+        VariantInfo::Struct(struct_info) => {
+            let mut dynamic_struct = DynamicStruct::default();
+            for field in struct_info.iter() {
+                dynamic_struct.insert_boxed(
+                    field.name(),
+                    default_value(field_info(field.type_info())?, registry)?,
+                );
+            }
+            DynamicVariant::Struct(dynamic_struct)
+        }
+    };
+    let mut new_value = DynamicEnum::new(name, variant);
+    let single_value = new_value.variant_type() == VariantType::Tuple && new_value.field_len() == 1;
+    match value {
+        None | Some(Value::Nil) => {}
+        Some(Value::Table(table)) if !single_value => {
+            apply_variant_fields(&mut new_value, table, registry)?;
+        }
+        Some(value) => {
+            let field = new_value
+                .field_at_mut(0)
+                .filter(|_| single_value)
+                .ok_or_else(|| anyhow!("variant {name} doesn't hold a single value"))?;
+            apply_lua_value(field, value, registry)?;
+        }
+    }
+    Ok(target.try_apply(&new_value)?)
+}
+
+fn set<T: PartialReflect>(target: &mut dyn PartialReflect, value: T) -> Result<(), anyhow::Error> {
+    let type_path = target.reflect_type_path().to_string();
+    target
+        .try_apply(&value)
+        .map_err(|_| anyhow!("expected {type_path}, got {}", value.reflect_type_path()))
+}
+
+/// Strings set `String`s and `char`s, or select a variant by name
+fn set_string(
+    target: &mut dyn PartialReflect,
+    string: &str,
+    registry: &TypeRegistry,
+) -> Result<(), anyhow::Error> {
+    if let Some(target) = target.try_downcast_mut::<char>() {
+        let mut chars = string.chars();
+        let (Some(char), None) = (chars.next(), chars.next()) else {
+            return Err(anyhow!("expected a single character, got {string:?}"));
+        };
+        *target = char;
+        return Ok(());
+    }
+    if let ReflectMut::Enum(enum_value) = target.reflect_mut() {
+        return set_variant(enum_value, string, None, registry);
+    }
+    set(target, string.to_string())
+}
+
+macro_rules! set_as {
+    ($target:ident, $value:expr, $($ty:ty),*) => {
+        $(
+            if let Some(target) = $target.try_downcast_mut::<$ty>() {
+                *target = <$ty>::try_from($value)
+                    .map_err(|_| anyhow!("{} is out of range for {}", $value, stringify!($ty)))?;
+                return Ok(());
+            }
+        )*
+    };
+}
+
+fn set_integer(target: &mut dyn PartialReflect, integer: i64) -> Result<(), anyhow::Error> {
+    set_as!(target, integer, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+    set_number(target, integer as f64)
+}
+
+fn set_number(target: &mut dyn PartialReflect, number: f64) -> Result<(), anyhow::Error> {
+    if let Some(target) = target.try_downcast_mut::<f32>() {
+        *target = number as f32;
+        return Ok(());
+    }
+    if let Some(target) = target.try_downcast_mut::<f64>() {
+        *target = number;
+        return Ok(());
+    }
+    // allow whole floats for integer fields, e.g. results of arithmetic
+    if number.fract() == 0.0 {
+        set_as!(target, number as i64, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+    }
+    Err(anyhow!(
+        "expected {}, got number {number}",
+        target.reflect_type_path()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::reflect::Typed;
+    use piccolo::Lua;
+
+    #[derive(Reflect, Default, Debug, PartialEq)]
+    #[reflect(Default)]
+    struct Stats {
+        health: u32,
+        speed: f32,
+        name: String,
+        initial: char,
+        tags: Vec<u32>,
+        scores: BTreeMap<String, i64>,
+        target: Option<u8>,
+        mode: Mode,
+    }
+
+    #[derive(Reflect, Default, Debug, PartialEq)]
+    #[reflect(Default)]
+    enum Mode {
+        #[default]
+        Idle,
+        Walking(f32),
+        Attacking { target: u32, damage: f32 },
+    }
+
+    fn registry() -> TypeRegistry {
+        let mut registry = TypeRegistry::default();
+        registry.register::<Stats>();
+        registry.register::<Mode>();
+        registry.register::<u8>();
+        registry.register::<u32>();
+        registry.register::<i64>();
+        registry.register::<f32>();
+        registry.register::<String>();
+        registry
+    }
+
+    fn string<'gc>(ctx: Context<'gc>, string: &str) -> Value<'gc> {
+        piccolo::String::from_slice(&ctx, string).into()
+    }
+
+    fn table<'gc>(ctx: Context<'gc>, entries: Vec<(Value<'gc>, Value<'gc>)>) -> Value<'gc> {
+        let table = Table::new(&ctx);
+        for (key, value) in entries {
+            table.set(ctx, key, value).unwrap();
+        }
+        table.into()
+    }
+
+    fn fields<'gc>(ctx: Context<'gc>, entries: Vec<(&str, Value<'gc>)>) -> Value<'gc> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (string(ctx, key), value))
+            .collect();
+        table(ctx, entries)
+    }
+
+    fn stats_from_lua<'gc>(
+        value: Value<'gc>,
+        registry: &TypeRegistry,
+    ) -> Result<Stats, anyhow::Error> {
+        let reflect = reflect_from_lua(value, Stats::type_info(), registry)?;
+        Ok(Stats::from_reflect(reflect.as_ref()).expect("a Stats"))
+    }
+
+    #[test]
+    fn builds_structs_from_tables() {
+        let registry = registry();
+        Lua::core().enter(|ctx| {
+            let tags = table(
+                ctx,
+                vec![(Value::Integer(1), Value::Integer(4)), (Value::Integer(2), Value::Integer(2))],
+            );
+            let scores = fields(ctx, vec![("alice", Value::Integer(3))]);
+            let value = fields(
+                ctx,
+                vec![
+                    ("health", Value::Integer(10)),
+                    // integers are accepted for floats
+                    ("speed", Value::Integer(2)),
+                    ("name", string(ctx, "knight")),
+                    ("initial", string(ctx, "k")),
+                    ("tags", tags),
+                    ("scores", scores),
+                    ("target", Value::Integer(7)),
+                    ("mode", string(ctx, "Idle")),
+                ],
+            );
+            let stats = stats_from_lua(value, &registry).unwrap();
+            assert_eq!(
+                stats,
+                Stats {
+                    health: 10,
+                    speed: 2.0,
+                    name: "knight".to_string(),
+                    initial: 'k',
+                    tags: vec![4, 2],
+                    scores: BTreeMap::from([("alice".to_string(), 3)]),
+                    target: Some(7),
+                    mode: Mode::Idle,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn converts_numbers_between_integers_and_floats() {
+        let registry = registry();
+        Lua::core().enter(|ctx| {
+            let whole = fields(ctx, vec![("health", Value::Number(3.0))]);
+            assert_eq!(stats_from_lua(whole, &registry).unwrap().health, 3);
+
+            let fraction = fields(ctx, vec![("health", Value::Number(3.5))]);
+            assert!(stats_from_lua(fraction, &registry).is_err());
+
+            let negative = fields(ctx, vec![("health", Value::Integer(-1))]);
+            let err = stats_from_lua(negative, &registry).unwrap_err();
+            assert!(err.to_string().contains("out of range for u32"), "{err}");
+        });
+    }
+
+    #[test]
+    fn selects_enum_variants() {
+        let registry = registry();
+        Lua::core().enter(|ctx| {
+            let walking = fields(ctx, vec![("Walking", Value::Number(1.5))]);
+            let reflect = reflect_from_lua(walking, Mode::type_info(), &registry).unwrap();
+            assert_eq!(Mode::from_reflect(reflect.as_ref()), Some(Mode::Walking(1.5)));
+
+            let attacking = fields(
+                ctx,
+                vec![(
+                    "Attacking",
+                    fields(ctx, vec![("target", Value::Integer(4)), ("damage", Value::Number(0.5))]),
+                )],
+            );
+            let reflect = reflect_from_lua(attacking, Mode::type_info(), &registry).unwrap();
+            assert_eq!(
+                Mode::from_reflect(reflect.as_ref()),
+                Some(Mode::Attacking {
+                    target: 4,
+                    damage: 0.5
+                })
+            );
+
+            let err = reflect_from_lua(string(ctx, "Running"), Mode::type_info(), &registry)
+                .unwrap_err();
+            assert!(err.to_string().contains("has no variant Running"), "{err}");
+        });
+    }
+
+    #[test]
+    fn nil_clears_options() {
+        let registry = registry();
+        Lua::core().enter(|ctx| {
+            let mut stats = Stats {
+                target: Some(1),
+                ..default()
+            };
+            apply_lua_value(field_mut(&mut stats, "target").unwrap(), Value::Nil, &registry)
+                .unwrap();
+            assert_eq!(stats.target, None);
+            apply_lua_value(
+                field_mut(&mut stats, "target").unwrap(),
+                Value::Integer(2),
+                &registry,
+            )
+            .unwrap();
+            assert_eq!(stats.target, Some(2));
+        });
+    }
+
+    #[test]
+    fn assigning_a_table_replaces_lists() {
+        let registry = registry();
+        Lua::core().enter(|ctx| {
+            let mut stats = Stats {
+                tags: vec![1, 2, 3],
+                ..default()
+            };
+            let tags = table(ctx, vec![(Value::Integer(1), Value::Integer(9))]);
+            apply_lua_value(field_mut(&mut stats, "tags").unwrap(), tags, &registry).unwrap();
+            assert_eq!(stats.tags, [9]);
+
+            let gaps = table(
+                ctx,
+                vec![(Value::Integer(1), Value::Integer(1)), (Value::Integer(3), Value::Integer(3))],
+            );
+            let err =
+                apply_lua_value(field_mut(&mut stats, "tags").unwrap(), gaps, &registry).unwrap_err();
+            assert!(err.to_string().contains("without gaps"), "{err}");
+        });
+    }
+
+    #[test]
+    fn refuses_unknown_fields_and_bad_strings() {
+        let registry = registry();
+        Lua::core().enter(|ctx| {
+            let unknown = fields(ctx, vec![("mana", Value::Integer(1))]);
+            let err = stats_from_lua(unknown, &registry).unwrap_err();
+            assert!(err.to_string().contains("has no field mana"), "{err}");
+
+            let long = fields(ctx, vec![("initial", string(ctx, "kn"))]);
+            let err = stats_from_lua(long, &registry).unwrap_err();
+            assert!(err.to_string().contains("single character"), "{err}");
+
+            let boolean = fields(ctx, vec![("name", Value::Boolean(true))]);
+            assert!(stats_from_lua(boolean, &registry).is_err());
+        });
+    }
+
+    #[test]
+    fn converts_plain_values_to_lua() {
+        Lua::core().enter(|ctx| {
+            assert!(matches!(reflect_to_lua(ctx, &3i32), Some(Value::Integer(3))));
+            assert!(matches!(
+                reflect_to_lua(ctx, &u64::MAX),
+                Some(Value::Number(number)) if number == u64::MAX as f64
+            ));
+            assert!(matches!(reflect_to_lua(ctx, &0.5f32), Some(Value::Number(0.5))));
+            assert!(matches!(reflect_to_lua(ctx, &true), Some(Value::Boolean(true))));
+            // functions without a return value
+            assert!(matches!(reflect_to_lua(ctx, &()), Some(Value::Nil)));
+            assert!(matches!(reflect_to_lua(ctx, &None::<u8>), Some(Value::Nil)));
+            assert!(matches!(reflect_to_lua(ctx, &Some(4u8)), Some(Value::Integer(4))));
+            let Some(Value::String(idle)) = reflect_to_lua(ctx, &Mode::Idle) else {
+                panic!("unit variants should convert to their name");
+            };
+            assert_eq!(idle.as_bytes(), b"Idle");
+            // reflected values stay references
+            assert!(reflect_to_lua(ctx, &Mode::Walking(1.0)).is_none());
+            assert!(reflect_to_lua(ctx, &Stats::default()).is_none());
+        });
+    }
+
+    #[test]
+    fn looks_up_fields_by_lua_keys() {
+        let stats = Stats {
+            health: 5,
+            tags: vec![7, 8],
+            scores: BTreeMap::from([("bob".to_string(), 2)]),
+            mode: Mode::Attacking {
+                target: 3,
+                damage: 1.0,
+            },
+            ..default()
+        };
+        let field = |path: &str| field_ref(&stats, path);
+        assert_eq!(field("health").unwrap().try_downcast_ref::<u32>(), Some(&5));
+        // indices start at 1
+        let tags = field("tags").unwrap();
+        assert_eq!(field_ref(tags, "1").unwrap().try_downcast_ref::<u32>(), Some(&7));
+        assert!(field_ref(tags, "0").is_err());
+        assert!(field_ref(tags, "3").is_err());
+        let scores = field("scores").unwrap();
+        assert_eq!(field_ref(scores, "bob").unwrap().try_downcast_ref::<i64>(), Some(&2));
+        let mode = field("mode").unwrap();
+        assert_eq!(field_ref(mode, "target").unwrap().try_downcast_ref::<u32>(), Some(&3));
+        let err = field("mana").unwrap_err();
+        assert!(err.to_string().contains("has no field mana"), "{err}");
+    }
+
+    #[test]
+    fn parses_map_keys() {
+        let key = map_key("12", Some(u8::type_info())).unwrap();
+        assert_eq!(key.try_downcast_ref::<u8>(), Some(&12));
+        assert!(map_key("300", Some(u8::type_info())).is_err());
+        let key = map_key("name", Some(String::type_info())).unwrap();
+        assert_eq!(key.try_downcast_ref::<String>().map(String::as_str), Some("name"));
+        assert!(map_key("1", Some(Mode::type_info())).is_err());
+    }
+}
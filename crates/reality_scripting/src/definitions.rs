@@ -0,0 +1,400 @@
+// LuaLS annotations for what's exposed to scripts, so editors can autocomplete and type check them
+
+use crate::convert::is_option_info;
+use crate::event::ReflectLuaEvent;
+use crate::naming::{self, type_path_segments, LuaNaming};
+use crate::reflect::{register_components_and_markers, ObjectFunctionRegistry};
+use bevy::prelude::*;
+use bevy::reflect::func::args::Ownership;
+use bevy::reflect::func::{DynamicFunction, FunctionRegistry, SignatureInfo};
+use bevy::reflect::{Type, TypeInfo, TypeRegistry, VariantInfo};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Writes the definitions to a `.d.lua` file once components and resources are exposed to lua.
+/// Point the Lua language server at the file, e.g. by keeping it next to the scripts
+pub struct LuaDefinitionsPlugin {
+    pub path: PathBuf,
+}
+
+impl LuaDefinitionsPlugin {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Plugin for LuaDefinitionsPlugin {
+    fn build(&self, app: &mut App) {
+        let path = self.path.clone();
+        app.add_systems(
+            PostStartup,
+            (move |world: &mut World| {
+                let definitions = lua_definitions(world);
+                match std::fs::write(&path, definitions) {
+                    Ok(()) => info!("wrote lua definitions to {}", path.display()),
+                    Err(err) => warn!("couldn't write lua definitions to {}: {err}", path.display()),
+                }
+            })
+            .after(register_components_and_markers),
+        );
+    }
+}
+
+/// What the global table of a type contains
+#[derive(Default)]
+struct TypeTable {
+    component: bool,
+    resource: bool,
+    event: bool,
+}
+
+/// LuaLS annotations for every exposed component, resource and event, the fields of reflected types,
+/// and the registered functions
+pub fn lua_definitions(world: &World) -> String {
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let function_registry = world
+        .get_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
+        .map(|function_registry| function_registry.borrow());
+
+    let mut tables: BTreeMap<&'static str, (TypeId, TypeTable)> = BTreeMap::new();
+    for registration in registry.iter() {
+        let type_path = registration.type_info().type_path();
+        let table = TypeTable {
+            component: world.components().get_id(registration.type_id()).is_some()
+                && registration.data::<ReflectComponent>().is_some(),
+            resource: false,
+            event: registration.data::<ReflectLuaEvent>().is_some(),
+        };
+        if table.component || table.event {
+            tables.insert(type_path, (registration.type_id(), table));
+        }
+    }
+    for (resource, _) in world.iter_resources() {
+        let Some(registration) = resource.type_id().and_then(|type_id| registry.get(type_id)) else {
+            continue;
+        };
+        let type_path = registration.type_info().type_path();
+        tables
+            .entry(type_path)
+            .or_insert_with(|| (registration.type_id(), TypeTable::default()))
+            .1
+            .resource = true;
+    }
+    // types that only have functions, like `glam.Vec3.new`
+    if let Some(function_registry) = &function_registry {
+        for type_id in function_registry.keys() {
+            if let Some(registration) = registry.get(*type_id) {
+                tables
+                    .entry(registration.type_info().type_path())
+                    .or_insert_with(|| (*type_id, TypeTable::default()));
+            }
+        }
+    }
+
+    let mut out = String::new();
+    out.push_str("---@meta\n");
+    out.push_str("-- Generated by reality_scripting from the type registry, don't edit by hand\n\n");
+    out.push_str(PRELUDE);
+
+    // values of reflected types, sorted so the output doesn't change between runs
+    let mut registrations = registry.iter().collect::<Vec<_>>();
+    registrations.sort_by_key(|registration| registration.type_info().type_path());
+    for registration in registrations {
+        let type_info = registration.type_info();
+        let class = class_name(type_info.type_path());
+        match type_info {
+            TypeInfo::Struct(info) => {
+                writeln!(out, "---@class {class}").unwrap();
+                for field in info.iter() {
+                    let lua_type = lua_type(field.type_id(), field.type_path(), &registry);
+                    writeln!(out, "---@field {} {lua_type}", field.name()).unwrap();
+                }
+            }
+            TypeInfo::TupleStruct(info) => {
+                writeln!(out, "---@class {class}").unwrap();
+                for field in info.iter() {
+                    let lua_type = lua_type(field.type_id(), field.type_path(), &registry);
+                    writeln!(out, "---@field [{}] {lua_type}", field.index() + 1).unwrap();
+                }
+            }
+            _ => {
+                if !function_registry
+                    .as_ref()
+                    .is_some_and(|functions| functions.contains_key(&registration.type_id()))
+                {
+                    continue;
+                }
+                writeln!(out, "---@class {class}").unwrap();
+            }
+        }
+        let functions = function_registry
+            .as_ref()
+            .and_then(|functions| functions.get(&registration.type_id()));
+        for function in sorted_functions(functions) {
+            let (Some(name), Some(signature)) = (function.name(), function.info().signatures().first())
+            else {
+                continue;
+            };
+            if is_method(signature, registration.type_id()) {
+                let lua_type = function_type(signature, Some(&class), &registry);
+                writeln!(out, "---@field {name} {lua_type}").unwrap();
+            }
+        }
+        out.push('\n');
+    }
+
+    // the global tables scripts reach types through
+    for (type_path, (type_id, table)) in &tables {
+        let class = class_name(type_path);
+        writeln!(out, "---@class {class}.Type").unwrap();
+        if table.component {
+            for marker in ["ref", "mut", "opt_ref", "opt_mut"] {
+                writeln!(out, "---@field {marker} ComponentType").unwrap();
+            }
+            for filter in ["with", "without", "changed", "added"] {
+                writeln!(out, "---@field {filter} QueryFilter").unwrap();
+            }
+        } else if table.resource {
+            writeln!(out, "---@field ref ComponentType").unwrap();
+            writeln!(out, "---@field mut ComponentType").unwrap();
+        }
+        if table.event {
+            writeln!(out, "---@field reader EventParam").unwrap();
+            writeln!(out, "---@field writer EventParam").unwrap();
+        }
+        let functions = function_registry
+            .as_ref()
+            .and_then(|functions| functions.get(type_id));
+        for function in sorted_functions(functions) {
+            let (Some(name), Some(signature)) = (function.name(), function.info().signatures().first())
+            else {
+                continue;
+            };
+            if !is_method(signature, *type_id) {
+                let lua_type = function_type(signature, None, &registry);
+                writeln!(out, "---@field {name} {lua_type}").unwrap();
+            }
+        }
+        out.push('\n');
+    }
+
+    let mut declared = BTreeSet::new();
+    for type_path in tables.keys() {
+        let segments = type_path_segments(type_path);
+        for depth in 1..segments.len() {
+            let path = lua_path(&segments[..depth]);
+            if declared.insert(path.clone()) {
+                writeln!(out, "{path} = {{}}").unwrap();
+            }
+        }
+        writeln!(out, "---@type {}.Type", class_name(type_path)).unwrap();
+        writeln!(out, "{} = {{}}", lua_path(&segments)).unwrap();
+    }
+
+    // the same tables under their short and explicit names
+    let lua_naming = world.get_resource::<LuaNaming>().cloned().unwrap_or_default();
+    let type_ids = tables.values().map(|(type_id, _)| *type_id);
+    let mut aliases = naming::aliases(&registry, &lua_naming, type_ids)
+        .into_iter()
+        .collect::<Vec<_>>();
+    aliases.sort_by(|(_, a), (_, b)| a.cmp(b));
+    for (type_id, alias) in aliases {
+        let Some(registration) = registry.get(type_id) else {
+            continue;
+        };
+        let segments = alias.iter().map(String::as_str).collect::<Vec<_>>();
+        for depth in 1..segments.len() {
+            let path = lua_path(&segments[..depth]);
+            if declared.insert(path.clone()) {
+                writeln!(out, "{path} = {{}}").unwrap();
+            }
+        }
+        let path = lua_path(&segments);
+        declared.insert(path.clone());
+        let class = class_name(registration.type_info().type_path());
+        writeln!(out, "---@type {class}.Type").unwrap();
+        writeln!(out, "{path} = {{}}").unwrap();
+    }
+    out
+}
+
+/// Globals every script has
+const PRELUDE: &str = r#"---@class ComponentType
+---@class QueryFilter
+---@class EventParam
+---@class SystemParam
+
+---@class EntityHandle
+---@field id integer
+---@field index integer
+---@field get fun(self: EntityHandle, component: ComponentType): any
+---@field insert fun(self: EntityHandle, components: table)
+---@field remove fun(self: EntityHandle, component: ComponentType)
+---@field despawn fun(self: EntityHandle)
+
+---@type SystemParam
+Commands = nil
+---@type SystemParam
+Entity = nil
+
+---@class Log
+---@field trace fun(...)
+---@field debug fun(...)
+---@field info fun(...)
+---@field warn fun(...)
+---@field error fun(...)
+---@type Log
+log = nil
+
+---@alias LoadState "NotLoaded"|"Loading"|"Loaded"|"Failed"
+---@class Assets
+---@field load fun(path: string, type_name: string): any
+---@field load_state fun(handle: any): LoadState
+---@field is_loaded fun(handle: any): boolean
+---@type Assets
+assets = nil
+
+"#;
+
+/// `a.b["C<D>"]`, quoting segments that aren't valid lua names
+fn lua_path(segments: &[&str]) -> String {
+    let mut path = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let is_name = segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+            && !segment.starts_with(|c: char| c.is_ascii_digit());
+        match (i, is_name) {
+            (0, _) => path.push_str(segment),
+            (_, true) => write!(path, ".{segment}").unwrap(),
+            (_, false) => write!(path, "[{segment:?}]").unwrap(),
+        }
+    }
+    path
+}
+
+/// The type path with `.` separators, and other characters LuaLS doesn't allow in class names replaced
+fn class_name(type_path: &str) -> String {
+    type_path
+        .replace("::", ".")
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+/// Methods take the type as their first argument, by reference
+fn is_method(signature: &SignatureInfo, type_id: TypeId) -> bool {
+    signature.args().first().is_some_and(|arg| {
+        arg.type_id() == type_id && matches!(arg.ownership(), Ownership::Ref | Ownership::Mut)
+    })
+}
+
+/// `fun(self: C, a: number): boolean`
+fn function_type(signature: &SignatureInfo, self_class: Option<&str>, registry: &TypeRegistry) -> String {
+    let args = signature
+        .args()
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| match (i, self_class) {
+            (0, Some(class)) => format!("self: {class}"),
+            _ => {
+                let name = arg.name().map_or_else(|| format!("arg{}", i + 1), str::to_string);
+                format!("{name}: {}", lua_type(arg.type_id(), arg.type_path(), registry))
+            }
+        })
+        .collect::<Vec<_>>();
+    let return_info = signature.return_info();
+    let returns = return_types(return_info.type_id(), return_info.type_path(), registry);
+    if returns.is_empty() {
+        format!("fun({})", args.join(", "))
+    } else {
+        format!("fun({}): {}", args.join(", "), returns.join(", "))
+    }
+}
+
+/// `Result`s return their `Ok` value, tuples multiple values and `()` nothing
+fn return_types(type_id: TypeId, type_path: &str, registry: &TypeRegistry) -> Vec<String> {
+    match registry.get(type_id).map(|registration| registration.type_info()) {
+        Some(TypeInfo::Tuple(info)) => info
+            .iter()
+            .map(|field| lua_type(field.type_id(), field.type_path(), registry))
+            .collect(),
+        Some(TypeInfo::Enum(info)) if info.type_path_table().ident() == Some("Result") => {
+            match info.variant("Ok") {
+                Some(VariantInfo::Tuple(ok)) => ok
+                    .field_at(0)
+                    .map(|field| return_types(field.type_id(), field.type_path(), registry))
+                    .unwrap_or_default(),
+                _ => vec![],
+            }
+        }
+        _ if type_path == "()" => vec![],
+        _ => vec![lua_type(type_id, type_path, registry)],
+    }
+}
+
+/// The lua type values of this type are converted to, see `convert::reflect_to_lua`
+fn lua_type(type_id: TypeId, type_path: &str, registry: &TypeRegistry) -> String {
+    match type_path {
+        "bool" => return "boolean".to_string(),
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64" | "u128"
+        | "usize" => return "integer".to_string(),
+        "f32" | "f64" => return "number".to_string(),
+        "char" | "alloc::string::String" | "&str" => return "string".to_string(),
+        "()" => return "nil".to_string(),
+        "bevy_ecs::entity::Entity" => return "EntityHandle".to_string(),
+        "reality_scripting::callback::LuaCallback" => return "function".to_string(),
+        _ => {}
+    }
+    let Some(registration) = registry.get(type_id) else {
+        return "any".to_string();
+    };
+    match registration.type_info() {
+        TypeInfo::Struct(_) | TypeInfo::TupleStruct(_) => class_name(type_path),
+        TypeInfo::Enum(info) if is_option_info(info) => match info.variant("Some") {
+            Some(VariantInfo::Tuple(some)) => some
+                .field_at(0)
+                .map(|field| format!("{}?", lua_type(field.type_id(), field.type_path(), registry)))
+                .unwrap_or_else(|| "any".to_string()),
+            _ => "any".to_string(),
+        },
+        TypeInfo::Enum(info) => {
+            // unit variants are their name, others `{Variant = value}` tables
+            let names = info
+                .iter()
+                .filter(|variant| matches!(variant, VariantInfo::Unit(_)))
+                .map(|variant| format!("{:?}", variant.name()))
+                .collect::<Vec<_>>();
+            if names.len() == info.variant_len() {
+                names.join("|")
+            } else {
+                "string|table".to_string()
+            }
+        }
+        TypeInfo::List(info) => list_type(info.item_ty(), registry),
+        TypeInfo::Array(info) => list_type(info.item_ty(), registry),
+        TypeInfo::Set(info) => list_type(info.value_ty(), registry),
+        TypeInfo::Map(info) => format!(
+            "table<{}, {}>",
+            lua_type(info.key_ty().id(), info.key_ty().path(), registry),
+            lua_type(info.value_ty().id(), info.value_ty().path(), registry)
+        ),
+        TypeInfo::Tuple(_) => "table".to_string(),
+        _ => "any".to_string(),
+    }
+}
+
+fn list_type(item: Type, registry: &TypeRegistry) -> String {
+    format!("{}[]", lua_type(item.id(), item.path(), registry))
+}
+
+fn sorted_functions(functions: Option<&FunctionRegistry>) -> Vec<&DynamicFunction<'static>> {
+    let mut functions = functions
+        .into_iter()
+        .flat_map(|functions| functions.iter())
+        .collect::<Vec<_>>();
+    functions.sort_by_key(|function| function.name().cloned());
+    functions
+}
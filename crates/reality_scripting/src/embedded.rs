@@ -0,0 +1,75 @@
+// Scripts loaded from bytes instead of through the asset server, e.g. embedded in the binary or sent in AO messages
+
+use crate::asset_loader::{LuaScript, LuaSource};
+use crate::module::resolve_requires;
+use crate::reflect::WorldMut;
+use crate::sandbox::LuaSandbox;
+use crate::{clear_loading_globals, insert_lua_vm, run_script, LuaVm};
+use anyhow::anyhow;
+use bevy::asset::AssetPath;
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+/// Handles of the scripts loaded with `load_script_bytes`, by path
+#[derive(Resource, Default)]
+pub struct EmbeddedScripts(pub HashMap<AssetPath<'static>, Handle<LuaScript>>);
+
+/// Runs the script right away, call it after the first update so components are exposed to lua.
+/// Loading a path again replaces the previous version like a hot reload, so AO messages can update scripts.
+/// Scripts loaded this way can't `require` modules
+pub fn load_script_bytes(
+    world: &mut World,
+    path: impl Into<AssetPath<'static>>,
+    bytes: impl Into<Vec<u8>>,
+) -> Result<Handle<LuaScript>, anyhow::Error> {
+    let path = path.into();
+    let bytes = bytes.into();
+    let requires = resolve_requires(&path, &bytes)?;
+    if let Some(module) = requires.keys().next() {
+        return Err(anyhow!(
+            "{path} requires {module}, scripts loaded from bytes can't require modules"
+        ));
+    }
+    let previous = world
+        .get_resource_or_init::<EmbeddedScripts>()
+        .0
+        .get(&path)
+        .cloned();
+    let old_state = previous.as_ref().and_then(|handle| {
+        let lua_script = world.get_resource::<Assets<LuaScript>>()?.get(handle)?;
+        (*lua_script.state).clone()
+    });
+
+    // the sandbox has to be in place before the first script runs, even before `Startup`
+    insert_lua_vm(world);
+    let mut lua = world
+        .remove_non_send_resource::<LuaVm>()
+        .ok_or_else(|| anyhow!("no lua vm to run {path} in"))?;
+    let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
+    let mut lua_app = WorldMut::new(world);
+    let source = LuaSource {
+        path: path.clone(),
+        bytes,
+        requires,
+        modules: vec![],
+    };
+    let result = run_script(world, &mut lua, &lua_app, &sandbox, source, old_state);
+    clear_loading_globals(&mut lua);
+    lua_app.this = None;
+    world.insert_non_send_resource(lua);
+    let lua_script = result?;
+
+    let mut lua_scripts = world.resource_mut::<Assets<LuaScript>>();
+    let handle = match previous {
+        Some(handle) => {
+            lua_scripts.insert(&handle, lua_script);
+            handle
+        }
+        None => lua_scripts.add(lua_script),
+    };
+    world
+        .resource_mut::<EmbeddedScripts>()
+        .0
+        .insert(path, handle.clone());
+    Ok(handle)
+}
@@ -0,0 +1,275 @@
+// Entity handles for scripts, and the per-system context they act through
+
+use crate::reflect::{ComponentType, ObjectFunctionRegistry, PtrState, ReflectPtr};
+use crate::userdata::{UserDataPtr, ValueExt};
+use crate::CommandQueueWrapper;
+use anyhow::anyhow;
+use bevy::ecs::component::ComponentId;
+use bevy::prelude::*;
+use bevy::reflect::ReflectFromPtr;
+use piccolo::{Callback, CallbackReturn, Context, FromValue, IntoValue, Table, TypeError, Value};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Query term yielding the matched entity, exposed to scripts as `Entity`
+#[derive(Copy, Clone, Debug)]
+pub struct EntityMarker;
+
+/// What a currently running lua system has access to, stored in the `__system_context` global
+pub struct SystemContext {
+    pub world: *mut World,
+    pub commands: *mut CommandQueueWrapper,
+    pub ptr_state: Rc<RefCell<PtrState>>,
+    pub function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+    /// Components fetched by the system's queries, and whether mutably
+    pub queried: Vec<(ComponentId, bool)>,
+    /// Components handed out by `entity:get`, and whether mutably
+    pub claimed: RefCell<Vec<(Entity, ComponentId, bool)>>,
+}
+
+impl SystemContext {
+    pub const GLOBAL: &'static str = "__system_context";
+
+    pub fn current<'gc>(ctx: Context<'gc>) -> Result<&'gc SystemContext, anyhow::Error> {
+        let context = ctx
+            .globals()
+            .get::<_, Value>(ctx, Self::GLOBAL)?
+            .as_static_user_data::<SystemContext>()
+            .map_err(|_| anyhow!("entities can only be accessed from inside a system"))?;
+        if *context.ptr_state.borrow() == PtrState::Invalid {
+            return Err(anyhow!("entities can only be accessed from inside a system"));
+        }
+        Ok(context)
+    }
+
+    pub fn world(&self) -> &mut World {
+        unsafe { &mut *self.world }
+    }
+
+    pub fn commands(&self) -> &mut CommandQueueWrapper {
+        unsafe { &mut *self.commands }
+    }
+
+    /// Refuses access that would alias a component the system's queries or an earlier `get` hand out,
+    /// since both point straight into the world
+    fn claim(
+        &self,
+        entity: Entity,
+        component_id: ComponentId,
+        mutable: bool,
+        name: &str,
+    ) -> Result<(), anyhow::Error> {
+        if self
+            .queried
+            .iter()
+            .any(|(id, queried_mut)| *id == component_id && (mutable || *queried_mut))
+        {
+            return Err(anyhow!(
+                "{name} is already fetched by one of the system's queries, use it through the query instead"
+            ));
+        }
+        let mut claimed = self.claimed.borrow_mut();
+        if claimed.iter().any(|(claimed_entity, id, claimed_mut)| {
+            *claimed_entity == entity && *id == component_id && (mutable || *claimed_mut)
+        }) {
+            return Err(anyhow!(
+                "{name} of {entity} was already got in this run of the system, reuse that value"
+            ));
+        }
+        claimed.push((entity, component_id, mutable));
+        Ok(())
+    }
+}
+
+/// A script-side reference to an entity
+/// Like `Entity` this is just an id, so it can be kept between runs of a system
+#[derive(Copy, Clone, Debug)]
+pub struct EntityHandle {
+    pub entity: Entity,
+}
+
+impl EntityHandle {
+    pub fn new(entity: Entity) -> Self {
+        Self { entity }
+    }
+
+    pub fn get<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+            let (this, component_type): (&EntityHandle, Value) = stack.consume(ctx)?;
+            let component_type = *component_type.as_static_user_data::<ComponentType>()?;
+            let context = SystemContext::current(ctx)?;
+            let world = context.world();
+            let app_registry = world.resource::<AppTypeRegistry>().clone();
+            let app_registry = app_registry.read();
+
+            let (ComponentType::Ref((component_id, type_id))
+            | ComponentType::Mut((component_id, type_id))
+            | ComponentType::OptionRef((component_id, type_id))
+            | ComponentType::OptionMut((component_id, type_id))) = component_type;
+            let registration = app_registry
+                .get(type_id)
+                .ok_or_else(|| anyhow!("component is not reflected"))?;
+            let reflect_from_ptr = registration
+                .data::<ReflectFromPtr>()
+                .ok_or_else(|| anyhow!("component is not reflected"))?;
+            let mutable = matches!(
+                component_type,
+                ComponentType::Mut(_) | ComponentType::OptionMut(_)
+            );
+            context.claim(
+                this.entity,
+                component_id,
+                mutable,
+                registration.type_info().type_path(),
+            )?;
+
+            let value = match component_type {
+                ComponentType::Ref(_) | ComponentType::OptionRef(_) => world
+                    .get_entity(this.entity)
+                    .ok()
+                    .and_then(|entity| entity.get_by_id(component_id).ok())
+                    .map(|ptr| {
+                        let value = unsafe { reflect_from_ptr.as_reflect(ptr) };
+                        ReflectPtr::new_ref(
+                            value,
+                            context.ptr_state.clone(),
+                            context.function_registry.clone(),
+                        )
+                    }),
+                ComponentType::Mut(_) | ComponentType::OptionMut(_) => world
+                    .get_entity_mut(this.entity)
+                    .ok()
+                    .and_then(|mut entity| {
+                        let mut ptr = entity.get_mut_by_id(component_id).ok()?;
+                        let value = unsafe { reflect_from_ptr.as_reflect_mut(ptr.as_mut()) };
+                        Some(ReflectPtr::new_mut(
+                            value,
+                            context.ptr_state.clone(),
+                            context.function_registry.clone(),
+                        ))
+                    }),
+            };
+            match value {
+                Some(value) => stack.replace(ctx, value.into_value(&ctx)),
+                None => stack.replace(ctx, Value::Nil),
+            }
+            Ok(CallbackReturn::Return)
+        })
+    }
+
+    pub fn insert<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+            let (this, value): (&EntityHandle, &ReflectPtr) = stack.consume(ctx)?;
+            let context = SystemContext::current(ctx)?;
+            let entity = this.entity;
+            let value = value.get_field_value_ref()?.clone_value();
+            context.commands().push(move |world: &mut World| {
+                let app_registry = world.resource::<AppTypeRegistry>().clone();
+                let app_registry = app_registry.read();
+                let Some(reflect_component) = value
+                    .get_represented_type_info()
+                    .and_then(|type_info| {
+                        app_registry.get_type_data::<ReflectComponent>(type_info.type_id())
+                    })
+                else {
+                    warn!("tried to insert a value that isn't a reflected component");
+                    return;
+                };
+                let Ok(mut entity) = world.get_entity_mut(entity) else {
+                    warn!("tried to insert a component into a despawned entity");
+                    return;
+                };
+                reflect_component.insert(&mut entity, value.as_partial_reflect(), &app_registry);
+            });
+            Ok(CallbackReturn::Return)
+        })
+    }
+
+    pub fn remove<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+            let (this, component_type): (&EntityHandle, Value) = stack.consume(ctx)?;
+            let component_type = *component_type.as_static_user_data::<ComponentType>()?;
+            let context = SystemContext::current(ctx)?;
+            let entity = this.entity;
+            let (ComponentType::Ref((component_id, _))
+            | ComponentType::Mut((component_id, _))
+            | ComponentType::OptionRef((component_id, _))
+            | ComponentType::OptionMut((component_id, _))) = component_type;
+            context.commands().push(move |world: &mut World| {
+                if let Ok(mut entity) = world.get_entity_mut(entity) {
+                    entity.remove_by_id(component_id);
+                }
+            });
+            Ok(CallbackReturn::Return)
+        })
+    }
+
+    pub fn despawn<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+            let this: &EntityHandle = stack.consume(ctx)?;
+            let context = SystemContext::current(ctx)?;
+            let entity = this.entity;
+            context.commands().push(move |world: &mut World| {
+                world.despawn(entity);
+            });
+            Ok(CallbackReturn::Return)
+        })
+    }
+}
+
+impl<'gc> FromValue<'gc> for &'gc EntityHandle {
+    fn from_value(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, TypeError> {
+        EntityHandle::from_value_2(ctx, value)
+    }
+}
+
+impl UserDataPtr for EntityHandle {
+    type Data = Entity;
+
+    fn get_data_mut(&self) -> Option<*mut Self::Data> {
+        None
+    }
+
+    fn get_data(&self) -> *const Self::Data {
+        &self.entity as *const Entity
+    }
+
+    fn edit_metatable<'gc>(&self, ctx: &Context<'gc>, metatable: &mut Table<'gc>) {
+        metatable
+            .set(
+                *ctx,
+                "__eq",
+                Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                    let (this, other): (&EntityHandle, &EntityHandle) = stack.consume(ctx)?;
+                    stack.replace(ctx, this.entity == other.entity);
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+    }
+
+    fn lua_to_string(&self) -> String {
+        format!("{}", self.entity)
+    }
+
+    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Result<Value<'gc>, anyhow::Error> {
+        Ok(match key {
+            "id" => Value::Integer(self.entity.to_bits() as i64),
+            "index" => Value::Integer(self.entity.index() as i64),
+            "get" => Self::get(ctx).into_value(*ctx),
+            "insert" => Self::insert(ctx).into_value(*ctx),
+            "remove" => Self::remove(ctx).into_value(*ctx),
+            "despawn" => Self::despawn(ctx).into_value(*ctx),
+            &_ => Value::Nil,
+        })
+    }
+
+    fn lua_new_index<'gc>(
+        &self,
+        _ctx: &Context<'gc>,
+        key: &str,
+        _new_value: Value<'gc>,
+    ) -> Result<(), anyhow::Error> {
+        Err(anyhow!("can't set {key} on an entity"))
+    }
+}
@@ -0,0 +1,116 @@
+// Script failures are reported instead of crashing the game
+
+use bevy::prelude::*;
+use piccolo::compiler::FunctionRef;
+use piccolo::thread::BacktraceFrame;
+use piccolo::{Executor, Function};
+use std::fmt;
+
+/// Sent when a script fails to load, or one of its systems raises an error
+#[derive(Event, Debug, Clone)]
+pub struct ScriptError {
+    /// Asset path of the script
+    pub script: String,
+    /// The failing system as `name (line N)`, or `None` if the script failed while loading
+    pub system: Option<String>,
+    pub message: String,
+    /// Where the error was raised, innermost call first, e.g. `scripts/player.lua:12: in move_system`.
+    /// Empty if it didn't come from running lua code, like a failed run condition lookup
+    pub traceback: Vec<String>,
+}
+
+impl ScriptError {
+    /// Takes the traceback from `err` if it's a `LuaFailure`
+    pub fn new(script: impl Into<String>, system: Option<String>, err: &anyhow::Error) -> Self {
+        let traceback = err
+            .downcast_ref::<LuaFailure>()
+            .map(|failure| failure.traceback.clone())
+            .unwrap_or_default();
+        Self {
+            script: script.into(),
+            system,
+            message: err.to_string(),
+            traceback,
+        }
+    }
+
+    /// The innermost location in the traceback, e.g. `scripts/player.lua:12`
+    pub fn location(&self) -> Option<&str> {
+        self.traceback.first().map(|frame| frame_location(frame))
+    }
+
+    /// Logs the error and sends it as an event
+    pub fn report(self, world: &mut World) {
+        error!("{self}");
+        world.send_event(self);
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.system {
+            Some(system) => write!(f, "{}: system {system}: {}", self.script, self.message),
+            None => write!(f, "{}: {}", self.script, self.message),
+        }?;
+        for frame in &self.traceback {
+            write!(f, "\n    {frame}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An error raised while running lua code, with the lua frames it was raised in
+#[derive(Debug, Clone)]
+pub struct LuaFailure {
+    pub message: String,
+    pub traceback: Vec<String>,
+}
+
+impl fmt::Display for LuaFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.traceback.first() {
+            Some(frame) => write!(f, "{}: {}", frame_location(frame), self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for LuaFailure {}
+
+/// `scripts/player.lua:12` for `scripts/player.lua:12: in move_system (line 10)`
+fn frame_location(frame: &str) -> &str {
+    frame.split_once(": in ").map_or(frame, |(location, _)| location)
+}
+
+/// The lua frames a failed executor stopped in, innermost first.
+/// Piccolo keeps the frames of an error nothing caught until the executor is restarted
+pub(crate) fn traceback(executor: Executor) -> Vec<String> {
+    executor
+        .backtrace()
+        .into_iter()
+        .rev()
+        .filter_map(|frame| match frame {
+            BacktraceFrame::Lua { closure, line } => {
+                let prototype = closure.prototype();
+                let chunk = String::from_utf8_lossy(prototype.chunk_name.as_bytes()).into_owned();
+                Some(format!("{chunk}:{line}: in {}", function_location(Function::Closure(closure))))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Describes where a lua function was defined, e.g. `move_system (line 12)`
+pub fn function_location(function: Function) -> String {
+    let Function::Closure(closure) = function else {
+        return "<native function>".to_string();
+    };
+    match &closure.prototype().reference {
+        FunctionRef::Named(name, line) => format!(
+            "{} (line {line})",
+            String::from_utf8_lossy(name.as_bytes())
+        ),
+        FunctionRef::Expression(line) => format!("<anonymous> (line {line})"),
+        FunctionRef::Chunk => "<main chunk>".to_string(),
+    }
+}
@@ -0,0 +1,82 @@
+// Bevy events for scripts, read and sent through reflection
+
+use crate::convert::{reflect_from_lua, type_registry};
+use crate::entity::SystemContext;
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy::reflect::FromType;
+use piccolo::{Callback, CallbackReturn, Context, Table, Value};
+use std::any::TypeId;
+
+/// Lets scripts read and send events of this type, add it with `#[reflect(LuaEvent)]`.
+/// The event still has to be added with `app.add_event::<E>()`
+#[derive(Clone)]
+pub struct ReflectLuaEvent {
+    /// Events sent since the given event count, and the count to continue reading from
+    pub read: fn(&World, usize) -> (Vec<&dyn Reflect>, usize),
+    /// A command sending the value as this event, or `None` if it can't be converted
+    pub send: fn(&dyn PartialReflect) -> Option<Box<dyn FnOnce(&mut World) + Send>>,
+}
+
+impl<E: Event + Reflect + FromReflect + TypePath> FromType<E> for ReflectLuaEvent {
+    fn from_type() -> Self {
+        Self {
+            read: |world, last_count| {
+                let Some(events) = world.get_resource::<Events<E>>() else {
+                    return (vec![], last_count);
+                };
+                let end = events.oldest_event_count() + events.len();
+                let start = last_count.max(events.oldest_event_count());
+                let sent = (start..end)
+                    .filter_map(|id| events.get_event(id))
+                    .map(|(event, _)| event as &dyn Reflect)
+                    .collect();
+                (sent, end)
+            },
+            send: |value| {
+                let event = E::from_reflect(value)?;
+                Some(Box::new(move |world: &mut World| {
+                    if world.send_event(event).is_none() {
+                        warn!("{} was sent from lua, but isn't added as an event", E::type_path());
+                    }
+                }))
+            },
+        }
+    }
+}
+
+/// System parameters for events, exposed to scripts as `MyEvent.reader` and `MyEvent.writer`
+#[derive(Copy, Clone, Debug)]
+pub enum EventParam {
+    Reader(TypeId),
+    Writer(TypeId),
+}
+
+/// A table with `send`, converting values like components in `Commands:spawn`
+pub fn event_writer<'gc>(ctx: Context<'gc>, type_id: TypeId) -> Result<Table<'gc>, anyhow::Error> {
+    let t = Table::new(&ctx);
+    t.set(
+        ctx,
+        "send",
+        Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let (_this, value): (Value, Value) = stack.consume(ctx)?;
+            let context = SystemContext::current(ctx)?;
+            let registry = type_registry(ctx)?.read();
+            let registration = registry
+                .get(type_id)
+                .ok_or_else(|| anyhow!("{type_id:?} isn't registered"))?;
+            let type_path = registration.type_info().type_path();
+            let send = registration
+                .data::<ReflectLuaEvent>()
+                .ok_or_else(|| anyhow!("{type_path} isn't a lua event"))?
+                .send;
+            let event = reflect_from_lua(value, registration.type_info(), &registry)
+                .map_err(|err| anyhow!("can't send {type_path}: {err}"))?;
+            let command = send(event.as_ref())
+                .ok_or_else(|| anyhow!("can't send {type_path}: the value is incomplete"))?;
+            context.commands().push(command);
+            Ok(CallbackReturn::Return)
+        }),
+    )?;
+    Ok(t)
+}
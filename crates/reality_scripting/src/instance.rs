@@ -0,0 +1,261 @@
+// Behaviour scripts, run once for every entity with a `BluaScript`
+
+use crate::asset_loader::LuaScript;
+use crate::callback::set_current_script;
+use crate::entity::{EntityHandle, SystemContext};
+use crate::error::ScriptError;
+use crate::module::script_env;
+use crate::reflect::{ObjectFunctionRegistry, PtrState};
+use crate::sandbox::{self, BudgetExceeded, LuaSandbox};
+use crate::userdata::UserDataPtr;
+use crate::{BluaScript, CommandQueueWrapper, LuaVm};
+use anyhow::anyhow;
+use bevy::prelude::*;
+use piccolo::{Closure, Executor, StashedTable, UserData, Value, Variadic};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::rc::Rc;
+
+/// The script instances of entities with a `BluaScript`
+#[derive(Default)]
+pub struct ScriptInstances(HashMap<Entity, ScriptInstance>);
+
+pub struct ScriptInstance {
+    script: AssetId<LuaScript>,
+    /// `LuaScript::version` the instance was created from
+    version: u64,
+    /// The instance's globals, `None` if the script failed before they were created
+    env: Option<StashedTable>,
+    /// Set when a hook exceeds its budget, or instantiating, `on_spawn` or `on_reload` failed.
+    /// The instance is recreated once the script changes
+    disabled: bool,
+}
+
+impl ScriptInstance {
+    /// Stands in for an instance whose script failed to run
+    fn failed(script: AssetId<LuaScript>, version: u64) -> Self {
+        Self {
+            script,
+            version,
+            env: None,
+            disabled: true,
+        }
+    }
+}
+
+/// Creates and updates the script instances of `BluaScript` entities, calling their
+/// `on_spawn`, `on_update(dt)`, `on_reload(old_globals)` and `on_despawn` hooks
+pub fn run_script_instances(world: &mut World) {
+    let Some(mut lua) = world.remove_non_send_resource::<LuaVm>() else {
+        return;
+    };
+    let Some(lua_scripts) = world.remove_resource::<Assets<LuaScript>>() else {
+        world.insert_non_send_resource(lua);
+        return;
+    };
+    let mut instances = world
+        .remove_non_send_resource::<ScriptInstances>()
+        .unwrap_or_default();
+    let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
+    let dt = world
+        .get_resource::<Time>()
+        .map_or(0.0, |time| time.delta_secs());
+
+    let attached = world
+        .query::<(Entity, &BluaScript)>()
+        .iter(world)
+        .map(|(entity, blua_script)| (entity, blua_script.0.id()))
+        .collect::<Vec<_>>();
+
+    // entities that were despawned, or had their script removed or replaced
+    let detached = instances
+        .0
+        .iter()
+        .filter(|(entity, instance)| !attached.contains(&(**entity, instance.script)))
+        .map(|(entity, _)| *entity)
+        .collect::<Vec<_>>();
+    for entity in detached {
+        let Some(instance) = instances.0.remove(&entity) else {
+            continue;
+        };
+        if let Some(lua_script) = lua_scripts.get(instance.script) {
+            if !instance.disabled {
+                set_current_script(&mut lua, &lua_script.lifetime);
+                let result =
+                    call_hook(world, &mut lua, &sandbox, &instance, "on_despawn", HookArgs::None);
+                report(world, lua_script, entity, "on_despawn", result);
+            }
+        }
+    }
+
+    for (entity, script) in attached {
+        // still loading
+        let Some(lua_script) = lua_scripts.get(script) else {
+            continue;
+        };
+        set_current_script(&mut lua, &lua_script.lifetime);
+        let (hook, args) = match instances.0.get_mut(&entity) {
+            Some(instance) if instance.version == lua_script.version => {
+                if instance.disabled {
+                    continue;
+                }
+                let result =
+                    call_hook(world, &mut lua, &sandbox, instance, "on_update", HookArgs::Dt(dt));
+                if matches!(&result, Err(err) if err.is::<BudgetExceeded>()) {
+                    instance.disabled = true;
+                }
+                report(world, lua_script, entity, "on_update", result);
+                continue;
+            }
+            // the script was hot reloaded, the new instance gets the old one's globals
+            Some(ScriptInstance { env: Some(env), .. }) => {
+                ("on_reload", HookArgs::OldGlobals(env.clone()))
+            }
+            // a new entity, or an instance that failed to run before the reload
+            _ => ("on_spawn", HookArgs::None),
+        };
+        let instantiated = instantiate(world, &mut lua, &sandbox, script, lua_script, entity);
+        let (instance, result) = match instantiated {
+            Ok(mut instance) => {
+                let result = call_hook(world, &mut lua, &sandbox, &instance, hook, args);
+                instance.disabled = result.is_err();
+                (instance, result)
+            }
+            Err(err) => (ScriptInstance::failed(script, lua_script.version), Err(err)),
+        };
+        // failed instances wait for the next version of the script instead of failing every frame
+        instances.0.insert(entity, instance);
+        report(world, lua_script, entity, hook, result);
+    }
+
+    world.insert_non_send_resource(instances);
+    world.insert_resource(lua_scripts);
+    world.insert_non_send_resource(lua);
+}
+
+/// Runs the script with its own globals, with `self` set to the entity
+fn instantiate(
+    world: &mut World,
+    lua: &mut LuaVm,
+    sandbox: &LuaSandbox,
+    script: AssetId<LuaScript>,
+    lua_script: &LuaScript,
+    entity: Entity,
+) -> Result<ScriptInstance, anyhow::Error> {
+    let mut env = None;
+    with_system_context(world, lua, |_world, lua| {
+        let exec = lua
+            .try_enter(|ctx| {
+                let this = EntityHandle::new(entity).into_value(&ctx);
+                let instance_env = script_env(ctx, &lua_script.requires)?;
+                instance_env.set(ctx, "self", this)?;
+                let closure = Closure::load_with_env(
+                    ctx,
+                    Some(&*lua_script.path.to_string()),
+                    Cursor::new(lua_script.source.clone()),
+                    instance_env,
+                )?;
+                env = Some(ctx.stash(instance_env));
+                Ok(ctx.stash(Executor::start(ctx, closure.into(), this)))
+            })
+            .map_err(|err| anyhow!("{err}"))?;
+        sandbox::execute::<()>(lua, &exec, sandbox)
+    })?;
+    Ok(ScriptInstance {
+        script,
+        version: lua_script.version,
+        env: Some(env.ok_or_else(|| anyhow!("the script instance wasn't created"))?),
+        disabled: false,
+    })
+}
+
+enum HookArgs {
+    None,
+    Dt(f32),
+    OldGlobals(StashedTable),
+}
+
+/// Calls a global function of the instance, if it's defined
+fn call_hook(
+    world: &mut World,
+    lua: &mut LuaVm,
+    sandbox: &LuaSandbox,
+    instance: &ScriptInstance,
+    hook: &'static str,
+    args: HookArgs,
+) -> Result<(), anyhow::Error> {
+    let Some(env) = &instance.env else {
+        return Ok(());
+    };
+    with_system_context(world, lua, |_world, lua| {
+        let exec = lua
+            .try_enter(|ctx| {
+                let env = ctx.fetch(env);
+                let Value::Function(function) = env.get::<_, Value>(ctx, hook)? else {
+                    return Ok(None);
+                };
+                let args = match args {
+                    HookArgs::None => vec![],
+                    HookArgs::Dt(dt) => vec![Value::Number(dt as f64)],
+                    HookArgs::OldGlobals(env) => vec![ctx.fetch(&env).into()],
+                };
+                Ok(Some(ctx.stash(Executor::start(ctx, function, Variadic(args)))))
+            })
+            .map_err(|err| anyhow!("{err}"))?;
+        match exec {
+            Some(exec) => sandbox::execute::<()>(lua, &exec, sandbox),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Lets entity handles and `Commands` be used while `f` runs, like in lua systems
+pub(crate) fn with_system_context<R>(
+    world: &mut World,
+    lua: &mut LuaVm,
+    f: impl FnOnce(&mut World, &mut LuaVm) -> R,
+) -> R {
+    world.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
+    let function_registry = world
+        .non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
+        .clone();
+    let mut command_queue = CommandQueueWrapper::new(world);
+    let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+    let system_context = SystemContext {
+        world: world as *mut World,
+        commands: &mut command_queue as *mut CommandQueueWrapper,
+        ptr_state: ptr_state.clone(),
+        function_registry,
+        queried: vec![],
+        claimed: RefCell::default(),
+    };
+    let _ = lua.try_enter(|ctx| {
+        ctx.set_global(
+            SystemContext::GLOBAL,
+            UserData::new_static(&ctx, system_context),
+        );
+        Ok(())
+    });
+    let result = f(world, lua);
+    let _ = lua.try_enter(|ctx| {
+        ctx.set_global(SystemContext::GLOBAL, Value::Nil);
+        Ok(())
+    });
+    *ptr_state.borrow_mut() = PtrState::Invalid;
+    command_queue.commands.apply(world);
+    result
+}
+
+fn report(
+    world: &mut World,
+    lua_script: &LuaScript,
+    entity: Entity,
+    hook: &str,
+    result: Result<(), anyhow::Error>,
+) {
+    if let Err(err) = result {
+        ScriptError::new(lua_script.path.to_string(), Some(format!("{hook} of {entity}")), &err)
+            .report(world);
+    }
+}
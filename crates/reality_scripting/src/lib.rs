@@ -1,33 +1,66 @@
 pub mod asset_loader;
-mod bevy_wrapper;
+pub mod bevy_wrapper;
+pub mod callback;
+mod convert;
+pub mod definitions;
+pub mod embedded;
+mod entity;
+pub mod error;
+pub mod event;
+pub mod instance;
+pub mod module;
+pub mod naming;
+mod operators;
+pub mod persist;
 mod reflect;
+pub mod sandbox;
+pub mod schedule;
+pub mod testing;
 pub mod userdata;
 
-use crate::asset_loader::{LuaAssetCommunicator, LuaAssetLoader, LuaScript};
+use crate::asset_loader::{LuaAssetCommunicator, LuaAssetLoader, LuaScript, LuaSource};
+use crate::bevy_wrapper::BevyWrapperPlugin;
+use crate::callback::{set_current_script, LuaCallback, ScriptLifetime};
+use crate::convert::{
+    components_from_entries, components_from_table, reflect_from_lua, reflect_to_lua,
+    registration_by_name, type_registry,
+};
+use crate::entity::{EntityHandle, SystemContext};
+use crate::error::ScriptError;
+use crate::event::{event_writer, ReflectLuaEvent};
+use crate::instance::run_script_instances;
+use crate::module::{run_module, script_env, LuaModule, LuaModuleLoader};
+use crate::naming::LuaNaming;
+use crate::operators::MathOperatorsPlugin;
+use crate::persist::{restore_script_state, save_script_state, ScriptState};
 use crate::reflect::{
-    ComponentType, ObjectFunctionRegistry, PtrState, ReflectPlugin, ReflectPtr, ReflectType,
-    SystemParameter, WorldMut,
+    lua_table_at_path, ComponentType, LuaSystem, ObjectFunctionRegistry, PtrState, QueryData,
+    ReflectPlugin, ReflectPtr, SystemParameter, WorldMut,
 };
+use crate::sandbox::{BudgetExceeded, BudgetPolicy, LuaSandbox};
+use crate::schedule::{sort_by_labels, warn_once, LuaLabelSets, LuaSchedule, LuaSystems, RunCondition, SystemOptions};
 use crate::userdata::{UserDataPtr, ValueExt};
-use bevy::ecs::component::ComponentId;
+use anyhow::anyhow;
+use bevy::asset::AssetPath;
 use bevy::ecs::world::CommandQueue;
 use bevy::prelude::*;
-use bevy::ptr::OwningPtr;
 use bevy::reflect::func::args::Ownership;
 use bevy::reflect::func::{
     ArgList, DynamicFunction, FunctionRegistry, Return,
 };
-use bevy::reflect::{ReflectFromPtr, Typed};
+use bevy::reflect::{
+    PartialReflect, ReflectFromPtr, ReflectFromReflect, ReflectRef, TypeData, TypeRegistry, Typed,
+};
 use piccolo::{
-    Callback, CallbackReturn, Closure, Context, Executor, IntoValue, Lua, Table,
-    UserData, Value, Variadic,
+    Callback, CallbackReturn, Closure, Context, Executor, IntoValue, Lua,
+    StashedTable, Table, UserData, Value, Variadic,
 };
 use send_wrapper::SendWrapper;
 use std::any::TypeId;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::ops::DerefMut;
-use std::ptr::NonNull;
 use std::rc::Rc;
 use std::sync::Mutex;
 
@@ -54,16 +87,70 @@ impl TableReflectWrapper {
 impl Plugin for LuaPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(ReflectPlugin);
-        app.init_asset_loader::<LuaAssetLoader>()
-            .init_asset::<LuaScript>();
+        app.add_plugins(MathOperatorsPlugin);
+        app.add_plugins(BevyWrapperPlugin);
+        app.add_event::<ScriptError>();
+        app.init_resource::<LuaSandbox>();
+        app.init_resource::<LuaNaming>();
+        app.register_type::<LuaCallback>();
+        app.register_type::<ScriptState>().init_resource::<ScriptState>();
+        // restored before scripts run, captured after, so snapshots taken between updates are current
+        app.add_systems(First, restore_script_state);
+        app.add_systems(Last, save_script_state);
+        if app.world().contains_resource::<AssetServer>() {
+            app.init_asset_loader::<LuaAssetLoader>()
+                .init_asset::<LuaScript>()
+                .init_asset_loader::<LuaModuleLoader>()
+                .init_asset::<LuaModule>();
+        } else {
+            // e.g. AO processes, which load scripts from bytes
+            app.init_resource::<Assets<LuaScript>>();
+        }
         app.add_systems(Startup, insert_lua_vm);
-        app.add_systems(Update, lua_asset_handling);
-        app.add_systems(Update, run_every_tick);
+        app.add_systems(Update, lua_asset_handling.before(LuaSystems));
+        app.add_systems(
+            PreUpdate,
+            (|world: &mut World| run_every_tick(world, LuaSchedule::PreUpdate)).in_set(LuaSystems),
+        );
+        app.add_systems(
+            Update,
+            (|world: &mut World| run_every_tick(world, LuaSchedule::Update)).in_set(LuaSystems),
+        );
+        app.add_systems(Update, run_script_instances.in_set(LuaSystems));
+        app.add_systems(
+            FixedUpdate,
+            (|world: &mut World| run_every_tick(world, LuaSchedule::FixedUpdate))
+                .in_set(LuaSystems),
+        );
+        app.add_systems(
+            PostUpdate,
+            (|world: &mut World| run_every_tick(world, LuaSchedule::PostUpdate)).in_set(LuaSystems),
+        );
         app.register_object_function::<CommandQueueWrapper>(
             CommandQueueWrapper::spawn
                 .into_function()
                 .with_name("spawn"),
         );
+        app.register_object_function::<CommandQueueWrapper>(
+            CommandQueueWrapper::spawn_child
+                .into_function()
+                .with_name("spawn_child"),
+        );
+        app.register_object_function::<CommandQueueWrapper>(
+            CommandQueueWrapper::insert
+                .into_function()
+                .with_name("insert"),
+        );
+        app.register_object_function::<CommandQueueWrapper>(
+            CommandQueueWrapper::insert_resource
+                .into_function()
+                .with_name("insert_resource"),
+        );
+        app.register_object_function::<CommandQueueWrapper>(
+            CommandQueueWrapper::remove_resource
+                .into_function()
+                .with_name("remove_resource"),
+        );
     }
 }
 
@@ -89,7 +176,23 @@ pub fn lua_wrapped_dynamic_function_call<'gc>(
         let args_uwu: Variadic<Vec<Value>> = stack.consume(context)?;
         let signatures = function.info().signatures();
         let args = signatures.iter().map(|info| info.args()).flatten();
+        let registry = type_registry(context)?.read();
+        // references returned by the function point into its reflected arguments
+        let mut borrowed_state = None;
+        let mut returns_borrowable = true;
         for (v, arg_info) in args_uwu.into_iter().zip(args) {
+            // plain values are converted to the argument's type where it's known, e.g. numbers to `f32`
+            let is_plain = matches!(
+                v,
+                Value::Boolean(_) | Value::Integer(_) | Value::Number(_) | Value::String(_)
+            );
+            if is_plain && arg_info.ownership() == Ownership::Owned {
+                if let Some(registration) = registry.get(arg_info.type_id()) {
+                    args_list = args_list
+                        .push_boxed(reflect_from_lua(v, registration.type_info(), &registry)?);
+                    continue;
+                }
+            }
             match v {
                 Value::Nil => {
                     args_list = args_list.push_owned(());
@@ -104,69 +207,210 @@ pub fn lua_wrapped_dynamic_function_call<'gc>(
                     args_list = args_list.push_owned(float);
                 }
                 Value::String(lua_string) => {
-                    args_list = args_list.push_owned(lua_string.to_str().unwrap().to_string())
+                    args_list = args_list.push_owned(lua_string.to_str()?.to_string())
                 }
                 Value::Table(table) => {
                     args_list = args_list.push_owned(unsafe { TableReflectWrapper::new(table) });
                 }
                 Value::Function(lua_function) => {
-                    todo!()
+                    args_list = args_list.push_owned(LuaCallback::new(context, lua_function));
                 }
                 Value::UserData(user_data) => {
                     if let Ok(reflect) = user_data.downcast_static::<ReflectPtr>() {
+                        if arg_info.ownership() != Ownership::Owned {
+                            match reflect.borrowed_state() {
+                                Some(state) => {
+                                    borrowed_state.get_or_insert(state);
+                                }
+                                None => returns_borrowable = false,
+                            }
+                        }
                         match arg_info.ownership() {
                             Ownership::Ref => {
                                 args_list = args_list
-                                    .push_ref(reflect.get_field_value_ref().as_partial_reflect())
+                                    .push_ref(reflect.get_field_value_ref()?.as_partial_reflect())
                             }
                             Ownership::Mut => {
                                 args_list = args_list.push_mut(
-                                    reflect.get_field_value_mut().as_partial_reflect_mut(),
+                                    reflect.get_field_value_mut()?.as_partial_reflect_mut(),
                                 )
                             }
-                            Ownership::Owned => todo!(),
+                            // the function gets its own copy
+                            Ownership::Owned => {
+                                let value = reflect.get_field_value_ref()?;
+                                let owned = registry
+                                    .get_type_data::<ReflectFromReflect>(arg_info.type_id())
+                                    .and_then(|from_reflect| {
+                                        from_reflect.from_reflect(value.as_partial_reflect())
+                                    })
+                                    .ok_or_else(|| {
+                                        anyhow!(
+                                            "{}: can't pass {} by value, it needs `#[reflect(FromReflect)]`",
+                                            function_name(&function),
+                                            value.reflect_type_path()
+                                        )
+                                    })?;
+                                args_list = args_list.push_boxed(owned.into_partial_reflect());
+                            }
                         }
+                    } else if let Ok(entity_handle) = user_data.downcast_static::<EntityHandle>() {
+                        args_list = args_list.push_owned(entity_handle.entity);
                     } else {
-                        todo!()
+                        return Err(anyhow!(
+                            "{}: can't pass this userdata to a reflected function",
+                            function_name(&function)
+                        )
+                        .into());
                     }
                 }
                 Value::Thread(_) => {}
             }
         }
-        let ret = function.call(args_list).unwrap();
-        match ret {
-            Return::Owned(mut owned) => {
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<f32>() {
-                    stack.push_front(Value::Number(*awa as f64))
-                }
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<f64>() {
-                    stack.push_front(Value::Number(*awa))
-                }
-
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<i32>() {
-                    stack.push_front(Value::Integer(*awa as i64))
-                }
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<i64>() {
-                    stack.push_front(Value::Integer(*awa))
+        drop(registry);
+        let ret = function
+            .call(args_list)
+            .map_err(|err| anyhow!("{}: {err}", function_name(&function)))?;
+        let registry = type_registry(context)?.read();
+        // without an argument to borrow from, references are copied
+        let borrowed_state = borrowed_state.filter(|_| returns_borrowable);
+        match (ret, borrowed_state) {
+            (Return::Owned(owned), _) => {
+                // `Err`s returned by functions become lua errors
+                let owned = unwrap_result(owned, &registry)
+                    .map_err(|err| anyhow!("{}: {err}", function_name(&function)))?;
+                // tuples are returned as multiple values
+                if let ReflectRef::Tuple(tuple) = owned.reflect_ref() {
+                    for field in tuple.iter_fields() {
+                        stack.push_back(owned_to_lua(
+                            context,
+                            concrete_value(field, &registry),
+                            object_function_registry.clone(),
+                        )?);
+                    }
+                } else {
+                    stack.push_back(owned_to_lua(
+                        context,
+                        owned,
+                        object_function_registry.clone(),
+                    )?);
                 }
-                let reflect_ptr = ReflectPtr::new_boxed(
-                    owned.try_into_reflect().unwrap(),
-                    Rc::new(RefCell::new(PtrState::Valid)),
-                    object_function_registry.clone(),
-                );
-                stack.push_front(reflect_ptr.into_value(&context));
             }
-            Return::Ref(_) => {
-                warn!("todo return &");
+            (Return::Ref(reference), Some(state)) => {
+                let value = match reflect_to_lua(context, reference) {
+                    Some(value) => value,
+                    None => ReflectPtr::new_ref(
+                        fully_reflected(reference)?,
+                        state,
+                        object_function_registry.clone(),
+                    )
+                    .into_value(&context),
+                };
+                stack.push_back(value);
+            }
+            (Return::Mut(reference), Some(state)) => {
+                let value = match reflect_to_lua(context, reference) {
+                    Some(value) => value,
+                    None => ReflectPtr::new_mut(
+                        reference.try_as_reflect_mut().ok_or_else(|| {
+                            anyhow!("{} is not fully reflected", reference.reflect_type_path())
+                        })?,
+                        state,
+                        object_function_registry.clone(),
+                    )
+                    .into_value(&context),
+                };
+                stack.push_back(value);
             }
-            Return::Mut(_) => {
-                warn!("todo return &mut");
+            (Return::Ref(reference), None) => {
+                stack.push_back(owned_to_lua(
+                    context,
+                    concrete_value(reference, &registry),
+                    object_function_registry.clone(),
+                )?);
+            }
+            (Return::Mut(reference), None) => {
+                stack.push_back(owned_to_lua(
+                    context,
+                    concrete_value(reference, &registry),
+                    object_function_registry.clone(),
+                )?);
             }
         }
         Ok(CallbackReturn::Return)
     })
     .into_value(ctx)
 }
+fn function_name(function: &DynamicFunction) -> String {
+    function
+        .name()
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "anonymous function".to_string())
+}
+
+/// Turns `Ok(value)` into `value`, and `Err(message)` into an error
+fn unwrap_result(
+    owned: Box<dyn PartialReflect>,
+    registry: &TypeRegistry,
+) -> Result<Box<dyn PartialReflect>, anyhow::Error> {
+    let ReflectRef::Enum(result) = owned.reflect_ref() else {
+        return Ok(owned);
+    };
+    let is_result = result
+        .get_represented_enum_info()
+        .is_some_and(|info| info.type_path_table().ident() == Some("Result"));
+    if !is_result {
+        return Ok(owned);
+    }
+    let Some(value) = result.field_at(0) else {
+        return Ok(owned);
+    };
+    if result.variant_name() == "Err" {
+        return Err(match value.try_downcast_ref::<String>() {
+            Some(message) => anyhow!("{message}"),
+            None => anyhow!("{value:?}"),
+        });
+    }
+    Ok(concrete_value(value, registry))
+}
+
+/// Copies a value, as its concrete type where possible since `clone_value` may give a dynamic value
+pub(crate) fn concrete_value(value: &dyn PartialReflect, registry: &TypeRegistry) -> Box<dyn PartialReflect> {
+    let from_reflect = value
+        .get_represented_type_info()
+        .and_then(|info| registry.get_type_data::<ReflectFromReflect>(info.type_id()))
+        .and_then(|from_reflect| from_reflect.from_reflect(value));
+    match from_reflect {
+        Some(value) => value.into_partial_reflect(),
+        None => value.clone_value(),
+    }
+}
+
+fn fully_reflected(reference: &dyn PartialReflect) -> Result<&dyn Reflect, anyhow::Error> {
+    reference
+        .try_as_reflect()
+        .ok_or_else(|| anyhow!("{} is not fully reflected", reference.reflect_type_path()))
+}
+
+/// Numbers, strings etc. become plain lua values, entities become handles, anything else is boxed
+pub(crate) fn owned_to_lua<'gc>(
+    ctx: Context<'gc>,
+    owned: Box<dyn PartialReflect>,
+    object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Result<Value<'gc>, anyhow::Error> {
+    if let Some(value) = reflect_to_lua(ctx, owned.as_ref()) {
+        return Ok(value);
+    }
+    let owned = owned
+        .try_into_reflect()
+        .map_err(|owned| anyhow!("{} is not fully reflected", owned.reflect_type_path()))?;
+    let reflect_ptr = ReflectPtr::new_boxed(
+        owned,
+        Rc::new(RefCell::new(PtrState::Valid)),
+        object_function_registry,
+    );
+    Ok(reflect_ptr.into_value(&ctx))
+}
+
 impl AppExtensionFunctionRegisterTrait for App {
     fn register_object_function<T: Reflect>(&mut self, function: DynamicFunction<'static>) {
         self.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
@@ -211,56 +455,23 @@ impl AppExtensionFunctionRegisterTrait for App {
 
         let world = self.world_mut();
 
-        let things = T::type_info()
-            .type_path()
-            .split("::")
-            .collect::<Vec<&str>>();
+        let type_path = T::type_info().type_path();
+        let Some(name) = function.name().map(|name| name.to_string()) else {
+            warn!("{type_path} functions need a name to be called from lua");
+            return;
+        };
 
-        // uncomment this if you wanna see the path of all the things aviable to you
-        //println!("{:?}", things);
         world.init_non_send_resource::<LuaVm>();
         let mut lua = world.get_non_send_resource_mut::<LuaVm>().unwrap();
-        lua.lua
-            .try_enter(move |ctx| {
-                let mut lua_table = ctx.globals();
-                let len = things.len();
-                let f = function.clone();
-                for (i, item) in things.into_iter().enumerate() {
-                    if i + 1 == len {
-                        let name = function.name().unwrap().to_string();
-
-                        let f = f.clone();
-                        let function = lua_wrapped_dynamic_function_call(ctx, f, ofr1);
-
-                        let t = match lua_table.get(ctx, item).unwrap() {
-                            Value::Nil => {
-                                lua_table.set(ctx, item, Table::new(&ctx)).unwrap();
-                                match lua_table.get(ctx, item).unwrap() {
-                                    Value::Table(table) => table,
-                                    _ => unreachable!(),
-                                }
-                            }
-                            Value::Table(table) => table,
-                            _ => panic!("awa"),
-                        };
-                        t.set(ctx, name, function).unwrap();
-                        break;
-                    }
-                    lua_table = match lua_table.get(ctx, item).unwrap() {
-                        Value::Nil => {
-                            lua_table.set(ctx, item, Table::new(&ctx)).unwrap();
-                            match lua_table.get(ctx, item).unwrap() {
-                                Value::Table(table) => table,
-                                _ => unreachable!(),
-                            }
-                        }
-                        Value::Table(table) => table,
-                        _ => panic!("awa"),
-                    };
-                }
-                Ok(())
-            })
-            .unwrap();
+        let result = lua.lua.try_enter(move |ctx| {
+            let t = lua_table_at_path(ctx, type_path)?;
+            let function = lua_wrapped_dynamic_function_call(ctx, function, ofr1);
+            t.set(ctx, name, function)?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("couldn't expose {type_path} function to lua: {err}");
+        }
     }
 }
 
@@ -272,64 +483,216 @@ struct HashMapWrapper {
 
 pub fn insert_lua_vm(world: &mut World) {
     world.init_non_send_resource::<LuaVm>();
+    let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
+    if let Some(mut lua) = world.get_non_send_resource_mut::<LuaVm>() {
+        if !lua.sandboxed {
+            sandbox.apply(&mut lua);
+            lua.sandboxed = true;
+        }
+    }
 }
 
 pub fn lua_asset_handling(world: &mut World) {
+    // scripts are only loaded from bytes without an `AssetServer`, see `embedded`
+    if !world.contains_resource::<LuaAssetCommunicator>() {
+        return;
+    }
     world.resource_scope(|world, lua_asset_communicator: Mut<LuaAssetCommunicator>| {
         let Some(mut lua) = world.remove_non_send_resource::<LuaVm>() else {
             return;
         };
 
+        let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
         let mut lua_app = WorldMut::new(world);
-        for (new_script_bytes, new_script_path) in
-            lua_asset_communicator.lua_script_bytes_rx.try_iter()
-        {
-            let mut systems_vec = Rc::new(RefCell::new(Some(Vec::new())));
-            let exec = lua
-                .try_enter(|ctx| {
-                    let user_data = UserData::new_static(&ctx, systems_vec.clone());
-                    ctx.set_global("__systems_vec", user_data);
-                    let lua_app_value = lua_app.clone().into_value(&ctx);
-                    let closure = Closure::load(
-                        ctx,
-                        Some(&*new_script_path.to_string()),
-                        Cursor::new(new_script_bytes),
-                    )?;
-                    Ok(ctx.stash(Executor::start(ctx, closure.into(), lua_app_value)))
-                })
-                .unwrap();
-            lua.execute::<()>(&exec).unwrap();
-            lua_asset_communicator
-                .lua_script_tx
-                .send(LuaScript {
-                    systems: SendWrapper::new(systems_vec.take().unwrap()),
-                })
-                .unwrap();
+        for source in lua_asset_communicator.lua_script_bytes_rx.try_iter() {
+            let old_state = previous_state(world, &source.path);
+            let lua_script = run_script(world, &mut lua, &lua_app, &sandbox, source, old_state);
+            if lua_asset_communicator.lua_script_tx.send(lua_script).is_err() {
+                warn!("the lua asset loader was dropped before the script loaded");
+            }
         }
-        lua.try_enter(|ctx| {
-            ctx.set_global("__systems_vec", Value::Nil);
-            Ok(CallbackReturn::Return)
-        })
-        .unwrap();
-        lua_app.this.take().unwrap();
+        clear_loading_globals(&mut lua);
+        lua_app.this = None;
         drop(lua_app);
         world.insert_non_send_resource(lua);
     });
 }
 
+/// Runs a new version of a script, reporting the error if it fails to load
+pub(crate) fn run_script(
+    world: &mut World,
+    lua: &mut LuaVm,
+    lua_app: &WorldMut,
+    sandbox: &LuaSandbox,
+    source: LuaSource,
+    old_state: Option<StashedTable>,
+) -> Result<LuaScript, anyhow::Error> {
+    let new_script_path = source.path;
+    let lifetime = ScriptLifetime::new();
+    set_current_script(lua, &lifetime);
+    let systems_vec = Rc::new(RefCell::new(Some(Vec::new())));
+    let mut env = None;
+    let result = source
+        .modules
+        .iter()
+        .try_for_each(|module| {
+            run_module(lua, module, sandbox)
+                .map_err(|err| anyhow!("in module {}: {err}", module.path))
+        })
+        .and_then(|()| {
+            lua.try_enter(|ctx| {
+                let user_data = UserData::new_static(&ctx, systems_vec.clone());
+                ctx.set_global("__systems_vec", user_data);
+                ctx.set_global(WorldMut::ON_RELOAD_GLOBAL, Value::Nil);
+                let lua_app_value = lua_app.clone().into_value(&ctx);
+                // every script gets its own globals, so a reload doesn't leave the old ones around
+                let script_env = script_env(ctx, &source.requires)?;
+                let closure = Closure::load_with_env(
+                    ctx,
+                    Some(&*new_script_path.to_string()),
+                    Cursor::new(source.bytes.clone()),
+                    script_env,
+                )?;
+                env = Some(ctx.stash(script_env));
+                Ok(ctx.stash(Executor::start(ctx, closure.into(), lua_app_value)))
+            })
+            .map_err(|err| anyhow!("{err}"))
+        })
+        .and_then(|exec| {
+            sandbox::finish(lua, &exec, sandbox)?;
+            lua.try_enter(|ctx| {
+                let state = ctx.fetch(&exec).take_result::<Option<Table>>(ctx)??;
+                Ok(state.map(|state| ctx.stash(state)))
+            })
+            .map_err(|err| anyhow!("{err}"))
+        })
+        .and_then(|state| {
+            if let Some(old_state) = old_state {
+                call_on_reload(lua, old_state, sandbox)?;
+            }
+            Ok(state)
+        });
+    match result {
+        Ok(state) => Ok(LuaScript {
+            path: new_script_path,
+            version: {
+                lua.loaded_scripts += 1;
+                lua.loaded_scripts
+            },
+            source: source.bytes,
+            requires: source.requires,
+            lifetime,
+            systems: SendWrapper::new(systems_vec.take().unwrap_or_default()),
+            state: SendWrapper::new(state),
+            env: SendWrapper::new(env),
+            // filled in by the loader
+            modules: vec![],
+        }),
+        // the previous version of the script keeps running
+        Err(err) => {
+            lifetime.end();
+            ScriptError::new(new_script_path.to_string(), None, &err).report(world);
+            Err(err)
+        }
+    }
+}
+
+/// Unsets what's only available while scripts load
+pub(crate) fn clear_loading_globals(lua: &mut LuaVm) {
+    let _ = lua.try_enter(|ctx| {
+        ctx.set_global("__systems_vec", Value::Nil);
+        ctx.set_global(WorldMut::ON_RELOAD_GLOBAL, Value::Nil);
+        Ok(())
+    });
+}
+
+/// The state returned by the currently loaded version of the script at `path`, if it's being reloaded
+fn previous_state(world: &World, path: &AssetPath<'static>) -> Option<StashedTable> {
+    let handle = world
+        .get_resource::<AssetServer>()?
+        .get_handle::<LuaScript>(path.clone())?;
+    let lua_script = world.get_resource::<Assets<LuaScript>>()?.get(&handle)?;
+    (*lua_script.state).clone()
+}
+
+/// Hands the previous version's state to the `on_reload` hook the new version set, if any
+fn call_on_reload(
+    lua: &mut LuaVm,
+    old_state: StashedTable,
+    sandbox: &LuaSandbox,
+) -> Result<(), anyhow::Error> {
+    let exec = lua.try_enter(|ctx| {
+        let hook = ctx
+            .globals()
+            .get::<_, Value>(ctx, WorldMut::ON_RELOAD_GLOBAL)?;
+        let Value::Function(hook) = hook else {
+            return Ok(None);
+        };
+        Ok(Some(ctx.stash(Executor::start(ctx, hook, ctx.fetch(&old_state)))))
+    })
+    .map_err(|err| anyhow!("{err}"))?;
+    match exec {
+        Some(exec) => sandbox::execute::<()>(lua, &exec, sandbox),
+        None => Ok(()),
+    }
+}
+
+pub enum QueryItem {
+    Entity(Entity),
+    Component(ReflectPtr),
+    /// An optional component the entity doesn't have
+    Missing,
+}
+
 pub struct IteratorState {
-    pub components: Vec<Vec<ReflectPtr>>,
+    pub components: Vec<Vec<QueryItem>>,
     pub ptr_state: Rc<RefCell<PtrState>>,
 }
 
 impl IteratorState {
+    /// A table with a `method` iterating over `items`, e.g. `query:iter()`
+    fn table<'gc>(
+        ctx: Context<'gc>,
+        method: &'static str,
+        items: Vec<Vec<QueryItem>>,
+        ptr_state: Rc<RefCell<PtrState>>,
+    ) -> Result<Table<'gc>, anyhow::Error> {
+        let iterator_state = ctx.stash(UserData::new_static(
+            &ctx,
+            Mutex::new(IteratorState {
+                components: items,
+                ptr_state,
+            }),
+        ));
+        let t = Table::new(&ctx);
+        t.set(
+            ctx,
+            method,
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                let iterator_state = ctx.fetch(&iterator_state).into_value(ctx);
+                *iterator_state
+                    .as_static_user_data::<Mutex<IteratorState>>()?
+                    .lock()
+                    .map_err(|_| anyhow!("query iterator was poisoned"))?
+                    .ptr_state
+                    .borrow_mut() = PtrState::Valid;
+                stack.replace(ctx, (IteratorState::iterator_fn(&ctx), iterator_state));
+
+                Ok(CallbackReturn::Return)
+            }),
+        )?;
+        Ok(t)
+    }
+
     fn iterator_fn<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
         Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
             let state: UserData = stack.consume(ctx)?;
 
             let state = state.downcast_static::<Mutex<IteratorState>>()?;
 
-            let mut state = state.lock().unwrap();
+            let mut state = state
+                .lock()
+                .map_err(|_| anyhow!("query iterator was poisoned"))?;
             let state = state.deref_mut();
             if state.components.is_empty() {
                 return Ok(CallbackReturn::Return);
@@ -337,7 +700,13 @@ impl IteratorState {
             let next_value_vec = state.components.remove(0);
 
             for value in next_value_vec {
-                stack.push_back(value.into_value(&ctx));
+                match value {
+                    QueryItem::Entity(entity) => {
+                        stack.push_back(EntityHandle::new(entity).into_value(&ctx))
+                    }
+                    QueryItem::Component(value) => stack.push_back(value.into_value(&ctx)),
+                    QueryItem::Missing => stack.push_back(Value::Nil),
+                }
             }
 
             Ok(CallbackReturn::Return)
@@ -345,176 +714,359 @@ impl IteratorState {
     }
 }
 
-pub fn run_every_tick(world: &mut World) {
-    let mut lua = world.remove_non_send_resource::<LuaVm>().unwrap();
+/// Runs the lua systems registered in `schedule`, see `LuaPlugin`.
+/// Systems with a label given its own set by `add_lua_label` are left to that set's runner
+pub fn run_every_tick(world: &mut World, schedule: LuaSchedule) {
+    run_lua_systems(world, schedule, None);
+}
 
-    let mut lua_scripts = world.remove_resource::<Assets<LuaScript>>().unwrap();
+/// Runs the lua systems registered in `schedule`, only those labelled `label` if it's given
+pub(crate) fn run_lua_systems(world: &mut World, schedule: LuaSchedule, label: Option<&str>) {
+    let own_sets = world
+        .get_resource::<LuaLabelSets>()
+        .and_then(|sets| sets.0.get(&schedule))
+        .cloned()
+        .unwrap_or_default();
+    let in_runner = |options: &SystemOptions| match label {
+        Some(label) => options.label.as_deref() == Some(label),
+        None => !options
+            .label
+            .as_ref()
+            .is_some_and(|label| own_sets.contains(label)),
+    };
+    let Some(mut lua) = world.remove_non_send_resource::<LuaVm>() else {
+        return;
+    };
+    let Some(mut lua_scripts) = world.remove_resource::<Assets<LuaScript>>() else {
+        world.insert_non_send_resource(lua);
+        return;
+    };
 
     let app_registry = world.get_resource::<AppTypeRegistry>().unwrap().clone();
+    let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
     world.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
     let object_function_registry = world
         .get_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
         .unwrap()
         .clone();
-    for (_, awa) in lua_scripts.iter_mut() {
-        let mut command_queue = CommandQueueWrapper {
-            commands: Default::default(),
+    let order = {
+        let mut systems = lua_scripts
+            .iter()
+            .flat_map(|(id, lua_script)| {
+                lua_script
+                    .systems
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, system)| system.runs_in(schedule) && in_runner(&system.options))
+                    .map(move |(index, system)| ((id, index), &system.options))
+            })
+            .collect::<Vec<_>>();
+        // startup systems go first
+        systems.sort_by_key(|(_, options)| options.schedule != LuaSchedule::Startup);
+        sort_by_labels(&systems)
+    };
+    let set_name = match label {
+        Some(label) => format!("the {label:?} set of {schedule:?}"),
+        None => format!("{schedule:?}"),
+    };
+    // startup systems that already ran and disabled systems still count as labelled
+    let labels = lua_scripts
+        .iter()
+        .flat_map(|(_, lua_script)| &lua_script.systems)
+        .filter(|system| system.options.schedule.runner() == schedule && in_runner(&system.options))
+        .filter_map(|system| system.options.label.clone())
+        .collect::<Vec<_>>();
+    for unknown in order.unknown_labels.iter().filter(|label| !labels.contains(label)) {
+        warn_once(
+            world,
+            format!("no lua system in {set_name} is labelled {unknown:?}, its `before`/`after` is ignored"),
+        );
+    }
+    if !order.cycle.is_empty() {
+        let names = order
+            .cycle
+            .iter()
+            .filter_map(|(id, index)| {
+                let lua_script = lua_scripts.get(*id)?;
+                Some(format!("{} in {}", lua_script.systems[*index].name, lua_script.path))
+            })
+            .collect::<Vec<_>>();
+        warn_once(
+            world,
+            format!(
+                "the `before`/`after` of lua systems in {set_name} form a cycle, running these last: {}",
+                names.join(", ")
+            ),
+        );
+    }
+    let order = order.order;
+    for (id, index) in order {
+        let Some(lua_script) = lua_scripts.get_mut(id) else {
+            continue;
+        };
+        let script = lua_script.path.to_string();
+        set_current_script(&mut lua, &lua_script.lifetime);
+        let mut command_queue = CommandQueueWrapper::new(world);
+        let awa = &mut lua_script.systems[index];
+        match check_run_conditions(world, &mut lua, awa, &sandbox) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                let mut script_error = ScriptError::new(script, Some(awa.name.clone()), &err);
+                script_error.message = format!("run condition failed: {}", script_error.message);
+                script_error.report(world);
+                continue;
+            }
+        }
+        awa.has_run = true;
+        let stashed_function = &awa.lua_func;
+        let mut ptr_states = vec![];
+        let ofr1 = object_function_registry.clone();
+        let last_run = awa.last_run;
+        let this_run = world.increment_change_tick();
+        let context_ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+        let queried = awa
+            .system_parameters
+            .iter()
+            .filter_map(|system_parameter| match system_parameter {
+                SystemParameter::Query((_, component_infos, _)) => Some(component_infos),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|query_data| match query_data {
+                QueryData::Component(
+                    ComponentType::Ref((component_id, _)) | ComponentType::OptionRef((component_id, _)),
+                ) => Some((*component_id, false)),
+                QueryData::Component(
+                    ComponentType::Mut((component_id, _)) | ComponentType::OptionMut((component_id, _)),
+                ) => Some((*component_id, true)),
+                QueryData::Entity => None,
+            })
+            .collect();
+        let system_context = SystemContext {
+            world: world as *mut World,
+            commands: &mut command_queue as *mut CommandQueueWrapper,
+            ptr_state: context_ptr_state.clone(),
+            function_registry: object_function_registry.clone(),
+            queried,
+            claimed: RefCell::default(),
         };
-        for awa in awa.systems.iter_mut() {
-            let stashed_function = &awa.lua_func;
-            let mut ptr_states = vec![];
-            let ofr1 = object_function_registry.clone();
-            let (exec) = lua
-                .try_enter(|ctx| {
-                    let func = ctx.fetch(stashed_function);
-                    let mut things = vec![];
-
-                    for system_parameter in &mut awa.system_parameters {
-                        let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
-                        let ptr_state2 = ptr_state.clone();
-                        match system_parameter {
-                            SystemParameter::Query((query, component_infos)) => {
-                                let items = query.iter_mut(world).collect::<Vec<_>>();
-                                let items = items
-                                    .into_iter()
-                                    .map(|mut a| {
-                                        let mut values = vec![];
-                                        //a.components();
-                                        for component_type in component_infos.iter() {
-                                            match component_type {
-                                                ComponentType::Ref((component_id, type_id)) => {
-                                                    let mut x = a.get_by_id(*component_id).unwrap();
-                                                    let app_registry = app_registry.read();
-                                                    let reflect_data =
-                                                        app_registry.get(*type_id).unwrap();
-                                                    let reflect_from_ptr = reflect_data
-                                                        .data::<ReflectFromPtr>()
-                                                        .unwrap();
-                                                    let value =
-                                                        unsafe { reflect_from_ptr.as_reflect(x) };
-                                                    values.push(ReflectPtr::new_ref(
-                                                        value,
-                                                        ptr_state2.clone(),
-                                                        ofr1.clone(),
-                                                    ));
-                                                }
-                                                ComponentType::Mut((component_id, type_id)) => {
-                                                    let mut x =
-                                                        a.get_mut_by_id(*component_id).unwrap();
-                                                    let app_registry = app_registry.read();
-                                                    let reflect_data =
-                                                        app_registry.get(*type_id).unwrap();
-                                                    let reflect_from_ptr = reflect_data
-                                                        .data::<ReflectFromPtr>()
-                                                        .unwrap();
-                                                    let value = unsafe {
-                                                        reflect_from_ptr.as_reflect_mut(x.as_mut())
-                                                    };
-                                                    values.push(ReflectPtr::new_mut(
-                                                        value,
-                                                        ptr_state2.clone(),
-                                                        ofr1.clone(),
-                                                    ));
-                                                }
+        ptr_states.push(context_ptr_state);
+        let result = lua
+            .try_enter(|ctx| {
+                let func = ctx.fetch(stashed_function);
+                let mut things = vec![];
+                ctx.set_global(
+                    SystemContext::GLOBAL,
+                    UserData::new_static(&ctx, system_context),
+                );
+
+                for system_parameter in &mut awa.system_parameters {
+                    let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+                    let ptr_state2 = ptr_state.clone();
+                    match system_parameter {
+                        SystemParameter::Query((query, component_infos, filters)) => {
+                            let mut items = query
+                                .iter_mut(world)
+                                .filter(|a| {
+                                    filters
+                                        .iter()
+                                        .all(|filter| filter.matches(a, last_run, this_run))
+                                })
+                                .collect::<Vec<_>>();
+                            // archetype order depends on the order entities were spawned and changed in,
+                            // which a restored snapshot doesn't keep
+                            if sandbox.deterministic {
+                                items.sort_by_key(|a| a.id());
+                            }
+                            let items = items
+                                .into_iter()
+                                .map(|mut a| -> Result<_, anyhow::Error> {
+                                    let mut values = vec![];
+                                    //a.components();
+                                    for query_data in component_infos.iter() {
+                                        let component_type = match query_data {
+                                            QueryData::Entity => {
+                                                values.push(QueryItem::Entity(a.id()));
+                                                continue;
+                                            }
+                                            QueryData::Component(component_type) => {
+                                                component_type
+                                            }
+                                        };
+                                        match component_type {
+                                            ComponentType::Ref((component_id, type_id))
+                                            | ComponentType::OptionRef((
+                                                component_id,
+                                                type_id,
+                                            )) => {
+                                                let Some(x) = a.get_by_id(*component_id)
+                                                else {
+                                                    values.push(QueryItem::Missing);
+                                                    continue;
+                                                };
+                                                let app_registry = app_registry.read();
+                                                let reflect_from_ptr =
+                                                    reflect_from_ptr(&app_registry, *type_id)?;
+                                                let value =
+                                                    unsafe { reflect_from_ptr.as_reflect(x) };
+                                                values.push(QueryItem::Component(ReflectPtr::new_ref(
+                                                    value,
+                                                    ptr_state2.clone(),
+                                                    ofr1.clone(),
+                                                )));
+                                            }
+                                            ComponentType::Mut((component_id, type_id))
+                                            | ComponentType::OptionMut((
+                                                component_id,
+                                                type_id,
+                                            )) => {
+                                                let Some(mut x) =
+                                                    a.get_mut_by_id(*component_id)
+                                                else {
+                                                    values.push(QueryItem::Missing);
+                                                    continue;
+                                                };
+                                                let app_registry = app_registry.read();
+                                                let reflect_from_ptr =
+                                                    reflect_from_ptr(&app_registry, *type_id)?;
+                                                let value = unsafe {
+                                                    reflect_from_ptr.as_reflect_mut(x.as_mut())
+                                                };
+                                                values.push(QueryItem::Component(ReflectPtr::new_mut(
+                                                    value,
+                                                    ptr_state2.clone(),
+                                                    ofr1.clone(),
+                                                )));
                                             }
                                         }
-                                        values
-                                    })
-                                    .collect::<Vec<_>>();
-                                let iterator_state = ctx.stash(UserData::new_static(
-                                    &ctx,
-                                    Mutex::new(IteratorState {
-                                        components: items,
-                                        ptr_state: ptr_state.clone(),
-                                    }),
-                                ));
-                                ptr_states.push(ptr_state);
-                                let t = Table::new(&ctx);
-                                t.set(
-                                    ctx,
-                                    "iter",
-                                    Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
-                                        let iterator_state =
-                                            ctx.fetch(&iterator_state).into_value(ctx);
-                                        *iterator_state
-                                            .as_static_user_data::<Mutex<IteratorState>>()
-                                            .unwrap()
-                                            .lock()
-                                            .unwrap()
-                                            .ptr_state
-                                            .borrow_mut() = PtrState::Valid;
-                                        stack.replace(
-                                            ctx,
-                                            (IteratorState::iterator_fn(&ctx), iterator_state),
-                                        );
-
-                                        Ok(CallbackReturn::Return)
-                                    }),
-                                )
-                                .unwrap();
-                                things.push(t.into_value(ctx));
-                            }
-                            SystemParameter::CommandQueue => {
-                                let reflect_mut = ReflectPtr::new_mut(
-                                    &mut command_queue,
-                                    ptr_state2.clone(),
-                                    ofr1.clone(),
-                                );
-                                things.push(reflect_mut.into_value(&ctx));
-                                ptr_states.push(ptr_state);
-                            }
-                            SystemParameter::Resource(resource_component_type) => {
-                                match resource_component_type {
-                                    ComponentType::Ref((component_id, type_id)) => {
-                                        let mut x =
-                                            world.get_resource_by_id(*component_id).unwrap();
-                                        let app_registry = app_registry.read();
-                                        let reflect_data = app_registry.get(*type_id).unwrap();
-                                        let reflect_from_ptr =
-                                            reflect_data.data::<ReflectFromPtr>().unwrap();
-                                        let value = unsafe { reflect_from_ptr.as_reflect(x) };
-                                        things.push(
-                                            ReflectPtr::new_ref(
-                                                value,
-                                                ptr_state2.clone(),
-                                                ofr1.clone(),
-                                            )
-                                            .into_value(&ctx),
-                                        );
-                                    }
-                                    ComponentType::Mut((component_id, type_id)) => {
-                                        let mut x =
-                                            world.get_resource_mut_by_id(*component_id).unwrap();
-                                        let app_registry = app_registry.read();
-                                        let reflect_data = app_registry.get(*type_id).unwrap();
-                                        let reflect_from_ptr =
-                                            reflect_data.data::<ReflectFromPtr>().unwrap();
-                                        let value =
-                                            unsafe { reflect_from_ptr.as_reflect_mut(x.as_mut()) };
-                                        things.push(
-                                            ReflectPtr::new_mut(
-                                                value,
-                                                ptr_state2.clone(),
-                                                ofr1.clone(),
-                                            )
-                                            .into_value(&ctx),
-                                        );
                                     }
+                                    Ok(values)
+                                })
+                                .collect::<Result<Vec<_>, _>>()?;
+                            let t = IteratorState::table(ctx, "iter", items, ptr_state.clone())?;
+                            ptr_states.push(ptr_state);
+                            things.push(t.into_value(ctx));
+                        }
+                        SystemParameter::EventReader((type_id, last_count)) => {
+                            let app_registry = app_registry.read();
+                            let reflect_lua_event = app_registry
+                                .get_type_data::<ReflectLuaEvent>(*type_id)
+                                .ok_or_else(|| anyhow!("{type_id:?} isn't a lua event"))?;
+                            let (events, count) = (reflect_lua_event.read)(world, *last_count);
+                            *last_count = count;
+                            let items = events
+                                .into_iter()
+                                .map(|event| {
+                                    vec![QueryItem::Component(ReflectPtr::new_ref(
+                                        event,
+                                        ptr_state2.clone(),
+                                        ofr1.clone(),
+                                    ))]
+                                })
+                                .collect();
+                            let t = IteratorState::table(ctx, "read", items, ptr_state.clone())?;
+                            ptr_states.push(ptr_state);
+                            things.push(t.into_value(ctx));
+                        }
+                        SystemParameter::EventWriter(type_id) => {
+                            things.push(event_writer(ctx, *type_id)?.into_value(ctx));
+                            ptr_states.push(ptr_state);
+                        }
+                        SystemParameter::CommandQueue => {
+                            let reflect_mut = ReflectPtr::new_mut(
+                                &mut command_queue,
+                                ptr_state2.clone(),
+                                ofr1.clone(),
+                            );
+                            things.push(reflect_mut.into_value(&ctx));
+                            ptr_states.push(ptr_state);
+                        }
+                        SystemParameter::Resource(resource_component_type) => {
+                            match resource_component_type {
+                                ComponentType::Ref((component_id, type_id))
+                                | ComponentType::OptionRef((component_id, type_id)) => {
+                                    let Some(x) = world.get_resource_by_id(*component_id)
+                                    else {
+                                        things.push(Value::Nil);
+                                        continue;
+                                    };
+                                    let app_registry = app_registry.read();
+                                    let reflect_from_ptr =
+                                        reflect_from_ptr(&app_registry, *type_id)?;
+                                    let value = unsafe { reflect_from_ptr.as_reflect(x) };
+                                    things.push(
+                                        ReflectPtr::new_ref(
+                                            value,
+                                            ptr_state2.clone(),
+                                            ofr1.clone(),
+                                        )
+                                        .into_value(&ctx),
+                                    );
+                                }
+                                ComponentType::Mut((component_id, type_id))
+                                | ComponentType::OptionMut((component_id, type_id)) => {
+                                    let Some(mut x) =
+                                        world.get_resource_mut_by_id(*component_id)
+                                    else {
+                                        things.push(Value::Nil);
+                                        continue;
+                                    };
+                                    let app_registry = app_registry.read();
+                                    let reflect_from_ptr =
+                                        reflect_from_ptr(&app_registry, *type_id)?;
+                                    let value =
+                                        unsafe { reflect_from_ptr.as_reflect_mut(x.as_mut()) };
+                                    things.push(
+                                        ReflectPtr::new_mut(
+                                            value,
+                                            ptr_state2.clone(),
+                                            ofr1.clone(),
+                                        )
+                                        .into_value(&ctx),
+                                    );
                                 }
                             }
                         }
                     }
+                }
 
-                    Ok(ctx.stash(Executor::start(ctx, func, Variadic(things))))
-                })
-                .unwrap();
-            if let Err(err) = lua.execute::<()>(&exec) {
-                warn!("error running lua script didn't work: {err}");
+                Ok(ctx.stash(Executor::start(ctx, func, Variadic(things))))
+            })
+            .map_err(|err| anyhow!("{err}"))
+            .and_then(|exec| sandbox::execute::<()>(&mut lua, &exec, &sandbox));
+        let over_budget = matches!(&result, Err(err) if err.is::<BudgetExceeded>());
+        if let Err(err) = result {
+            let mut script_error = ScriptError::new(script.clone(), Some(awa.name.clone()), &err);
+            match (over_budget, sandbox.policy) {
+                (false, _) => {}
+                (true, BudgetPolicy::DisableSystem) => {
+                    script_error.message += ", disabled until the script reloads";
+                }
+                (true, BudgetPolicy::KillScript) => {
+                    script_error.message += ", the script is stopped until it reloads";
+                }
             }
-            for ptr_state in ptr_states.iter() {
-                *ptr_state.borrow_mut() = PtrState::Invalid;
+            script_error.report(world);
+        }
+        let _ = lua.try_enter(|ctx| {
+            ctx.set_global(SystemContext::GLOBAL, Value::Nil);
+            Ok(())
+        });
+        for ptr_state in ptr_states.iter() {
+            *ptr_state.borrow_mut() = PtrState::Invalid;
+        }
+        awa.last_run = this_run;
+        command_queue.commands.apply(world);
+        if over_budget {
+            match sandbox.policy {
+                BudgetPolicy::DisableSystem => lua_script.systems[index].disabled = true,
+                BudgetPolicy::KillScript => {
+                    for system in lua_script.systems.iter_mut() {
+                        system.disabled = true;
+                    }
+                }
             }
-            command_queue.commands.apply(world);
         }
     }
 
@@ -526,49 +1078,256 @@ pub fn run_every_tick(world: &mut World) {
 pub struct CommandQueueWrapper {
     #[reflect(ignore)]
     pub commands: CommandQueue,
+    /// Used to reserve entities and look up types while the system runs
+    #[reflect(ignore)]
+    world: SendWrapper<*const World>,
 }
 
 impl CommandQueueWrapper {
-    pub fn spawn(&mut self, table: TableReflectWrapper) {
+    pub fn new(world: &World) -> Self {
+        Self {
+            commands: Default::default(),
+            world: SendWrapper::new(world as *const World),
+        }
+    }
+
+    fn world(&self) -> &World {
+        unsafe { &**self.world }
+    }
+
+    /// Spawns an entity from a table of components, returning it so later commands can target it
+    /// A `children` entry can hold a list of tables to spawn as children
+    pub fn spawn(&mut self, table: TableReflectWrapper) -> Result<Entity, String> {
+        let table = unsafe { table.take() };
+        self.spawn_from_table(table, None)
+            .map_err(|err| format!("couldn't spawn entity: {err}"))
+    }
+
+    pub fn spawn_child(
+        &mut self,
+        parent: Entity,
+        table: TableReflectWrapper,
+    ) -> Result<Entity, String> {
+        let table = unsafe { table.take() };
+        self.spawn_from_table(table, Some(parent))
+            .map_err(|err| format!("couldn't spawn child of {parent}: {err}"))
+    }
+
+    /// Inserts a table of components into an existing entity
+    pub fn insert(&mut self, entity: Entity, table: TableReflectWrapper) -> Result<(), String> {
+        let table = unsafe { table.take() };
+        let app_registry = self.world().resource::<AppTypeRegistry>().clone();
+        let components = components_from_table(table, &app_registry.read())
+            .and_then(|components| {
+                check_type_data::<ReflectComponent>(&components, &app_registry.read(), "component")?;
+                Ok(components)
+            })
+            .map_err(|err| format!("couldn't insert components into {entity}: {err}"))?;
         self.push(move |world: &mut World| {
-            let table = table.table.unwrap().take();
-            for (_key, value) in table {
-                let Ok(value) = value.as_static_user_data::<ReflectPtr>() else {
-                    warn!("passed non reflect to spawn function");
+            insert_reflect_components(world, entity, components);
+        });
+        Ok(())
+    }
+
+    /// Inserts resources from a `{ResourceName = {field = value}}` table
+    pub fn insert_resource(&mut self, table: TableReflectWrapper) -> Result<(), String> {
+        let table = unsafe { table.take() };
+        let app_registry = self.world().resource::<AppTypeRegistry>().clone();
+        let resources = components_from_table(table, &app_registry.read())
+            .and_then(|resources| {
+                check_type_data::<ReflectResource>(&resources, &app_registry.read(), "resource")?;
+                Ok(resources)
+            })
+            .map_err(|err| format!("couldn't insert resources: {err}"))?;
+        self.push(move |world: &mut World| {
+            let app_registry = world.resource::<AppTypeRegistry>().clone();
+            let app_registry = app_registry.read();
+            for resource in resources {
+                let Some(reflect_resource) = resource
+                    .get_represented_type_info()
+                    .and_then(|info| app_registry.get_type_data::<ReflectResource>(info.type_id()))
+                else {
                     continue;
                 };
-                let type_id = unsafe { &*value.get_data() }
-                    .get_represented_type_info()
-                    .unwrap()
-                    .type_id();
-                match &value.data {
-                    ReflectType::Boxed(boxed) => {
-                        let thing_to_add = boxed.borrow_mut().take().unwrap();
-                        let component_id: ComponentId = world.components().get_id(type_id).unwrap();
-                        let mut e: EntityWorldMut = world.spawn_empty();
-                        let data_ptr = Box::into_raw(thing_to_add) as *mut u8;
-                        unsafe {
-                            e.insert_by_id(
-                                component_id,
-                                OwningPtr::new(NonNull::new(data_ptr).unwrap()),
-                            )
+                reflect_resource.insert(world, resource.as_partial_reflect(), &app_registry);
+            }
+        });
+        Ok(())
+    }
+
+    /// Removes a resource by its short or full type path
+    pub fn remove_resource(&mut self, name: String) -> Result<(), String> {
+        let app_registry = self.world().resource::<AppTypeRegistry>().clone();
+        let Some(type_id) = registration_by_name(&app_registry.read(), &name)
+            .filter(|registration| registration.data::<ReflectResource>().is_some())
+            .map(|registration| registration.type_id())
+        else {
+            return Err(format!("{name} is not a reflected resource"));
+        };
+        self.push(move |world: &mut World| {
+            let app_registry = world.resource::<AppTypeRegistry>().clone();
+            let app_registry = app_registry.read();
+            if let Some(reflect_resource) = app_registry.get_type_data::<ReflectResource>(type_id) {
+                reflect_resource.remove(world);
+            }
+        });
+        Ok(())
+    }
+
+    fn spawn_from_table(
+        &mut self,
+        table: Table,
+        parent: Option<Entity>,
+    ) -> Result<Entity, anyhow::Error> {
+        let app_registry = self.world().resource::<AppTypeRegistry>().clone();
+        // convert the whole hierarchy first, so nothing is spawned if part of it is invalid
+        let spawn = SpawnTable::new(table, &app_registry.read())?;
+        Ok(self.queue_spawn(spawn, parent))
+    }
+
+    fn queue_spawn(&mut self, spawn: SpawnTable, parent: Option<Entity>) -> Entity {
+        let entity = self.world().entities().reserve_entity();
+        let components = spawn.components;
+        self.push(move |world: &mut World| {
+            insert_reflect_components(world, entity, components);
+            if let Some(Ok(mut parent)) = parent.map(|parent| world.get_entity_mut(parent)) {
+                parent.add_child(entity);
+            }
+        });
+        // children are spawned separately, after their parent
+        for child in spawn.children {
+            self.queue_spawn(child, Some(entity));
+        }
+        entity
+    }
+}
+
+/// The components of an entity and its `children`, converted from a lua table
+struct SpawnTable {
+    components: Vec<Box<dyn PartialReflect>>,
+    children: Vec<SpawnTable>,
+}
+
+impl SpawnTable {
+    fn new(table: Table, registry: &TypeRegistry) -> Result<Self, anyhow::Error> {
+        let mut children = vec![];
+        let mut component_entries = vec![];
+        for (key, value) in table {
+            match (key, value) {
+                (Value::String(name), Value::Table(child_tables))
+                    if name.as_bytes() == b"children" =>
+                {
+                    for (_, child) in child_tables {
+                        let Value::Table(child) = child else {
+                            return Err(anyhow!("children have to be tables of components"));
                         };
-                    }
-                    _ => {
-                        error!("non boxed component in spawn function");
-                        continue;
+                        children.push(Self::new(child, registry)?);
                     }
                 }
+                entry => component_entries.push(entry),
             }
-        });
+        }
+
+        let components = components_from_entries(component_entries, registry)?;
+        check_type_data::<ReflectComponent>(&components, registry, "component")?;
+        Ok(Self { components, children })
+    }
+}
+
+/// Makes sure values built by a script can be inserted, so mistakes are reported to the script
+fn check_type_data<T: TypeData>(
+    values: &[Box<dyn PartialReflect>],
+    registry: &TypeRegistry,
+    kind: &str,
+) -> Result<(), anyhow::Error> {
+    for value in values {
+        let is_reflected = value
+            .get_represented_type_info()
+            .is_some_and(|info| registry.get_type_data::<T>(info.type_id()).is_some());
+        if !is_reflected {
+            return Err(anyhow!(
+                "{} is not a reflected {kind}",
+                value.reflect_type_path()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn insert_reflect_components(world: &mut World, entity: Entity, components: Vec<Box<dyn PartialReflect>>) {
+    let app_registry = world.resource::<AppTypeRegistry>().clone();
+    let app_registry = app_registry.read();
+    let Ok(mut entity) = world.get_entity_mut(entity) else {
+        warn!("tried to insert components into a despawned entity");
+        return;
+    };
+    for component in components {
+        let Some(reflect_component) = component
+            .get_represented_type_info()
+            .and_then(|info| app_registry.get_type_data::<ReflectComponent>(info.type_id()))
+        else {
+            warn!("{} is not a reflected component", component.reflect_type_path());
+            continue;
+        };
+        reflect_component.insert(&mut entity, component.as_partial_reflect(), &app_registry);
     }
 }
+
 #[derive(Deref, DerefMut)]
 pub struct LuaVm {
+    #[deref]
     lua: Lua,
+    /// Hash of the source and dependencies of the modules that ran, so they only run again when either changes
+    pub(crate) module_versions: HashMap<AssetPath<'static>, u64>,
+    /// Number of scripts loaded so far, each load gets the next one as `LuaScript::version`
+    pub(crate) loaded_scripts: u64,
+    /// Set once `LuaSandbox` was applied, before any script runs
+    pub(crate) sandboxed: bool,
 }
 impl Default for LuaVm {
     fn default() -> Self {
-        Self { lua: Lua::full() }
+        // the rest of the stdlib is loaded in `insert_lua_vm`, depending on `LuaSandbox`
+        Self {
+            lua: Lua::core(),
+            module_versions: HashMap::new(),
+            loaded_scripts: 0,
+            sandboxed: false,
+        }
     }
 }
+
+/// Checks the `run_if` conditions of a system, calling the lua functions among them
+fn check_run_conditions(
+    world: &World,
+    lua: &mut LuaVm,
+    system: &LuaSystem,
+    sandbox: &LuaSandbox,
+) -> Result<bool, anyhow::Error> {
+    for condition in &system.options.run_if {
+        let passed = match condition {
+            RunCondition::Function(function) => {
+                let exec = lua
+                    .try_enter(|ctx| {
+                        Ok(ctx.stash(Executor::start(ctx, ctx.fetch(function), ())))
+                    })
+                    .map_err(|err| anyhow!("{err}"))?;
+                sandbox::execute::<bool>(lua, &exec, sandbox)?
+            }
+            condition => condition.check_world(world),
+        };
+        if !passed {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Looks up how to reflect a component or resource from a pointer
+fn reflect_from_ptr(
+    registry: &TypeRegistry,
+    type_id: TypeId,
+) -> Result<&ReflectFromPtr, anyhow::Error> {
+    registry
+        .get_type_data::<ReflectFromPtr>(type_id)
+        .ok_or_else(|| anyhow!("{type_id:?} can't be reflected from a pointer"))
+}
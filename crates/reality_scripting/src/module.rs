@@ -0,0 +1,255 @@
+// Modules scripts can `require`, loaded through the asset server ahead of time
+
+use crate::sandbox::{self, LuaSandbox};
+use crate::LuaVm;
+use anyhow::anyhow;
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, AssetPath, LoadContext};
+use bevy::prelude::*;
+use piccolo::{Callback, CallbackReturn, Closure, Context, Executor, Table, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+
+/// Global table of the values modules returned, by asset path
+const MODULES_GLOBAL: &str = "__modules";
+
+/// Source of a lua file that's `require`d by a script
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct LuaModule {
+    pub path: AssetPath<'static>,
+    pub source: Vec<u8>,
+    /// What the module's `require` calls resolve to
+    pub requires: HashMap<String, AssetPath<'static>>,
+}
+
+#[derive(Default)]
+pub struct LuaModuleLoader;
+
+impl AssetLoader for LuaModuleLoader {
+    type Asset = LuaModule;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &Self::Settings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut source = vec![];
+        reader.read_to_end(&mut source).await?;
+        let path = load_context.asset_path().clone();
+        let requires = resolve_requires(&path, &source)?;
+        Ok(LuaModule {
+            path,
+            source,
+            requires,
+        })
+    }
+}
+
+/// Finds the `require "name"` calls in a script, resolving the names relative to its path.
+/// `.lua` is appended to names without an extension
+pub fn resolve_requires(
+    path: &AssetPath<'static>,
+    source: &[u8],
+) -> Result<HashMap<String, AssetPath<'static>>, anyhow::Error> {
+    let mut requires = HashMap::new();
+    for name in required_names(&String::from_utf8_lossy(source)) {
+        let file = if name.rsplit('/').next().is_some_and(|file| file.contains('.')) {
+            name.clone()
+        } else {
+            format!("{name}.lua")
+        };
+        let module_path = path
+            .resolve_embed(&file)
+            .map_err(|err| anyhow!("can't require {name}: {err}"))?;
+        requires.insert(name, module_path);
+    }
+    Ok(requires)
+}
+
+/// Names passed as string literals to `require`, e.g. `require("util")` or `require "util"`
+fn required_names(source: &str) -> Vec<String> {
+    let mut names = vec![];
+    for line in source.lines() {
+        // skip comments
+        let line = line.split("--").next().unwrap_or_default();
+        let mut rest = line;
+        while let Some(start) = rest.find("require") {
+            let is_call = !rest[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == ':');
+            rest = &rest[start + "require".len()..];
+            if !is_call {
+                continue;
+            }
+            let argument = rest.trim_start();
+            let argument = argument.strip_prefix('(').unwrap_or(argument).trim_start();
+            let Some(quote) = argument.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                continue;
+            };
+            if let Some(end) = argument[1..].find(quote) {
+                names.push(argument[1..end + 1].to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Loads the required modules and the modules they require, dependencies before the modules requiring them.
+/// Loading them immediately makes the asset server reload the script when one of them changes
+pub async fn load_modules(
+    load_context: &mut LoadContext<'_>,
+    requires: &HashMap<String, AssetPath<'static>>,
+) -> Result<Vec<LuaModule>, anyhow::Error> {
+    let mut loaded: HashMap<AssetPath<'static>, LuaModule> = HashMap::new();
+    let mut pending = requires.values().cloned().collect::<Vec<_>>();
+    while let Some(path) = pending.pop() {
+        if loaded.contains_key(&path) {
+            continue;
+        }
+        let module = load_context
+            .loader()
+            .immediate()
+            .load::<LuaModule>(path.clone())
+            .await?
+            .take();
+        pending.extend(module.requires.values().cloned());
+        loaded.insert(path, module);
+    }
+
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    for path in requires.values() {
+        dependencies_first(path, &loaded, &mut visited, &mut order);
+    }
+    Ok(order
+        .into_iter()
+        .filter_map(|path| loaded.remove(&path))
+        .collect())
+}
+
+fn dependencies_first(
+    path: &AssetPath<'static>,
+    loaded: &HashMap<AssetPath<'static>, LuaModule>,
+    visited: &mut HashSet<AssetPath<'static>>,
+    order: &mut Vec<AssetPath<'static>>,
+) {
+    if !visited.insert(path.clone()) {
+        return;
+    }
+    if let Some(module) = loaded.get(path) {
+        for dependency in module.requires.values() {
+            dependencies_first(dependency, loaded, visited, order);
+        }
+    }
+    order.push(path.clone());
+}
+
+/// Runs a module, unless it already ran with the same source and dependencies, and keeps what it returned for `require`.
+/// Modules have to run after their dependencies, like `load_modules` orders them
+pub fn run_module(
+    lua: &mut LuaVm,
+    module: &LuaModule,
+    sandbox: &LuaSandbox,
+) -> Result<(), anyhow::Error> {
+    let version = module_version(lua, module);
+    if lua.module_versions.get(&module.path) == Some(&version) {
+        return Ok(());
+    }
+    let path = module.path.to_string();
+    let exec = lua
+        .try_enter(|ctx| {
+            let closure = Closure::load_with_env(
+                ctx,
+                Some(&*path),
+                Cursor::new(module.source.clone()),
+                script_env(ctx, &module.requires)?,
+            )?;
+            let name = piccolo::String::from_slice(&ctx, path.as_bytes());
+            Ok(ctx.stash(Executor::start(ctx, closure.into(), name)))
+        })
+        .map_err(|err| anyhow!("{err}"))?;
+    sandbox::finish(lua, &exec, sandbox)?;
+    lua.try_enter(|ctx| {
+        let value = ctx.fetch(&exec).take_result::<Value>(ctx)??;
+        // like lua's require, modules that don't return anything are `true`
+        let value = match value {
+            Value::Nil => Value::Boolean(true),
+            value => value,
+        };
+        modules_table(ctx)?.set(ctx, path.clone(), value)?;
+        Ok(())
+    })
+    .map_err(|err| anyhow!("{err}"))?;
+    lua.module_versions.insert(module.path.clone(), version);
+    Ok(())
+}
+
+/// Changes with the module's source and the versions of the modules it requires, so a module
+/// runs again when a dependency did, instead of keeping values it captured from the old one
+fn module_version(lua: &LuaVm, module: &LuaModule) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    module.source.hash(&mut hasher);
+    let mut dependencies = module
+        .requires
+        .values()
+        .map(|path| (path.to_string(), lua.module_versions.get(path)))
+        .collect::<Vec<_>>();
+    dependencies.sort();
+    dependencies.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn modules_table<'gc>(ctx: Context<'gc>) -> Result<Table<'gc>, anyhow::Error> {
+    match ctx.globals().get::<_, Value>(ctx, MODULES_GLOBAL)? {
+        Value::Table(modules) => Ok(modules),
+        _ => {
+            let modules = Table::new(&ctx);
+            ctx.globals().set(ctx, MODULES_GLOBAL, modules)?;
+            Ok(modules)
+        }
+    }
+}
+
+/// Globals of a script or module, falling back to the shared ones. `_G` is still shared between scripts.
+/// `require` returns the modules loaded for it
+pub fn script_env<'gc>(
+    ctx: Context<'gc>,
+    requires: &HashMap<String, AssetPath<'static>>,
+) -> Result<Table<'gc>, anyhow::Error> {
+    let env = Table::new(&ctx);
+    let metatable = Table::new(&ctx);
+    metatable.set(ctx, "__index", ctx.globals())?;
+    env.set_metatable(&ctx, Some(metatable));
+
+    let requires = requires
+        .iter()
+        .map(|(name, path)| (name.clone(), path.to_string()))
+        .collect::<HashMap<_, _>>();
+    env.set(
+        ctx,
+        "require",
+        Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let name: piccolo::String = stack.consume(ctx)?;
+            let name = name.to_str()?;
+            let path = requires.get(name).ok_or_else(|| {
+                anyhow!("can't require {name}, modules have to be required with a string literal")
+            })?;
+            let module = modules_table(ctx)?.get::<_, Value>(ctx, path.clone())?;
+            if let Value::Nil = module {
+                return Err(anyhow!(
+                    "{path} isn't loaded, modules can't require each other in a cycle"
+                )
+                .into());
+            }
+            stack.replace(ctx, module);
+            Ok(CallbackReturn::Return)
+        }),
+    )?;
+    Ok(env)
+}
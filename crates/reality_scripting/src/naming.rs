@@ -0,0 +1,228 @@
+// Short names for reflected types, next to their full type paths
+
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy::reflect::{TypeInfo, TypeRegistration, TypeRegistry};
+use piccolo::{Context, Table, Value};
+use std::any::TypeId;
+use std::collections::HashMap;
+
+/// Gives a type a name of its own in lua, e.g. `#[reflect(@LuaName("Player"))]`.
+/// Dots make nested names, like `"Game.Player"`
+#[derive(Reflect, Clone, Debug)]
+pub struct LuaName(pub &'static str);
+
+/// How reflected types are named in lua, insert it to change the defaults.
+/// Types can always be reached through their full type path, e.g. `bevy_transform.components.transform.Transform`
+#[derive(Resource, Clone, Debug)]
+pub struct LuaNaming {
+    /// Also expose types under their short name, e.g. `Transform`, or `Time.Virtual` for `Time<Virtual>`.
+    /// Short names shared by several types are left out
+    pub short_names: bool,
+    /// Names for types that can't have a `LuaName` attribute, like those from other crates
+    pub names: HashMap<TypeId, String>,
+}
+
+impl Default for LuaNaming {
+    fn default() -> Self {
+        Self {
+            short_names: true,
+            names: HashMap::new(),
+        }
+    }
+}
+
+impl LuaNaming {
+    pub fn with_name<T: 'static>(mut self, name: impl Into<String>) -> Self {
+        self.names.insert(TypeId::of::<T>(), name.into());
+        self
+    }
+}
+
+/// Splits a type path on the `::` outside of generic arguments,
+/// e.g. `bevy_state::state::State<game::GameState>` into `bevy_state`, `state` and `State<game::GameState>`
+pub fn type_path_segments(type_path: &str) -> Vec<&str> {
+    let mut segments = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let bytes = type_path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'<' | b'(' | b'[' => depth += 1,
+            b'>' | b')' | b']' => depth -= 1,
+            b':' if depth == 0 && bytes.get(i + 1) == Some(&b':') => {
+                segments.push(&type_path[start..i]);
+                start = i + 2;
+                i += 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    segments.push(&type_path[start..]);
+    segments
+}
+
+/// The names of the given types besides their type paths, as table path segments.
+/// Explicit names take precedence over short names, and names shared by several types are left out
+pub fn aliases(
+    registry: &TypeRegistry,
+    naming: &LuaNaming,
+    type_ids: impl IntoIterator<Item = TypeId>,
+) -> HashMap<TypeId, Vec<String>> {
+    let mut explicit: HashMap<Vec<String>, TypeId> = HashMap::new();
+    let mut short: HashMap<Vec<String>, Vec<TypeId>> = HashMap::new();
+    for type_id in type_ids {
+        let Some(registration) = registry.get(type_id) else {
+            continue;
+        };
+        if let Some(name) = explicit_name(registration, naming) {
+            let segments = name.split('.').map(str::to_string).collect::<Vec<_>>();
+            if let Some(other) = explicit.insert(segments, type_id) {
+                warn!(
+                    "{} and {} are both named {name} in lua, keeping the first",
+                    type_path(registry, other),
+                    registration.type_info().type_path()
+                );
+                explicit.insert(name.split('.').map(str::to_string).collect(), other);
+            }
+        } else if naming.short_names {
+            if let Some(segments) = short_name(registration.type_info()) {
+                short.entry(segments).or_default().push(type_id);
+            }
+        }
+    }
+    let mut aliases = explicit
+        .into_iter()
+        .map(|(segments, type_id)| (type_id, segments))
+        .collect::<HashMap<_, _>>();
+    let taken = aliases.values().cloned().collect::<Vec<_>>();
+    for (segments, type_ids) in short {
+        if let [type_id] = type_ids[..] {
+            if !taken.contains(&segments) {
+                aliases.insert(type_id, segments);
+                continue;
+            }
+        }
+        let type_paths = type_ids
+            .iter()
+            .map(|type_id| type_path(registry, *type_id))
+            .collect::<Vec<_>>();
+        debug!(
+            "{} isn't exposed to lua, it's shared by {}",
+            segments.join("."),
+            type_paths.join(", ")
+        );
+    }
+    aliases
+}
+
+fn type_path(registry: &TypeRegistry, type_id: TypeId) -> &'static str {
+    registry
+        .get(type_id)
+        .map_or("<unregistered>", |registration| registration.type_info().type_path())
+}
+
+fn explicit_name(registration: &TypeRegistration, naming: &LuaNaming) -> Option<String> {
+    if let Some(name) = naming.names.get(&registration.type_id()) {
+        return Some(name.clone());
+    }
+    let lua_name = match registration.type_info() {
+        TypeInfo::Struct(info) => info.get_attribute::<LuaName>(),
+        TypeInfo::TupleStruct(info) => info.get_attribute::<LuaName>(),
+        TypeInfo::Enum(info) => info.get_attribute::<LuaName>(),
+        _ => None,
+    };
+    lua_name.map(|lua_name| lua_name.0.to_string())
+}
+
+/// `Transform` for `Transform`, `Time.Virtual` for `Time<Virtual>` and `Time` for `Time<()>`
+fn short_name(type_info: &TypeInfo) -> Option<Vec<String>> {
+    generic_segments(type_info.type_path_table().short_path())
+}
+
+fn generic_segments(short_path: &str) -> Option<Vec<String>> {
+    let short_path = short_path.trim();
+    let Some(open) = short_path.find('<') else {
+        let is_name = !short_path.is_empty()
+            && short_path.chars().all(|c| c.is_alphanumeric() || c == '_')
+            && !short_path.starts_with(|c: char| c.is_ascii_digit());
+        return is_name.then(|| vec![short_path.to_string()]);
+    };
+    let inner = short_path[open + 1..].strip_suffix('>')?;
+    let mut segments = generic_segments(&short_path[..open])?;
+    let mut depth = 0;
+    let mut start = 0;
+    let mut args = vec![];
+    for (i, c) in inner.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(&inner[start..]);
+    for arg in args {
+        // `Time<()>` is just `Time`
+        if arg.trim() == "()" {
+            continue;
+        }
+        segments.extend(generic_segments(arg)?);
+    }
+    Some(segments)
+}
+
+/// The table at the type path, if the type is exposed to lua
+pub fn existing_table<'gc>(ctx: Context<'gc>, type_path: &str) -> Option<Table<'gc>> {
+    let mut table = ctx.globals();
+    for segment in type_path_segments(type_path) {
+        table = match table.get::<_, Value>(ctx, segment).ok()? {
+            Value::Table(next) => next,
+            _ => return None,
+        };
+    }
+    Some(table)
+}
+
+/// Makes the type's table reachable through the alias too. Tables created as parents of other aliases,
+/// like `Time` for `Time.Virtual`, are merged into it
+pub fn set_alias<'gc>(
+    ctx: Context<'gc>,
+    table: Table<'gc>,
+    alias: &[String],
+) -> Result<(), anyhow::Error> {
+    let Some((last, parents)) = alias.split_last() else {
+        return Ok(());
+    };
+    let mut parent = ctx.globals();
+    for segment in parents {
+        parent = match parent.get::<_, Value>(ctx, segment.as_str())? {
+            Value::Nil => {
+                let next = Table::new(&ctx);
+                parent.set(ctx, segment.as_str(), next)?;
+                next
+            }
+            Value::Table(next) => next,
+            other => return Err(anyhow!("{segment} is already a {}", other.type_name())),
+        };
+    }
+    match parent.get::<_, Value>(ctx, last.as_str())? {
+        Value::Nil => {}
+        Value::Table(existing) if existing == table => return Ok(()),
+        Value::Table(existing) => {
+            for (key, value) in existing {
+                if let Value::Nil = table.get::<_, Value>(ctx, key)? {
+                    table.set(ctx, key, value)?;
+                }
+            }
+        }
+        other => return Err(anyhow!("{last} is already a {}", other.type_name())),
+    }
+    parent.set(ctx, last.as_str(), table)?;
+    Ok(())
+}
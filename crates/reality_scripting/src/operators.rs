@@ -0,0 +1,324 @@
+// Lua operators for reflected values
+// `a + b` calls the `__add` function registered for the type of `a` (or `b`), see `MathOperatorsPlugin`
+
+use crate::convert::{reflect_from_lua, type_registry};
+use crate::owned_to_lua;
+use crate::reflect::ReflectPtr;
+use crate::userdata::ValueExt;
+use crate::AppExtensionFunctionRegisterTrait;
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy::reflect::func::args::Ownership;
+use bevy::reflect::func::{ArgList, Return, SignatureInfo};
+use bevy::reflect::{ReflectFromReflect, ReflectRef, TypeRegistry};
+use piccolo::{Callback, CallbackReturn, Context, Table, Value, Variadic};
+
+/// Metamethods dispatched to the registered function of the same name
+const OPERATORS: [&str; 6] = ["__add", "__sub", "__mul", "__div", "__lt", "__le"];
+
+pub fn add_operator_metamethods<'gc>(ctx: &Context<'gc>, metatable: &mut Table<'gc>) {
+    for name in OPERATORS {
+        metatable
+            .set(
+                *ctx,
+                name,
+                Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
+                    let (lhs, rhs): (Value, Value) = stack.consume(ctx)?;
+                    let result = call_operator(ctx, name, &[lhs, rhs])?
+                        .ok_or_else(|| no_operator(name, &[lhs, rhs]))?;
+                    stack.replace(ctx, result);
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+    }
+    metatable
+        .set(
+            *ctx,
+            "__unm",
+            Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                // unary metamethods get their operand twice
+                let operands: Variadic<Vec<Value>> = stack.consume(ctx)?;
+                let operand = operands.into_iter().next().unwrap_or(Value::Nil);
+                let result = call_operator(ctx, "__unm", &[operand])?
+                    .ok_or_else(|| no_operator("__unm", &[operand]))?;
+                stack.replace(ctx, result);
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+    metatable
+        .set(
+            *ctx,
+            "__len",
+            Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                // unary metamethods get their operand twice
+                let operands: Variadic<Vec<Value>> = stack.consume(ctx)?;
+                let operand = operands.into_iter().next().unwrap_or(Value::Nil);
+                let result = match call_operator(ctx, "__len", &[operand])? {
+                    Some(result) => result,
+                    None => collection_len(operand)?,
+                };
+                stack.replace(ctx, result);
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+    metatable
+        .set(
+            *ctx,
+            "__eq",
+            Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                let (lhs, rhs): (Value, Value) = stack.consume(ctx)?;
+                let result = match call_operator(ctx, "__eq", &[lhs, rhs])? {
+                    Some(result) => result,
+                    None => Value::Boolean(reflect_eq(lhs, rhs)?),
+                };
+                stack.replace(ctx, result);
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+}
+
+/// Calls the operator registered for the first reflected operand's type that accepts all operands,
+/// or returns `None` if there isn't one
+fn call_operator<'gc>(
+    ctx: Context<'gc>,
+    name: &str,
+    operands: &[Value<'gc>],
+) -> Result<Option<Value<'gc>>, anyhow::Error> {
+    let registry = type_registry(ctx)?.read();
+    for operand in operands {
+        let Ok(reflect_ptr) = operand.as_static_user_data::<ReflectPtr>() else {
+            continue;
+        };
+        let Some(function) = reflect_ptr.object_function(name)? else {
+            continue;
+        };
+        for signature in function.info().signatures() {
+            let Some(args) = operator_args(signature, operands, &registry)? else {
+                continue;
+            };
+            let ret = function
+                .call(args)
+                .map_err(|err| anyhow!("{name} failed: {err}"))?;
+            return match ret {
+                Return::Owned(owned) => {
+                    owned_to_lua(ctx, owned, reflect_ptr.function_registry.clone()).map(Some)
+                }
+                _ => Err(anyhow!("{name} has to return an owned value")),
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Converts the operands to the argument types of `signature`, if they fit
+fn operator_args<'a>(
+    signature: &SignatureInfo,
+    operands: &[Value<'a>],
+    registry: &TypeRegistry,
+) -> Result<Option<ArgList<'a>>, anyhow::Error> {
+    if signature.arg_count() != operands.len() {
+        return Ok(None);
+    }
+    let mut args = ArgList::new();
+    for (arg_info, operand) in signature.args().iter().zip(operands) {
+        if let Ok(reflect_ptr) = operand.as_static_user_data::<ReflectPtr>() {
+            let value = reflect_ptr.get_field_value_ref()?;
+            if value.reflect_type_info().type_id() != arg_info.type_id() {
+                return Ok(None);
+            }
+            match arg_info.ownership() {
+                Ownership::Ref => args = args.push_ref(value.as_partial_reflect()),
+                Ownership::Owned => {
+                    let Some(owned) = registry
+                        .get_type_data::<ReflectFromReflect>(arg_info.type_id())
+                        .and_then(|from_reflect| from_reflect.from_reflect(value.as_partial_reflect()))
+                    else {
+                        return Ok(None);
+                    };
+                    args = args.push_boxed(owned.into_partial_reflect());
+                }
+                Ownership::Mut => return Ok(None),
+            }
+            continue;
+        }
+        // plain lua values, e.g. the number in `v * 2`
+        let Some(registration) = registry.get(arg_info.type_id()) else {
+            return Ok(None);
+        };
+        let Ok(owned) = reflect_from_lua(*operand, registration.type_info(), registry) else {
+            return Ok(None);
+        };
+        match arg_info.ownership() {
+            Ownership::Owned => args = args.push_boxed(owned),
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(args))
+}
+
+fn no_operator(name: &str, operands: &[Value]) -> anyhow::Error {
+    let types = operands
+        .iter()
+        .map(|operand| match operand.as_static_user_data::<ReflectPtr>() {
+            Ok(reflect_ptr) => reflect_ptr
+                .get_field_value_ref()
+                .map(|value| value.reflect_type_path().to_string())
+                .unwrap_or_else(|_| "invalid value".to_string()),
+            Err(_) => operand.type_name().to_string(),
+        })
+        .collect::<Vec<_>>();
+    anyhow!("no {name} registered for ({})", types.join(", "))
+}
+
+fn collection_len(operand: Value) -> Result<Value, anyhow::Error> {
+    let reflect_ptr = operand.as_static_user_data::<ReflectPtr>()?;
+    let value = reflect_ptr.get_field_value_ref()?;
+    let len = match value.reflect_ref() {
+        ReflectRef::List(list) => list.len(),
+        ReflectRef::Array(array) => array.len(),
+        ReflectRef::Map(map) => map.len(),
+        ReflectRef::Set(set) => set.len(),
+        _ => return Err(no_operator("__len", &[operand])),
+    };
+    Ok(Value::Integer(len as i64))
+}
+
+/// Falls back to `ReflectPartialEq`, which compares field by field for types without `PartialEq`
+fn reflect_eq(lhs: Value, rhs: Value) -> Result<bool, anyhow::Error> {
+    let (Ok(lhs), Ok(rhs)) = (
+        lhs.as_static_user_data::<ReflectPtr>(),
+        rhs.as_static_user_data::<ReflectPtr>(),
+    ) else {
+        return Ok(false);
+    };
+    let lhs = lhs.get_field_value_ref()?;
+    let rhs = rhs.get_field_value_ref()?;
+    Ok(lhs
+        .reflect_partial_eq(rhs.as_partial_reflect())
+        .unwrap_or(false))
+}
+
+/// Registers operators for bevy's math and color types
+pub struct MathOperatorsPlugin;
+
+macro_rules! register_vector_operators {
+    ($app:ident, $($ty:ty),*) => {
+        $(
+            $app.register_object_function::<$ty>(
+                (|a: $ty, b: $ty| a + b).into_function().with_name("__add"),
+            );
+            $app.register_object_function::<$ty>(
+                (|a: $ty, b: $ty| a - b).into_function().with_name("__sub"),
+            );
+            $app.register_object_function::<$ty>(
+                (|a: $ty, b: $ty| a * b)
+                    .into_function()
+                    .with_overload(|a: $ty, b: f32| a * b)
+                    .with_overload(|a: f32, b: $ty| a * b)
+                    .with_name("__mul"),
+            );
+            $app.register_object_function::<$ty>(
+                (|a: $ty, b: $ty| a / b)
+                    .into_function()
+                    .with_overload(|a: $ty, b: f32| a / b)
+                    .with_name("__div"),
+            );
+            $app.register_object_function::<$ty>(
+                (|a: $ty| -a).into_function().with_name("__unm"),
+            );
+            $app.register_object_function::<$ty>(
+                (|a: $ty| a.length()).into_function().with_name("__len"),
+            );
+        )*
+    };
+}
+
+impl Plugin for MathOperatorsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<Vec2>()
+            .register_type::<Vec3>()
+            .register_type::<Vec4>()
+            .register_type::<Quat>()
+            .register_type::<Color>();
+
+        // constructors, e.g. `glam.Vec3.new(0, 1, 0)`
+        app.register_non_self_object_function::<Vec2>(Vec2::new.into_function().with_name("new"));
+        app.register_non_self_object_function::<Vec3>(Vec3::new.into_function().with_name("new"));
+        app.register_non_self_object_function::<Vec4>(Vec4::new.into_function().with_name("new"));
+        app.register_non_self_object_function::<Quat>(
+            Quat::from_xyzw.into_function().with_name("from_xyzw"),
+        );
+        app.register_non_self_object_function::<Quat>(
+            Quat::from_rotation_x
+                .into_function()
+                .with_name("from_rotation_x"),
+        );
+        app.register_non_self_object_function::<Quat>(
+            Quat::from_rotation_y
+                .into_function()
+                .with_name("from_rotation_y"),
+        );
+        app.register_non_self_object_function::<Quat>(
+            Quat::from_rotation_z
+                .into_function()
+                .with_name("from_rotation_z"),
+        );
+        app.register_non_self_object_function::<Color>(
+            Color::srgb.into_function().with_name("srgb"),
+        );
+        app.register_non_self_object_function::<Color>(
+            Color::srgba.into_function().with_name("srgba"),
+        );
+
+        register_vector_operators!(app, Vec2, Vec3, Vec4);
+
+        app.register_object_function::<Quat>(
+            (|a: Quat, b: Quat| a + b).into_function().with_name("__add"),
+        );
+        app.register_object_function::<Quat>(
+            (|a: Quat, b: Quat| a - b).into_function().with_name("__sub"),
+        );
+        // rotations compose, and rotate vectors
+        app.register_object_function::<Quat>(
+            (|a: Quat, b: Quat| a * b)
+                .into_function()
+                .with_overload(|a: Quat, b: Vec3| a * b)
+                .with_overload(|a: Quat, b: f32| a * b)
+                .with_name("__mul"),
+        );
+        app.register_object_function::<Quat>(
+            (|a: Quat, b: f32| a / b).into_function().with_name("__div"),
+        );
+        app.register_object_function::<Quat>((|a: Quat| -a).into_function().with_name("__unm"));
+        app.register_object_function::<Quat>(
+            (|a: Quat| a.length()).into_function().with_name("__len"),
+        );
+
+        // colors are mixed in linear space
+        app.register_object_function::<Color>(
+            (|a: Color, b: Color| Color::from(a.to_linear() + b.to_linear()))
+                .into_function()
+                .with_name("__add"),
+        );
+        app.register_object_function::<Color>(
+            (|a: Color, b: Color| Color::from(a.to_linear() - b.to_linear()))
+                .into_function()
+                .with_name("__sub"),
+        );
+        app.register_object_function::<Color>(
+            (|a: Color, b: f32| Color::from(a.to_linear() * b))
+                .into_function()
+                .with_overload(|a: f32, b: Color| Color::from(b.to_linear() * a))
+                .with_name("__mul"),
+        );
+        app.register_object_function::<Color>(
+            (|a: Color, b: f32| Color::from(a.to_linear() / b))
+                .into_function()
+                .with_name("__div"),
+        );
+    }
+}
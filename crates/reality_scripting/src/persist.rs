@@ -0,0 +1,483 @@
+// Script state that's saved and restored with the rest of the game
+
+use crate::LuaVm;
+use anyhow::anyhow;
+use bevy::prelude::*;
+use piccolo::{Context, Table, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Global holding the tables scripts persisted, by key
+const PERSISTED_GLOBAL: &str = "__persisted";
+
+/// Written at the start of `ScriptState::to_bytes`, followed by the format version
+const MAGIC: &[u8] = b"LUAS\x01";
+
+/// The plain data of the tables scripts persisted with `app:persist(key, table)`.
+/// Reflected, so it's part of world snapshots. Replacing it, e.g. by loading a snapshot, refills the tables
+#[derive(Resource, Reflect, Default, Clone, Debug, PartialEq)]
+#[reflect(Resource, Default)]
+pub struct ScriptState {
+    pub tables: BTreeMap<String, LuaData>,
+}
+
+/// A lua value without functions, userdata or shared references, so it can be saved
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub enum LuaData {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    /// Entries sorted by key, so equal tables always save the same way
+    Table(Vec<(LuaData, LuaData)>),
+}
+
+impl ScriptState {
+    /// Reads the persisted tables, failing if one holds something other than plain data.
+    /// Saved tables whose script didn't persist them yet are kept
+    pub fn capture(world: &mut World) -> Result<Self, anyhow::Error> {
+        let mut tables = world
+            .get_resource::<ScriptState>()
+            .map(|state| state.tables.clone())
+            .unwrap_or_default();
+        let mut lua = world
+            .get_non_send_resource_mut::<LuaVm>()
+            .ok_or_else(|| anyhow!("no lua vm to capture script state from"))?;
+        lua.try_enter(|ctx| {
+            for (key, table) in persisted_tables(ctx)? {
+                let (Value::String(key), Value::Table(table)) = (key, table) else {
+                    continue;
+                };
+                let key = key.to_str()?.to_string();
+                let data = LuaData::from_table(ctx, table, &key, &mut vec![])?;
+                tables.insert(key, data);
+            }
+            Ok(())
+        })
+        .map_err(|err| anyhow!("{err}"))?;
+        Ok(Self { tables })
+    }
+
+    /// Overwrites the contents of the persisted tables with the saved ones. Tables that aren't persisted yet
+    /// are filled in once their script persists them, e.g. when it loads after a restart
+    pub fn restore(&self, world: &mut World) -> Result<(), anyhow::Error> {
+        world.insert_resource(self.clone());
+        world.insert_resource(SyncedScriptState(self.clone()));
+        let Some(mut lua) = world.get_non_send_resource_mut::<LuaVm>() else {
+            return Ok(());
+        };
+        lua.try_enter(|ctx| {
+            let persisted = persisted_tables(ctx)?;
+            for (key, data) in &self.tables {
+                if let Value::Table(table) = persisted.get::<_, Value>(ctx, key.as_str())? {
+                    data.fill_table(ctx, table)?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(|err| anyhow!("{err}"))
+    }
+
+    /// A compact encoding that doesn't change between runs or platforms, e.g. for AO checkpoints
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_len(&mut bytes, self.tables.len());
+        for (key, data) in &self.tables {
+            write_str(&mut bytes, key);
+            data.write(&mut bytes);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut reader = bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| anyhow!("not a saved script state, or saved by another version"))?;
+        let mut tables = BTreeMap::new();
+        for _ in 0..read_len(&mut reader)? {
+            let key = read_str(&mut reader)?;
+            tables.insert(key, LuaData::read(&mut reader)?);
+        }
+        if !reader.is_empty() {
+            return Err(anyhow!("{} unexpected bytes after the script state", reader.len()));
+        }
+        Ok(Self { tables })
+    }
+}
+
+/// The `ScriptState` last captured or restored, to tell when something else replaced the resource
+#[derive(Resource, Default)]
+struct SyncedScriptState(ScriptState);
+
+/// The `ScriptState` resource, if it changed since it was last captured or restored
+fn replaced_state(world: &World) -> Option<ScriptState> {
+    let state = world.get_resource::<ScriptState>()?;
+    let synced = world.get_resource::<SyncedScriptState>();
+    let unchanged = match synced {
+        Some(synced) => synced.0 == *state,
+        None => *state == ScriptState::default(),
+    };
+    (!unchanged).then(|| state.clone())
+}
+
+/// Refills the persisted tables when the `ScriptState` resource was replaced, e.g. by loading a snapshot.
+/// `LuaPlugin` runs it in `First`
+pub fn restore_script_state(world: &mut World) {
+    let Some(state) = replaced_state(world) else {
+        return;
+    };
+    if let Err(err) = state.restore(world) {
+        error!("couldn't restore script state: {err}");
+    }
+}
+
+/// Updates the `ScriptState` resource from the persisted tables. `LuaPlugin` runs it in `Last`, so world
+/// snapshots taken between updates are current. If the resource was replaced during the update,
+/// the tables are restored from it instead
+pub fn save_script_state(world: &mut World) {
+    if replaced_state(world).is_some() {
+        restore_script_state(world);
+        return;
+    }
+    match ScriptState::capture(world) {
+        Ok(state) => {
+            world.insert_resource(SyncedScriptState(state.clone()));
+            // only touched when it changed, so change detection on it stays meaningful
+            if world.get_resource::<ScriptState>() != Some(&state) {
+                world.insert_resource(state);
+            }
+        }
+        Err(err) => error!("couldn't save script state: {err}"),
+    }
+}
+
+/// Registers `table` to be saved under `key`, filling it with the data it had before a hot reload
+/// or in the restored `ScriptState`
+pub(crate) fn persist_table<'gc>(
+    ctx: Context<'gc>,
+    world: &World,
+    key: &str,
+    table: Table<'gc>,
+) -> Result<(), anyhow::Error> {
+    let persisted = persisted_tables(ctx)?;
+    let previous = match persisted.get::<_, Value>(ctx, key)? {
+        Value::Table(previous) if previous != table => {
+            Some(LuaData::from_table(ctx, previous, key, &mut vec![])?)
+        }
+        _ => world
+            .get_resource::<ScriptState>()
+            .and_then(|state| state.tables.get(key).cloned()),
+    };
+    if let Some(previous) = previous {
+        previous.fill_table(ctx, table)?;
+    }
+    persisted.set(ctx, key, table)?;
+    Ok(())
+}
+
+fn persisted_tables(ctx: Context<'_>) -> Result<Table<'_>, anyhow::Error> {
+    match ctx.globals().get::<_, Value>(ctx, PERSISTED_GLOBAL)? {
+        Value::Table(table) => Ok(table),
+        _ => {
+            let table = Table::new(&ctx);
+            ctx.set_global(PERSISTED_GLOBAL, table);
+            Ok(table)
+        }
+    }
+}
+
+impl LuaData {
+    /// `path` names the value in errors, `parents` are the tables it's nested in, to reject cycles
+    fn from_table<'gc>(
+        ctx: Context<'gc>,
+        table: Table<'gc>,
+        path: &str,
+        parents: &mut Vec<Table<'gc>>,
+    ) -> Result<Self, anyhow::Error> {
+        if parents.contains(&table) {
+            return Err(anyhow!("{path} contains itself"));
+        }
+        parents.push(table);
+        let mut entries = vec![];
+        for (key, value) in table {
+            let key = match key {
+                Value::Table(_) => {
+                    return Err(anyhow!("{path} has a table as a key, only plain keys can be saved"))
+                }
+                key => Self::from_value(ctx, key, path, parents)?,
+            };
+            let value_path = match &key {
+                LuaData::String(name) => format!("{path}.{name}"),
+                key => format!("{path}[{}]", key.key_string()),
+            };
+            entries.push((key, Self::from_value(ctx, value, &value_path, parents)?));
+        }
+        parents.pop();
+        entries.sort_by(|(a, _), (b, _)| a.key_cmp(b));
+        Ok(Self::Table(entries))
+    }
+
+    fn from_value<'gc>(
+        ctx: Context<'gc>,
+        value: Value<'gc>,
+        path: &str,
+        parents: &mut Vec<Table<'gc>>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(match value {
+            Value::Boolean(boolean) => Self::Boolean(boolean),
+            Value::Integer(integer) => Self::Integer(integer),
+            Value::Number(number) => Self::Number(number),
+            Value::String(string) => Self::String(
+                string
+                    .to_str()
+                    .map_err(|_| anyhow!("{path} isn't valid UTF-8"))?
+                    .to_string(),
+            ),
+            Value::Table(table) => Self::from_table(ctx, table, path, parents)?,
+            value => {
+                return Err(anyhow!(
+                    "{path} is a {}, only plain data can be saved",
+                    value.type_name()
+                ))
+            }
+        })
+    }
+
+    fn into_value<'gc>(&self, ctx: Context<'gc>) -> Result<Value<'gc>, anyhow::Error> {
+        Ok(match self {
+            Self::Boolean(boolean) => Value::Boolean(*boolean),
+            Self::Integer(integer) => Value::Integer(*integer),
+            Self::Number(number) => Value::Number(*number),
+            Self::String(string) => piccolo::String::from_slice(&ctx, string.as_bytes()).into(),
+            Self::Table(_) => {
+                let table = Table::new(&ctx);
+                self.fill_table(ctx, table)?;
+                table.into()
+            }
+        })
+    }
+
+    /// Replaces the table's entries with the saved ones, keeping the table itself so references to it stay valid
+    fn fill_table<'gc>(&self, ctx: Context<'gc>, table: Table<'gc>) -> Result<(), anyhow::Error> {
+        let Self::Table(entries) = self else {
+            return Err(anyhow!("expected a saved table, got {self:?}"));
+        };
+        let keys = table.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        for key in keys {
+            table.set(ctx, key, Value::Nil)?;
+        }
+        for (key, value) in entries {
+            table.set(ctx, key.into_value(ctx)?, value.into_value(ctx)?)?;
+        }
+        Ok(())
+    }
+
+    fn key_string(&self) -> String {
+        match self {
+            Self::Boolean(boolean) => boolean.to_string(),
+            Self::Integer(integer) => integer.to_string(),
+            Self::Number(number) => number.to_string(),
+            Self::String(string) => format!("{string:?}"),
+            Self::Table(_) => "table".to_string(),
+        }
+    }
+
+    /// Booleans, then numbers, then strings
+    fn key_cmp(&self, other: &Self) -> Ordering {
+        fn rank(data: &LuaData) -> u8 {
+            match data {
+                LuaData::Boolean(_) => 0,
+                LuaData::Integer(_) | LuaData::Number(_) => 1,
+                LuaData::String(_) => 2,
+                LuaData::Table(_) => 3,
+            }
+        }
+        match (self, other) {
+            (Self::Boolean(a), Self::Boolean(b)) => a.cmp(b),
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            (Self::Integer(a), Self::Number(b)) => (*a as f64).total_cmp(b),
+            (Self::Number(a), Self::Integer(b)) => a.total_cmp(&(*b as f64)),
+            (Self::Number(a), Self::Number(b)) => a.total_cmp(b),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (a, b) => rank(a).cmp(&rank(b)),
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Boolean(boolean) => bytes.extend([b'b', *boolean as u8]),
+            Self::Integer(integer) => {
+                bytes.push(b'i');
+                bytes.extend(integer.to_le_bytes());
+            }
+            Self::Number(number) => {
+                bytes.push(b'n');
+                bytes.extend(number.to_bits().to_le_bytes());
+            }
+            Self::String(string) => {
+                bytes.push(b's');
+                write_str(bytes, string);
+            }
+            Self::Table(entries) => {
+                bytes.push(b't');
+                write_len(bytes, entries.len());
+                for (key, value) in entries {
+                    key.write(bytes);
+                    value.write(bytes);
+                }
+            }
+        }
+    }
+
+    fn read(reader: &mut &[u8]) -> Result<Self, anyhow::Error> {
+        Ok(match take::<1>(reader)? {
+            [b'b'] => Self::Boolean(take::<1>(reader)? != [0]),
+            [b'i'] => Self::Integer(i64::from_le_bytes(take(reader)?)),
+            [b'n'] => Self::Number(f64::from_bits(u64::from_le_bytes(take(reader)?))),
+            [b's'] => Self::String(read_str(reader)?),
+            [b't'] => {
+                let len = read_len(reader)?;
+                let mut entries = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    entries.push((Self::read(reader)?, Self::read(reader)?));
+                }
+                Self::Table(entries)
+            }
+            [tag] => return Err(anyhow!("unknown value tag {tag} in the script state")),
+        })
+    }
+}
+
+fn write_len(bytes: &mut Vec<u8>, len: usize) {
+    bytes.extend((len as u32).to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, string: &str) {
+    write_len(bytes, string.len());
+    bytes.extend(string.as_bytes());
+}
+
+fn take<const N: usize>(reader: &mut &[u8]) -> Result<[u8; N], anyhow::Error> {
+    let (taken, rest) = reader
+        .split_first_chunk::<N>()
+        .ok_or_else(|| anyhow!("the script state ends early"))?;
+    *reader = rest;
+    Ok(*taken)
+}
+
+fn read_len(reader: &mut &[u8]) -> Result<usize, anyhow::Error> {
+    Ok(u32::from_le_bytes(take(reader)?) as usize)
+}
+
+fn read_str(reader: &mut &[u8]) -> Result<String, anyhow::Error> {
+    let len = read_len(reader)?;
+    if reader.len() < len {
+        return Err(anyhow!("the script state ends early"));
+    }
+    let (string, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(String::from_utf8(string.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> ScriptState {
+        let quests = LuaData::Table(vec![
+            (LuaData::Boolean(true), LuaData::Number(-0.5)),
+            (LuaData::Integer(1), LuaData::String("find the key".to_string())),
+            (
+                LuaData::String("nested".to_string()),
+                LuaData::Table(vec![(LuaData::Integer(i64::MIN), LuaData::Boolean(false))]),
+            ),
+        ]);
+        ScriptState {
+            tables: BTreeMap::from([
+                ("empty".to_string(), LuaData::Table(vec![])),
+                ("quests".to_string(), quests),
+            ]),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let state = state();
+        assert_eq!(ScriptState::from_bytes(&state.to_bytes()).unwrap(), state);
+        let empty = ScriptState::default();
+        assert_eq!(ScriptState::from_bytes(&empty.to_bytes()).unwrap(), empty);
+    }
+
+    #[test]
+    fn keeps_numbers_exact() {
+        let state = ScriptState {
+            tables: BTreeMap::from([(
+                "numbers".to_string(),
+                LuaData::Table(vec![
+                    (LuaData::Integer(1), LuaData::Number(0.1 + 0.2)),
+                    (LuaData::Integer(2), LuaData::Number(f64::MAX)),
+                    (LuaData::Integer(3), LuaData::Integer(i64::MAX)),
+                ]),
+            )]),
+        };
+        assert_eq!(ScriptState::from_bytes(&state.to_bytes()).unwrap(), state);
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        let err = ScriptState::from_bytes(b"not a script state").unwrap_err();
+        assert!(err.to_string().contains("not a saved script state"), "{err}");
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = state().to_bytes();
+        bytes.push(0);
+        let err = ScriptState::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("1 unexpected bytes"), "{err}");
+    }
+
+    #[test]
+    fn rejects_truncated_bytes() {
+        let bytes = state().to_bytes();
+        for len in MAGIC.len()..bytes.len() {
+            assert!(ScriptState::from_bytes(&bytes[..len]).is_err(), "accepted {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        let mut bytes = MAGIC.to_vec();
+        write_len(&mut bytes, 1);
+        write_str(&mut bytes, "key");
+        bytes.push(b'x');
+        let err = ScriptState::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("unknown value tag"), "{err}");
+    }
+
+    #[test]
+    fn sorts_keys_by_kind_then_value() {
+        let mut keys = vec![
+            LuaData::String("b".to_string()),
+            LuaData::Number(1.5),
+            LuaData::String("a".to_string()),
+            LuaData::Integer(2),
+            LuaData::Boolean(true),
+            LuaData::Integer(1),
+            LuaData::Boolean(false),
+        ];
+        keys.sort_by(LuaData::key_cmp);
+        assert_eq!(
+            keys,
+            [
+                LuaData::Boolean(false),
+                LuaData::Boolean(true),
+                LuaData::Integer(1),
+                LuaData::Number(1.5),
+                LuaData::Integer(2),
+                LuaData::String("a".to_string()),
+                LuaData::String("b".to_string()),
+            ]
+        );
+    }
+}
@@ -1,15 +1,28 @@
+use crate::convert::{
+    apply_lua_value, field_mut, field_ref, is_some, reflect_to_lua, type_registry,
+    TYPE_REGISTRY_GLOBAL,
+};
+use crate::entity::{EntityHandle, EntityMarker, SystemContext};
+use crate::error::function_location;
+use crate::event::{EventParam, ReflectLuaEvent};
+use crate::naming::{self, existing_table, set_alias, type_path_segments, LuaNaming};
+use crate::operators::add_operator_metamethods;
+use crate::persist::persist_table;
+use crate::schedule::{LuaSchedule, SystemOptions};
 use crate::userdata::{UserDataPtr, ValueExt};
 use crate::{lua_wrapped_dynamic_function_call, LuaVm};
-use bevy::ecs::component::ComponentId;
+use anyhow::anyhow;
+use bevy::ecs::component::{ComponentId, Tick};
 use bevy::ecs::world::FilteredEntityMut;
 use bevy::prelude::*;
-use bevy::reflect::func::FunctionRegistry;
+use bevy::reflect::func::{DynamicFunction, FunctionRegistry};
+use bevy::reflect::{PartialReflect, ReflectRef};
 use piccolo::{
     Callback, CallbackReturn, Context, FromValue, Function, IntoValue, StashedFunction, Table,
     TypeError, UserData, Value,
 };
 use std::any::TypeId;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::rc::Rc;
 
@@ -17,6 +30,55 @@ use std::rc::Rc;
 pub enum ComponentType {
     Ref((ComponentId, TypeId)),
     Mut((ComponentId, TypeId)),
+    // Yields nil for entities (or resources) without the component
+    OptionRef((ComponentId, TypeId)),
+    OptionMut((ComponentId, TypeId)),
+}
+
+/// What a query yields for each matched entity
+#[derive(Copy, Clone, Debug)]
+pub enum QueryData {
+    Entity,
+    Component(ComponentType),
+}
+
+/// Restricts which entities a query matches without fetching the component
+#[derive(Copy, Clone, Debug)]
+pub enum QueryFilter {
+    With(ComponentId),
+    Without(ComponentId),
+    Changed(ComponentId),
+    Added(ComponentId),
+}
+
+impl QueryFilter {
+    fn add_to_builder(&self, query_builder: &mut QueryBuilder<FilteredEntityMut>) {
+        match self {
+            QueryFilter::With(component_id) => {
+                query_builder.with_id(*component_id);
+            }
+            QueryFilter::Without(component_id) => {
+                query_builder.without_id(*component_id);
+            }
+            // there are no dynamic change detection filters, so we need read access to check the ticks
+            QueryFilter::Changed(component_id) | QueryFilter::Added(component_id) => {
+                query_builder.ref_id(*component_id);
+            }
+        }
+    }
+
+    /// Checks the filters that can't be expressed through the `QueryBuilder`
+    pub fn matches(&self, entity: &FilteredEntityMut, last_run: Tick, this_run: Tick) -> bool {
+        match self {
+            QueryFilter::With(_) | QueryFilter::Without(_) => true,
+            QueryFilter::Changed(component_id) => entity
+                .get_change_ticks_by_id(*component_id)
+                .is_some_and(|ticks| ticks.is_changed(last_run, this_run)),
+            QueryFilter::Added(component_id) => entity
+                .get_change_ticks_by_id(*component_id)
+                .is_some_and(|ticks| ticks.is_added(last_run, this_run)),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -24,20 +86,52 @@ pub struct CommandQueueMarker;
 
 pub struct LuaSystem {
     pub lua_func: StashedFunction,
+    /// Where the system's function is defined, for error messages
+    pub name: String,
     pub system_parameters: Vec<SystemParameter>,
+    /// Change tick of the previous run, for `changed` and `added` filters
+    pub last_run: Tick,
+    pub options: SystemOptions,
+    /// `Startup` systems only run once
+    pub has_run: bool,
+    /// Set when the system exceeds its budget, see `LuaSandbox`
+    pub disabled: bool,
+}
+
+impl LuaSystem {
+    pub fn runs_in(&self, schedule: LuaSchedule) -> bool {
+        if self.disabled {
+            return false;
+        }
+        match self.options.schedule {
+            // startup systems run at the start of the first frame they're loaded in
+            LuaSchedule::Startup => schedule == LuaSchedule::PreUpdate && !self.has_run,
+            system_schedule => system_schedule == schedule,
+        }
+    }
 }
 
 pub enum SystemParameter {
-    Query((QueryState<FilteredEntityMut<'static>>, Vec<ComponentType>)),
+    Query(
+        (
+            QueryState<FilteredEntityMut<'static>>,
+            Vec<QueryData>,
+            Vec<QueryFilter>,
+        ),
+    ),
     CommandQueue,
     Resource(ComponentType),
+    /// The event type, and the event count read up to
+    EventReader((TypeId, usize)),
+    EventWriter(TypeId),
 }
 
 pub struct ReflectPtr {
     pub data: ReflectType,
-    path: String,
+    /// Keys from the root value to the referenced field, see `convert::field_ref`
+    path: Vec<String>,
     ptr_state: Rc<RefCell<PtrState>>,
-    function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+    pub(crate) function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
 }
 
 pub enum ReflectType {
@@ -75,7 +169,7 @@ impl ReflectPtr {
     ) -> Self {
         Self {
             data: ReflectType::PtrMut(reflect as *mut dyn Reflect),
-            path: "".to_string(),
+            path: vec![],
             ptr_state,
             function_registry,
         }
@@ -87,7 +181,7 @@ impl ReflectPtr {
     ) -> Self {
         Self {
             data: ReflectType::PtrRef(reflect as *const dyn Reflect),
-            path: "".to_string(),
+            path: vec![],
             ptr_state,
             function_registry,
         }
@@ -99,52 +193,63 @@ impl ReflectPtr {
     ) -> Self {
         Self {
             data: ReflectType::Boxed(Rc::new(RefCell::new(Some(reflect)))),
-            path: "".to_string(),
+            path: vec![],
             ptr_state,
             function_registry,
         }
     }
-    pub fn get_field_value_ref(&self) -> &dyn Reflect {
-        if &*self.ptr_state.borrow() == &PtrState::Invalid {
-            panic!("invalid pointer state, saved outside of valid area")
+    pub fn get_field_value_ref(&self) -> Result<&dyn Reflect, anyhow::Error> {
+        self.check_valid()?;
+        let mut reflect = unsafe { &*self.get_data() }.as_partial_reflect();
+        for key in &self.path {
+            reflect = field_ref(reflect, key)?;
         }
-        let mut reflect = unsafe { &*self.get_data() };
-        for field in self.path.split(".") {
-            if field.is_empty() {
-                continue;
-            }
-            reflect = reflect
-                .as_reflect()
-                .reflect_ref()
-                .as_struct()
-                .unwrap()
-                .field(field)
-                .unwrap()
-                .try_as_reflect()
-                .unwrap();
+        reflect
+            .try_as_reflect()
+            .ok_or_else(|| anyhow!("{} is not fully reflected", reflect.reflect_type_path()))
+    }
+    pub fn get_field_value_mut(&self) -> Result<&mut dyn Reflect, anyhow::Error> {
+        self.check_valid()?;
+        let data = self
+            .get_data_mut()
+            .ok_or_else(|| anyhow!("can't modify a value fetched with `ref`, use `mut` instead"))?;
+        let mut reflect = unsafe { &mut *data }.as_partial_reflect_mut();
+        for key in &self.path {
+            reflect = field_mut(reflect, key)?;
         }
+        let type_path = reflect.reflect_type_path().to_string();
         reflect
+            .try_as_reflect_mut()
+            .ok_or_else(|| anyhow!("{type_path} is not fully reflected"))
     }
-    pub fn get_field_value_mut(&self) -> &mut dyn Reflect {
-        if &*self.ptr_state.borrow() == &PtrState::Invalid {
-            panic!("invalid pointer state, saved outside of valid area")
+    /// Finds a function registered for the type of the referenced value
+    pub fn object_function(
+        &self,
+        name: &str,
+    ) -> Result<Option<DynamicFunction<'static>>, anyhow::Error> {
+        let type_id = self.get_field_value_ref()?.reflect_type_info().type_id();
+        Ok(self
+            .function_registry
+            .borrow()
+            .get(&type_id)
+            .and_then(|function_registry| function_registry.get(name))
+            .cloned())
+    }
+    /// The state references into this value have to share, or `None` if it's boxed and
+    /// references into it could outlive it
+    pub fn borrowed_state(&self) -> Option<Rc<RefCell<PtrState>>> {
+        match self.data {
+            ReflectType::PtrMut(_) | ReflectType::PtrRef(_) => Some(self.ptr_state.clone()),
+            ReflectType::Boxed(_) => None,
         }
-        let mut reflect = unsafe { &mut *self.get_data_mut().unwrap() };
-        for field in self.path.split(".") {
-            if field.is_empty() {
-                continue;
-            }
-            reflect = reflect
-                .as_reflect_mut()
-                .reflect_mut()
-                .as_struct()
-                .unwrap()
-                .field_mut(field)
-                .unwrap()
-                .try_as_reflect_mut()
-                .unwrap();
+    }
+    fn check_valid(&self) -> Result<(), anyhow::Error> {
+        if *self.ptr_state.borrow() == PtrState::Invalid {
+            return Err(anyhow!(
+                "reflected value was kept outside of the system that fetched it"
+            ));
         }
-        reflect
+        Ok(())
     }
 }
 
@@ -172,110 +277,140 @@ impl UserDataPtr for ReflectPtr {
     }
 
     fn edit_metatable<'gc>(&self, ctx: &Context<'gc>, metatable: &mut Table<'gc>) {
+        add_operator_metamethods(ctx, metatable);
         metatable
-            .set(
-                *ctx,
-                "__add",
-                Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
-                    let (this, rhs): (&Self, Value) = stack.consume(ctx)?;
-                    match rhs {
-                        Value::Integer(integer) => {
-                            if let Some(awa) = this.get_field_value_ref().downcast_ref::<i32>() {
-                                stack.push_front(Value::Integer(*awa as i64 + integer))
-                            }
-                        }
-                        Value::Number(number) => {
-                            if let Some(awa) = this.get_field_value_ref().downcast_ref::<f32>() {
-                                stack.push_front(Value::Number(*awa as f64 + number))
-                            }
-                        }
-                        Value::UserData(_) => {
-                            todo!()
-                        }
-                        _ => {
-                            stack.push_front(Value::Nil);
-                            println!("tried to add something that can't be added")
-                        }
-                    }
-                    Ok(CallbackReturn::Return)
-                }),
-            )
+            .set(*ctx, "__pairs", reflect_pairs(ctx))
             .unwrap();
     }
 
     fn lua_to_string(&self) -> String {
-        format!("{:?}", self.get_field_value_ref())
+        match self.get_field_value_ref() {
+            Ok(reflect) => format!("{:?}", reflect),
+            Err(err) => format!("<{err}>"),
+        }
     }
 
     // TODO safe mutability by seperating mut vs ref pointers
-    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Value<'gc> {
+    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Result<Value<'gc>, anyhow::Error> {
         let mut reflect_ptr = self.clone();
-        if let Some(function_registry) = self
-            .function_registry
-            .borrow()
-            .get(&self.get_field_value_ref().reflect_type_info().type_id())
-        {
-            if let Some(function) = function_registry.get(key) {
-                return lua_wrapped_dynamic_function_call(
-                    *ctx,
-                    function.clone(),
-                    self.function_registry.clone(),
-                );
-            }
+        if let Some(function) = self.object_function(key)? {
+            return Ok(lua_wrapped_dynamic_function_call(
+                *ctx,
+                function,
+                self.function_registry.clone(),
+            ));
         }
         // this is the case where it's not in the function registry
-        reflect_ptr.path.push('.');
-        reflect_ptr.path.push_str(key);
-        reflect_ptr.into_value(ctx)
+        reflect_ptr.path.push(key.to_string());
+        let field = reflect_ptr.get_field_value_ref()?.as_partial_reflect();
+        if let Some(value) = reflect_to_lua(*ctx, field) {
+            return Ok(value);
+        }
+        // options holding a value act like the value itself
+        if is_some(field) {
+            reflect_ptr.path.push("1".to_string());
+        }
+        Ok(reflect_ptr.into_value(ctx))
     }
 
-    fn lua_new_index<'gc>(&self, _ctx: &Context<'gc>, key: &str, new_value: Value<'gc>) {
+    fn lua_new_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: &str,
+        new_value: Value<'gc>,
+    ) -> Result<(), anyhow::Error> {
         let mut reflect_ptr = self.clone();
-        reflect_ptr.path.push('.');
-        reflect_ptr.path.push_str(key);
-        match new_value {
-            Value::Number(n) => {
-                let mut reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                reflect_field.set(Box::new(n as f32)).unwrap();
-            }
-            Value::Integer(i) => {
-                let mut reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                if let Err(awa) = reflect_field.set(Box::new(i as i32)) {
-                    println!("error setting value: {:?}", awa);
-                }
-            }
-            Value::Boolean(b) => {
-                let mut reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                reflect_field.set(Box::new(b)).unwrap();
-            }
-            Value::String(_s) => {
-                todo!()
-            }
-            Value::UserData(data) => {
-                if reflect_ptr.path.is_empty() {
-                    reflect_ptr.data = data.downcast_static::<ReflectPtr>().unwrap().data.clone();
-                    reflect_ptr.path = String::default();
-                    reflect_ptr.ptr_state = data
-                        .downcast_static::<ReflectPtr>()
-                        .unwrap()
-                        .ptr_state
-                        .clone();
-                } else {
-                    let reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                    let reflect = data
-                        .downcast_static::<ReflectPtr>()
-                        .unwrap()
-                        .get_field_value_mut()
-                        .clone_value();
-                    reflect_field.apply(&*reflect);
-                    //reflect_field.set(reflect).unwrap();
-                }
-            }
-            _ => {}
+        reflect_ptr.path.push(key.to_string());
+        let registry = type_registry(*ctx)?.read();
+        let field = reflect_ptr.get_field_value_mut()?;
+        apply_lua_value(field.as_partial_reflect_mut(), new_value, &registry)
+            .map_err(|err| anyhow!("couldn't set {key}: {err}"))
+    }
+}
+
+/// A key `pairs` yields, ordered booleans first, then integers, then strings
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum PairsKey {
+    Boolean(bool),
+    Integer(i64),
+    String(String),
+}
+
+impl PairsKey {
+    fn from_reflect(ctx: Context, key: &dyn PartialReflect) -> Result<Self, anyhow::Error> {
+        match reflect_to_lua(ctx, key) {
+            Some(Value::Boolean(boolean)) => Ok(Self::Boolean(boolean)),
+            Some(Value::Integer(integer)) => Ok(Self::Integer(integer)),
+            Some(Value::String(string)) => Ok(Self::String(string.to_str()?.to_string())),
+            _ => Err(anyhow!("can't iterate over a map with {} keys", key.reflect_type_path())),
+        }
+    }
+
+    fn into_value<'gc>(&self, ctx: Context<'gc>) -> Value<'gc> {
+        match self {
+            Self::Boolean(boolean) => Value::Boolean(*boolean),
+            Self::Integer(integer) => Value::Integer(*integer),
+            Self::String(string) => piccolo::String::from_slice(&ctx, string.as_bytes()).into(),
+        }
+    }
+
+    /// The key as `lua_index` takes it
+    fn field(&self) -> String {
+        match self {
+            Self::Boolean(boolean) => boolean.to_string(),
+            Self::Integer(integer) => integer.to_string(),
+            Self::String(string) => string.clone(),
         }
     }
 }
 
+/// `pairs(value)` visits struct fields in declaration order, list items by index and map entries sorted by key,
+/// so it's the same on every run, unlike the order of a `HashMap`
+fn reflect_pairs<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+    Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+        let this: Value = stack.consume(ctx)?;
+        let reflect_ptr = this.as_static_user_data::<ReflectPtr>()?;
+        let value = reflect_ptr.get_field_value_ref()?;
+        let indices = |len: usize| (1..=len as i64).map(PairsKey::Integer).collect::<Vec<_>>();
+        let keys: Vec<PairsKey> = match value.reflect_ref() {
+            ReflectRef::Struct(value) => (0..value.field_len())
+                .filter_map(|index| value.name_at(index))
+                .map(|name| PairsKey::String(name.to_string()))
+                .collect(),
+            ReflectRef::TupleStruct(value) => indices(value.field_len()),
+            ReflectRef::Tuple(value) => indices(value.field_len()),
+            ReflectRef::List(value) => indices(value.len()),
+            ReflectRef::Array(value) => indices(value.len()),
+            ReflectRef::Map(value) => {
+                let mut keys = value
+                    .iter()
+                    .map(|(key, _)| PairsKey::from_reflect(ctx, key))
+                    .collect::<Result<Vec<_>, _>>()?;
+                keys.sort();
+                keys
+            }
+            _ => {
+                return Err(anyhow!("can't iterate over {}", value.reflect_type_path()).into());
+            }
+        };
+        let next = Rc::new(Cell::new(0));
+        let iterator = Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let (this, _previous): (Value, Value) = stack.consume(ctx)?;
+            let Some(key) = keys.get(next.get()) else {
+                stack.replace(ctx, Value::Nil);
+                return Ok(CallbackReturn::Return);
+            };
+            next.set(next.get() + 1);
+            let reflect_ptr = this.as_static_user_data::<ReflectPtr>()?;
+            let value = reflect_ptr.lua_index(&ctx, &key.field())?;
+            stack.replace(ctx, (key.into_value(ctx), value));
+            Ok(CallbackReturn::Return)
+        });
+        stack.replace(ctx, (iterator, this, Value::Nil));
+        Ok(CallbackReturn::Return)
+    })
+}
+
 impl Clone for ReflectPtr {
     fn clone(&self) -> Self {
         Self {
@@ -299,18 +434,52 @@ pub struct WorldMut {
 
 impl Clone for WorldMut {
     fn clone(&self) -> Self {
-        WorldMut {
-            this: Some(self.this.unwrap()),
-        }
+        WorldMut { this: self.this }
     }
 }
 
 impl WorldMut {
+    /// Global the `on_reload` hook of the loading script is kept in
+    pub const ON_RELOAD_GLOBAL: &'static str = "__on_reload";
+
+    /// Sets the function called with the previous version's state when the script is hot reloaded
+    pub fn on_reload<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
+            let (this, hook): (&WorldMut, Function) = stack.consume(ctx)?;
+            // only set while the script loads
+            this.world()
+                .map_err(|_| anyhow!("on_reload can only be set while the script loads"))?;
+            ctx.set_global(Self::ON_RELOAD_GLOBAL, hook);
+            Ok(CallbackReturn::Return)
+        })
+    }
+
+    /// Saves the table's plain data with `ScriptState`, under a key unique to the game, and returns it.
+    /// The table gets the data it had before a hot reload, or the data of a restored `ScriptState`
+    pub fn persist<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
+            let (this, key, table): (&WorldMut, piccolo::String, Table) = stack.consume(ctx)?;
+            let world = this
+                .world()
+                .map_err(|_| anyhow!("tables can only be persisted while the script loads"))?;
+            persist_table(ctx, world, key.to_str()?, table)?;
+            stack.replace(ctx, table);
+            Ok(CallbackReturn::Return)
+        })
+    }
+
     pub fn new(world: &mut World) -> Self {
         Self {
             this: Some(world as *mut World),
         }
     }
+
+    fn world(&self) -> Result<&mut World, anyhow::Error> {
+        let world = self
+            .this
+            .ok_or_else(|| anyhow!("the app is no longer available"))?;
+        Ok(unsafe { &mut *world })
+    }
 }
 
 impl<'gc> FromValue<'gc> for &'gc WorldMut {
@@ -323,11 +492,11 @@ impl UserDataPtr for WorldMut {
     type Data = World;
 
     fn get_data_mut(&self) -> Option<*mut Self::Data> {
-        Some(self.this.unwrap())
+        self.this
     }
 
     fn get_data(&self) -> *const Self::Data {
-        self.this.unwrap() as *const Self::Data
+        self.this.map_or(std::ptr::null(), |world| world as *const Self::Data)
     }
 
     fn edit_metatable<'gc>(&self, _ctx: &Context<'gc>, _table: &mut Table<'gc>) {}
@@ -336,18 +505,47 @@ impl UserDataPtr for WorldMut {
         "app".to_string()
     }
 
-    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Value<'gc> {
-        match key {
+    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Result<Value<'gc>, anyhow::Error> {
+        Ok(match key {
             "query" => Self::query(ctx).into_value(*ctx),
             "register_system" => Self::register_system(ctx).into_value(*ctx),
+            "entity" => Self::entity(ctx).into_value(*ctx),
+            "on_reload" => Self::on_reload(ctx).into_value(*ctx),
+            "persist" => Self::persist(ctx).into_value(*ctx),
             &_ => Value::Nil,
-        }
+        })
     }
 
-    fn lua_new_index<'gc>(&self, _ctx: &Context<'gc>, _key: &str, _new_value: Value<'gc>) {}
+    fn lua_new_index<'gc>(
+        &self,
+        _ctx: &Context<'gc>,
+        key: &str,
+        _new_value: Value<'gc>,
+    ) -> Result<(), anyhow::Error> {
+        Err(anyhow!("can't set {key} on the app"))
+    }
 }
 
 impl WorldMut {
+    /// Looks up an entity by its `id`, returning nil if it doesn't exist.
+    /// Works inside systems, where the app itself is no longer available, and while the script loads
+    pub fn entity<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
+        Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
+            let (this, id): (&WorldMut, i64) = stack.consume(ctx)?;
+            let world = match SystemContext::current(ctx) {
+                Ok(context) => &*context.world(),
+                Err(_) => &*this.world()?,
+            };
+            let entity = Entity::try_from_bits(id as u64)
+                .ok()
+                .filter(|entity| world.get_entity(*entity).is_ok());
+            match entity {
+                Some(entity) => stack.replace(ctx, EntityHandle::new(entity).into_value(&ctx)),
+                None => stack.replace(ctx, Value::Nil),
+            }
+            Ok(CallbackReturn::Return)
+        })
+    }
     pub fn query<'gc>(ctx: &Context<'gc>) -> Callback<'gc> {
         Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
             let args: Value = stack.consume(ctx)?;
@@ -361,28 +559,37 @@ impl WorldMut {
         Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
             let systems_vec = ctx
                 .globals()
-                .get::<_, Value>(ctx, "__systems_vec")
-                .unwrap()
-                .as_static_user_data::<Rc<RefCell<Option<Vec<LuaSystem>>>>>()?;
+                .get::<_, Value>(ctx, "__systems_vec")?
+                .as_static_user_data::<Rc<RefCell<Option<Vec<LuaSystem>>>>>()
+                .map_err(|_| anyhow!("systems can only be registered while the script loads"))?;
             let systems_vec = systems_vec.clone();
 
-            let (this, system, system_params): (&WorldMut, Value, Table) = stack.consume(ctx)?;
+            let (this, system, system_params, options): (&WorldMut, Value, Table, Option<Table>) =
+                stack.consume(ctx)?;
 
             let function: Function = Function::from_value(ctx, system)?;
+            let name = function_location(function);
+            let options = SystemOptions::from_table(ctx, options)
+                .map_err(|err| anyhow!("{name}: bad system options: {err}"))?;
 
-            let world = unsafe { &mut *this.get_data_mut().unwrap() };
+            let world = this.world()?;
 
             let mut system_parameters = vec![];
 
             for (_, system_parameter) in system_params.into_iter() {
-                //println!("hi");
                 // TODO add resources and other things here too
                 if system_parameter
                     .as_static_user_data::<CommandQueueMarker>()
                     .is_ok()
                 {
                     system_parameters.push(SystemParameter::CommandQueue);
-                    //println!("hello");
+                    continue;
+                }
+                if let Ok(event_param) = system_parameter.as_static_user_data::<EventParam>() {
+                    system_parameters.push(match *event_param {
+                        EventParam::Reader(type_id) => SystemParameter::EventReader((type_id, 0)),
+                        EventParam::Writer(type_id) => SystemParameter::EventWriter(type_id),
+                    });
                     continue;
                 }
                 if let Ok(resource_component_type) =
@@ -393,37 +600,77 @@ impl WorldMut {
                     continue;
                 }
 
-                let table = Table::from_value(ctx, system_parameter)?;
-                //println!("UwU");
+                let table = Table::from_value(ctx, system_parameter).map_err(|_| {
+                    anyhow!(
+                        "{name}: expected Commands, a resource, an event reader or writer, or a table of query terms, got a {}",
+                        system_parameter.type_name()
+                    )
+                })?;
                 let mut query_builder = QueryBuilder::<FilteredEntityMut>::new(world);
-                //println!("UwU");
                 //TODO we might want to restrict this to something like mut vs ref components
                 let mut components = vec![];
+                let mut filters = vec![];
                 for (_, component_type) in table.into_iter() {
-                    let component_type = UserData::from_value(ctx, component_type)?;
-                    let component_type = component_type
-                        .downcast_static::<ComponentType>()
-                        .unwrap()
-                        .clone();
+                    if component_type.as_static_user_data::<EntityMarker>().is_ok() {
+                        components.push(QueryData::Entity);
+                        continue;
+                    }
+                    if let Ok(filter) = component_type.as_static_user_data::<QueryFilter>() {
+                        filter.add_to_builder(&mut query_builder);
+                        filters.push(*filter);
+                        continue;
+                    }
+                    let component_type = *component_type
+                        .as_static_user_data::<ComponentType>()
+                        .map_err(|_| {
+                            anyhow!(
+                                "{name}: expected a query term like `Transform.ref`, got a {}",
+                                component_type.type_name()
+                            )
+                        })?;
                     match component_type {
-                        ComponentType::Ref((component_id, type_id)) => {
+                        ComponentType::Ref((component_id, _)) => {
                             query_builder.ref_id(component_id);
-                            components.push(component_type);
                         }
-                        ComponentType::Mut((component_id, type_id)) => {
+                        ComponentType::Mut((component_id, _)) => {
                             query_builder.mut_id(component_id);
-                            components.push(component_type);
+                        }
+                        ComponentType::OptionRef((component_id, _)) => {
+                            query_builder.optional(|query_builder| {
+                                query_builder.ref_id(component_id);
+                            });
+                        }
+                        ComponentType::OptionMut((component_id, _)) => {
+                            query_builder.optional(|query_builder| {
+                                query_builder.mut_id(component_id);
+                            });
                         }
                     }
+                    components.push(QueryData::Component(component_type));
                 }
                 let query_state = query_builder.build();
-                system_parameters.push(SystemParameter::Query((query_state, components)));
+                // only keep the filters that have to be checked per entity
+                filters.retain(|filter| {
+                    matches!(filter, QueryFilter::Changed(_) | QueryFilter::Added(_))
+                });
+                system_parameters.push(SystemParameter::Query((query_state, components, filters)));
             }
             let stashed_function = ctx.stash(function);
-            systems_vec.borrow_mut().as_mut().unwrap().push(LuaSystem {
-                lua_func: stashed_function,
-                system_parameters,
-            });
+            // like bevy systems, treat everything as changed on the first run
+            let last_run = world.change_tick().relative_to(Tick::MAX);
+            systems_vec
+                .borrow_mut()
+                .as_mut()
+                .ok_or_else(|| anyhow!("systems can only be registered while the script loads"))?
+                .push(LuaSystem {
+                    lua_func: stashed_function,
+                    name,
+                    system_parameters,
+                    last_run,
+                    options,
+                    has_run: false,
+                    disabled: false,
+                });
             Ok(CallbackReturn::Return)
         })
     }
@@ -437,22 +684,32 @@ impl Plugin for ReflectPlugin {
     }
 }
 
-fn register_components_and_markers(world: &mut World) {
+pub(crate) fn register_components_and_markers(world: &mut World) {
     world.resource_scope(|world, registry: Mut<AppTypeRegistry>| {
-        let mut lua = world.remove_non_send_resource::<LuaVm>().unwrap();
-        lua.try_enter(|ctx| {
-            ctx.globals()
-                .set(
-                    ctx,
-                    "Commands",
-                    UserData::new_static(&ctx, CommandQueueMarker).into_value(ctx),
-                )
-                .unwrap();
-            Ok(CallbackReturn::Return)
-        })
-        .unwrap();
-        for a in registry.read().iter() {
-            //println!("{}", a.type_info().type_path());
+        let Some(mut lua) = world.remove_non_send_resource::<LuaVm>() else {
+            warn!("no lua vm to register components in");
+            return;
+        };
+        let result = lua.try_enter(|ctx| {
+            ctx.globals().set(
+                ctx,
+                "Commands",
+                UserData::new_static(&ctx, CommandQueueMarker).into_value(ctx),
+            )?;
+            ctx.globals().set(
+                ctx,
+                "Entity",
+                UserData::new_static(&ctx, EntityMarker).into_value(ctx),
+            )?;
+            ctx.globals().set(
+                ctx,
+                TYPE_REGISTRY_GLOBAL,
+                UserData::new_static(&ctx, (*registry).clone()).into_value(ctx),
+            )?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            error!("couldn't set up lua globals: {err}");
         }
         let ids = registry
             .read()
@@ -474,136 +731,158 @@ fn register_components_and_markers(world: &mut World) {
             };
 
             let type_id = item.type_id();
-            let things = item
-                .type_info()
-                .type_path()
-                .split("::")
-                .collect::<Vec<&str>>();
-
-            // uncomment this if you wanna see the path of all the things aviable to you
-            //println!("{:?}", things);
-
-            lua.try_enter(|ctx| {
-                let mut lua_table = ctx.globals();
-                let len = things.len();
-                for (i, item) in things.into_iter().enumerate() {
-                    if i + 1 == len {
-                        let t = match lua_table.get(ctx, item).unwrap() {
-                            Value::Nil => {
-                                lua_table.set(ctx, item, Table::new(&ctx)).unwrap();
-                                match lua_table.get(ctx, item).unwrap() {
-                                    Value::Table(table) => table,
-                                    _ => unreachable!(),
-                                }
-                            }
-                            Value::Table(table) => table,
-                            _ => panic!("awa"),
-                        };
-
-                        if item == "Transform" {
-                            //println!("THIS ONE WAS TRANSOFMR");
-                            //println!("type id of the transfomr is: {:?}", type_id);
-                        }
+            let type_path = item.type_info().type_path();
 
-                        t.set(
-                            ctx,
-                            "ref",
-                            UserData::new_static(&ctx, ComponentType::Ref((component_id, type_id))),
-                        )
-                        .unwrap();
-                        t.set(
-                            ctx,
-                            "mut",
-                            UserData::new_static(&ctx, ComponentType::Mut((component_id, type_id))),
-                        )
-                        .unwrap();
-
-                        break;
-                    }
-                    lua_table = match lua_table.get(ctx, item).unwrap() {
-                        Value::Nil => {
-                            lua_table.set(ctx, item, Table::new(&ctx)).unwrap();
-                            match lua_table.get(ctx, item).unwrap() {
-                                Value::Table(table) => table,
-                                _ => unreachable!(),
-                            }
-                        }
-                        Value::Table(table) => table,
-                        _ => panic!("awa"),
-                    };
+            let result = lua.try_enter(|ctx| {
+                let t = lua_table_at_path(ctx, type_path)?;
+                t.set(
+                    ctx,
+                    "ref",
+                    UserData::new_static(&ctx, ComponentType::Ref((component_id, type_id))),
+                )?;
+                t.set(
+                    ctx,
+                    "mut",
+                    UserData::new_static(&ctx, ComponentType::Mut((component_id, type_id))),
+                )?;
+                t.set(
+                    ctx,
+                    "opt_ref",
+                    UserData::new_static(&ctx, ComponentType::OptionRef((component_id, type_id))),
+                )?;
+                t.set(
+                    ctx,
+                    "opt_mut",
+                    UserData::new_static(&ctx, ComponentType::OptionMut((component_id, type_id))),
+                )?;
+                for (key, filter) in [
+                    ("with", QueryFilter::With(component_id)),
+                    ("without", QueryFilter::Without(component_id)),
+                    ("changed", QueryFilter::Changed(component_id)),
+                    ("added", QueryFilter::Added(component_id)),
+                ] {
+                    t.set(ctx, key, UserData::new_static(&ctx, filter))?;
                 }
                 Ok(())
-            })
-            .unwrap();
+            });
+            if let Err(err) = result {
+                warn!("couldn't expose component {type_path} to lua: {err}");
+            }
+        }
+        // events
+        for item in registry.read().iter() {
+            if item.data::<ReflectLuaEvent>().is_none() {
+                continue;
+            }
+            let type_id = item.type_id();
+            let type_path = item.type_info().type_path();
+            let result = lua.try_enter(|ctx| {
+                let t = lua_table_at_path(ctx, type_path)?;
+                t.set(
+                    ctx,
+                    "reader",
+                    UserData::new_static(&ctx, EventParam::Reader(type_id)),
+                )?;
+                t.set(
+                    ctx,
+                    "writer",
+                    UserData::new_static(&ctx, EventParam::Writer(type_id)),
+                )?;
+                Ok(())
+            });
+            if let Err(err) = result {
+                warn!("couldn't expose event {type_path} to lua: {err}");
+            }
         }
         // now for resources
-        println!("THE RESOURCES IS");
         for (resource, _) in world.iter_resources() {
-            let type_id = resource.type_id().unwrap();
+            let Some(type_id) = resource.type_id() else {
+                continue;
+            };
             let r = registry.read();
             let Some(type_registration) = r.get(type_id) else {
                 continue;
             };
 
             let component_id = resource.id();
+            let type_path = type_registration.type_info().type_path();
 
-            let things = type_registration
-                .type_info()
-                .type_path()
-                .split("::")
-                .collect::<Vec<&str>>();
-
-            // uncomment this if you wanna see the path of all the things aviable to you
-            println!("{:?}", things);
-
-            lua.try_enter(|ctx| {
-                let mut lua_table = ctx.globals();
-                let len = things.len();
-                for (i, item) in things.into_iter().enumerate() {
-                    if i + 1 == len {
-                        let t = match lua_table.get(ctx, item).unwrap() {
-                            Value::Nil => {
-                                lua_table.set(ctx, item, Table::new(&ctx)).unwrap();
-                                match lua_table.get(ctx, item).unwrap() {
-                                    Value::Table(table) => table,
-                                    _ => unreachable!(),
-                                }
-                            }
-                            Value::Table(table) => table,
-                            _ => panic!("awa"),
-                        };
-
-                        t.set(
-                            ctx,
-                            "ref",
-                            UserData::new_static(&ctx, ComponentType::Ref((component_id, type_id))),
-                        )
-                        .unwrap();
-                        t.set(
-                            ctx,
-                            "mut",
-                            UserData::new_static(&ctx, ComponentType::Mut((component_id, type_id))),
-                        )
-                        .unwrap();
-
-                        break;
-                    }
-                    lua_table = match lua_table.get(ctx, item).unwrap() {
-                        Value::Nil => {
-                            lua_table.set(ctx, item, Table::new(&ctx)).unwrap();
-                            match lua_table.get(ctx, item).unwrap() {
-                                Value::Table(table) => table,
-                                _ => unreachable!(),
-                            }
-                        }
-                        Value::Table(table) => table,
-                        _ => panic!("awa"),
-                    };
-                }
+            let result = lua.try_enter(|ctx| {
+                let t = lua_table_at_path(ctx, type_path)?;
+                t.set(
+                    ctx,
+                    "ref",
+                    UserData::new_static(&ctx, ComponentType::Ref((component_id, type_id))),
+                )?;
+                t.set(
+                    ctx,
+                    "mut",
+                    UserData::new_static(&ctx, ComponentType::Mut((component_id, type_id))),
+                )?;
                 Ok(())
+            });
+            if let Err(err) = result {
+                warn!("couldn't expose resource {type_path} to lua: {err}");
+            }
+        }
+        // short and explicit names, for everything exposed above or by registered functions
+        let lua_naming = world.get_resource::<LuaNaming>().cloned().unwrap_or_default();
+        let registry = registry.read();
+        let exposed = registry
+            .iter()
+            .filter(|item| {
+                let type_path = item.type_info().type_path();
+                lua.enter(|ctx| existing_table(ctx, type_path).is_some())
             })
-            .unwrap();
+            .map(|item| item.type_id())
+            .collect::<Vec<_>>();
+        let mut aliases = naming::aliases(&registry, &lua_naming, exposed)
+            .into_iter()
+            .collect::<Vec<_>>();
+        aliases.sort_by(|(_, a), (_, b)| a.cmp(b));
+        for (type_id, alias) in aliases {
+            let Some(type_path) = registry.get(type_id).map(|item| item.type_info().type_path())
+            else {
+                continue;
+            };
+            let result = lua.try_enter(|ctx| {
+                let t = existing_table(ctx, type_path)
+                    .ok_or_else(|| anyhow!("{type_path} isn't exposed"))?;
+                set_alias(ctx, t, &alias)?;
+                Ok(())
+            });
+            if let Err(err) = result {
+                warn!(
+                    "couldn't expose {type_path} to lua as {}: {err}",
+                    alias.join(".")
+                );
+            }
         }
         world.insert_non_send_resource(lua);
     });
 }
+
+/// Finds or creates the nested global table for a type path, e.g. `bevy_transform.components.transform.Transform`
+pub fn lua_table_at_path<'gc>(
+    ctx: Context<'gc>,
+    type_path: &str,
+) -> Result<Table<'gc>, anyhow::Error> {
+    let mut lua_table = ctx.globals();
+    for item in type_path_segments(type_path) {
+        lua_table = match lua_table.get::<_, Value>(ctx, item)? {
+            Value::Nil => {
+                let table = Table::new(&ctx);
+                lua_table.set(ctx, item, table)?;
+                table
+            }
+            Value::Table(table) => table,
+            other => {
+                return Err(anyhow!(
+                    "{item} in {type_path} is already a {}",
+                    other.type_name()
+                ))
+            }
+        };
+    }
+    Ok(lua_table)
+}
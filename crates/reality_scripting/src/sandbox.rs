@@ -0,0 +1,230 @@
+// Limits on what scripts can access and how much time and memory they can use
+
+use crate::error::{traceback, LuaFailure};
+use anyhow::anyhow;
+use bevy::prelude::*;
+use piccolo::{
+    Callback, CallbackReturn, Context, FromMultiValue, Fuel, Lua, StashedExecutor, Value,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+/// Configures the sandbox scripts run in, insert it after adding `LuaPlugin` to change the defaults
+#[derive(Resource, Clone, Debug)]
+pub struct LuaSandbox {
+    /// Also load `io`, by default scripts only get piccolo's core library
+    pub full_stdlib: bool,
+    /// Globals removed after the stdlib is loaded, e.g. `load` to keep scripts from compiling code
+    pub removed_globals: Vec<String>,
+    /// Fuel each system run, script load or callback can use up, `None` for no limit
+    pub fuel_per_run: Option<i32>,
+    /// Memory the lua vm can use in bytes, `None` for no limit
+    pub memory_limit: Option<usize>,
+    /// What happens to a system that exceeds its budget
+    pub policy: BudgetPolicy,
+    /// Runs the same way every time, e.g. for AO processes: seeds `math.random` with `random_seed` and iterates
+    /// queries in entity order. piccolo's stdlib has no `os` library, so scripts can't read the clock either way
+    pub deterministic: bool,
+    /// The seed `math.random` starts from in deterministic mode, scripts can change it with `math.randomseed`
+    pub random_seed: u64,
+}
+
+impl Default for LuaSandbox {
+    fn default() -> Self {
+        Self {
+            full_stdlib: false,
+            removed_globals: vec![],
+            fuel_per_run: None,
+            memory_limit: None,
+            policy: BudgetPolicy::default(),
+            deterministic: false,
+            random_seed: 0,
+        }
+    }
+}
+
+impl LuaSandbox {
+    /// Limits for running scripts you don't trust, like user made mods
+    pub fn untrusted() -> Self {
+        Self {
+            full_stdlib: false,
+            removed_globals: ["load", "collectgarbage"].map(str::to_string).to_vec(),
+            fuel_per_run: Some(1_000_000),
+            memory_limit: Some(64 * 1024 * 1024),
+            policy: BudgetPolicy::KillScript,
+            deterministic: false,
+            random_seed: 0,
+        }
+    }
+
+    /// For AO processes and replays, where every run has to end up in the same state
+    pub fn deterministic(random_seed: u64) -> Self {
+        Self {
+            deterministic: true,
+            random_seed,
+            ..Self::default()
+        }
+    }
+
+    /// Loads the allowed stdlib into a vm created with `Lua::core()`
+    pub fn apply(&self, lua: &mut Lua) {
+        lua.enter(|ctx| {
+            if self.full_stdlib {
+                piccolo::stdlib::load_io(ctx);
+            }
+            if self.deterministic {
+                seed_random(ctx, self.random_seed);
+            }
+            for global in &self.removed_globals {
+                ctx.set_global(global.as_str(), Value::Nil);
+            }
+        });
+    }
+}
+
+/// Replaces `math.random` and `math.randomseed` with a generator that starts from `seed`.
+/// `math.randomseed()` without a seed goes back to `seed`, instead of picking a random one
+fn seed_random(ctx: Context, seed: u64) {
+    let Ok(Value::Table(math)) = ctx.globals().get::<_, Value>(ctx, "math") else {
+        return;
+    };
+    let state = Rc::new(Cell::new(seed));
+    let random_state = state.clone();
+    let random = Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+        let (m, n): (Option<i64>, Option<i64>) = stack.consume(ctx)?;
+        let bits = split_mix(&random_state);
+        let value = match (m, n) {
+            // like lua, a float in [0, 1), any integer for `random(0)`, or an integer in [1, m] or [m, n]
+            (None, _) => Value::Number((bits >> 11) as f64 / (1u64 << 53) as f64),
+            (Some(0), None) => Value::Integer(bits as i64),
+            (Some(m), n) => {
+                let (low, high) = n.map_or((1, m), |n| (m, n));
+                if low > high {
+                    return Err(anyhow!("bad argument to random, interval is empty").into());
+                }
+                let range = (high as i128 - low as i128 + 1) as u128;
+                Value::Integer((low as i128 + (bits as u128 % range) as i128) as i64)
+            }
+        };
+        stack.replace(ctx, value);
+        Ok(CallbackReturn::Return)
+    });
+    let randomseed = Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+        let value = match stack.consume::<Value>(ctx)? {
+            Value::Nil => seed,
+            Value::Integer(integer) => integer as u64,
+            // integral floats seed like the same integer, others by their bits
+            Value::Number(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => {
+                number as i64 as u64
+            }
+            Value::Number(number) => number.to_bits(),
+            value => {
+                return Err(anyhow!(
+                    "bad argument to randomseed, number expected, got {}",
+                    value.type_name()
+                )
+                .into())
+            }
+        };
+        state.set(value);
+        Ok(CallbackReturn::Return)
+    });
+    let _ = math.set(ctx, "random", random);
+    let _ = math.set(ctx, "randomseed", randomseed);
+}
+
+/// SplitMix64, small and the same on every platform
+fn split_mix(state: &Cell<u64>) -> u64 {
+    let next = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+    state.set(next);
+    let mut z = next;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    /// Stop running the system until the script is reloaded
+    #[default]
+    DisableSystem,
+    /// Stop running all systems of the script until it's reloaded
+    KillScript,
+}
+
+/// A script ran out of fuel or memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetExceeded {
+    Fuel(i32),
+    Memory(usize),
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetExceeded::Fuel(fuel) => write!(f, "used up its fuel budget of {fuel}"),
+            BudgetExceeded::Memory(limit) => write!(f, "exceeded the memory limit of {limit} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// Fuel given to the executor between memory checks and garbage collection
+const FUEL_PER_STEP: i32 = 4096;
+
+/// Like `Lua::finish`, but stops with `BudgetExceeded` once the sandbox's budget is used up
+pub fn finish(lua: &mut Lua, executor: &StashedExecutor, sandbox: &LuaSandbox) -> Result<(), anyhow::Error> {
+    let mut remaining = sandbox.fuel_per_run;
+    loop {
+        let step_fuel = remaining.map_or(FUEL_PER_STEP, |remaining| remaining.min(FUEL_PER_STEP));
+        let mut fuel = Fuel::with(step_fuel);
+        let finished = lua.enter(|ctx| ctx.fetch(executor).step(ctx, &mut fuel));
+        check_memory(lua, sandbox)?;
+        if finished {
+            return Ok(());
+        }
+        if let Some(remaining) = &mut remaining {
+            *remaining -= step_fuel - fuel.remaining();
+            if *remaining <= 0 {
+                return Err(BudgetExceeded::Fuel(sandbox.fuel_per_run.unwrap_or_default()).into());
+            }
+        }
+    }
+}
+
+/// Like `Lua::execute`, within the sandbox's budget
+pub fn execute<R: for<'gc> FromMultiValue<'gc>>(
+    lua: &mut Lua,
+    executor: &StashedExecutor,
+    sandbox: &LuaSandbox,
+) -> Result<R, anyhow::Error> {
+    finish(lua, executor, sandbox)?;
+    lua.enter(|ctx| {
+        let executor = ctx.fetch(executor);
+        match executor.take_result::<R>(ctx) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(err)) => Err(LuaFailure {
+                message: err.to_string(),
+                traceback: traceback(executor),
+            }
+            .into()),
+            Err(err) => Err(anyhow!("{err}")),
+        }
+    })
+}
+
+fn check_memory(lua: &mut Lua, sandbox: &LuaSandbox) -> Result<(), BudgetExceeded> {
+    let Some(limit) = sandbox.memory_limit else {
+        return Ok(());
+    };
+    if lua.total_memory() > limit {
+        // only count what's still reachable
+        lua.gc_collect();
+        if lua.total_memory() > limit {
+            return Err(BudgetExceeded::Memory(limit));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,411 @@
+// Which schedule lua systems run in, their order, and when they're skipped
+
+use crate::reflect::ComponentType;
+use crate::userdata::ValueExt;
+use anyhow::anyhow;
+use bevy::ecs::component::ComponentId;
+use bevy::prelude::*;
+use bevy::reflect::{ReflectFromPtr, ReflectRef};
+use piccolo::{Context, StashedFunction, Table, Value};
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+
+/// Contains the systems running lua systems, so native systems can be ordered around them,
+/// e.g. `my_system.after(LuaSystems)`
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LuaSystems;
+
+/// Contains the lua systems with this `label`, once `add_lua_label` gave it a set of its own,
+/// e.g. `my_system.after(LuaLabel("move".into()))`
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LuaLabel(pub String);
+
+/// The labels given their own `LuaLabel` set, by the schedule they run in
+#[derive(Resource, Default, Debug)]
+pub struct LuaLabelSets(pub HashMap<LuaSchedule, Vec<String>>);
+
+pub trait AppExtensionLuaLabelTrait {
+    /// Runs the lua systems labelled `label` in `schedule` from their own `LuaLabel` set, inside `LuaSystems`.
+    /// `before` and `after` only order lua systems within the same set, order the sets natively
+    fn add_lua_label(&mut self, schedule: LuaSchedule, label: impl Into<String>) -> &mut Self;
+}
+
+impl AppExtensionLuaLabelTrait for App {
+    fn add_lua_label(&mut self, schedule: LuaSchedule, label: impl Into<String>) -> &mut Self {
+        let label = label.into();
+        let schedule = schedule.runner();
+        self.world_mut()
+            .get_resource_or_init::<LuaLabelSets>()
+            .0
+            .entry(schedule)
+            .or_default()
+            .push(label.clone());
+        let set = LuaLabel(label.clone());
+        let runner = move |world: &mut World| crate::run_lua_systems(world, schedule, Some(&label));
+        self.add_systems(schedule.bevy_schedule(), runner.in_set(set).in_set(LuaSystems))
+    }
+}
+
+/// Schedules a lua system can be registered in, with `schedule = "FixedUpdate"`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum LuaSchedule {
+    /// Not bevy's `Startup` schedule, which has already run by the time scripts load:
+    /// runs once in `PreUpdate`, before the other lua systems there, the first frame after the script (re)loads
+    Startup,
+    PreUpdate,
+    #[default]
+    Update,
+    FixedUpdate,
+    PostUpdate,
+}
+
+impl LuaSchedule {
+    const NAMES: [(&'static str, LuaSchedule); 5] = [
+        ("Startup", LuaSchedule::Startup),
+        ("PreUpdate", LuaSchedule::PreUpdate),
+        ("Update", LuaSchedule::Update),
+        ("FixedUpdate", LuaSchedule::FixedUpdate),
+        ("PostUpdate", LuaSchedule::PostUpdate),
+    ];
+
+    pub fn from_name(name: &str) -> Result<Self, anyhow::Error> {
+        Self::NAMES
+            .iter()
+            .find(|(schedule_name, _)| *schedule_name == name)
+            .map(|(_, schedule)| *schedule)
+            .ok_or_else(|| {
+                let names = Self::NAMES.map(|(name, _)| name);
+                anyhow!("unknown schedule {name}, expected one of {}", names.join(", "))
+            })
+    }
+
+    /// The schedule whose runner runs these systems, `Startup` systems run in `PreUpdate`
+    pub fn runner(self) -> Self {
+        match self {
+            LuaSchedule::Startup => LuaSchedule::PreUpdate,
+            schedule => schedule,
+        }
+    }
+
+    pub fn bevy_schedule(self) -> InternedScheduleLabel {
+        match self.runner() {
+            LuaSchedule::Startup | LuaSchedule::PreUpdate => PreUpdate.intern(),
+            LuaSchedule::Update => Update.intern(),
+            LuaSchedule::FixedUpdate => FixedUpdate.intern(),
+            LuaSchedule::PostUpdate => PostUpdate.intern(),
+        }
+    }
+}
+
+/// Has to hold for a lua system to run
+pub enum RunCondition {
+    /// The resource exists
+    Resource(ComponentType),
+    /// The resource, or the state in a `State<S>`, is the enum variant of this name
+    InState(ComponentType, String),
+    /// The function returns a truthy value
+    Function(StashedFunction),
+}
+
+/// The options table passed as the third argument of `app:register_system`
+#[derive(Default)]
+pub struct SystemOptions {
+    pub schedule: LuaSchedule,
+    /// Name other systems refer to in `before` and `after`
+    pub label: Option<String>,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+    pub run_if: Vec<RunCondition>,
+}
+
+impl SystemOptions {
+    pub fn from_table<'gc>(
+        ctx: Context<'gc>,
+        options: Option<Table<'gc>>,
+    ) -> Result<Self, anyhow::Error> {
+        let Some(options) = options else {
+            return Ok(Self::default());
+        };
+        let schedule = match options.get::<_, Option<piccolo::String>>(ctx, "schedule")? {
+            Some(name) => LuaSchedule::from_name(name.to_str()?)?,
+            None => LuaSchedule::default(),
+        };
+        let label = options
+            .get::<_, Option<piccolo::String>>(ctx, "label")?
+            .map(|label| label.to_str().map(str::to_string))
+            .transpose()?;
+        Ok(Self {
+            schedule,
+            label,
+            before: labels(ctx, options.get(ctx, "before")?)?,
+            after: labels(ctx, options.get(ctx, "after")?)?,
+            run_if: run_conditions(ctx, options.get(ctx, "run_if")?)?,
+        })
+    }
+}
+
+/// A single label or a list of them
+fn labels<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Result<Vec<String>, anyhow::Error> {
+    let values = match value {
+        Value::Nil => return Ok(vec![]),
+        Value::Table(table) => table.into_iter().map(|(_, value)| value).collect(),
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| match value {
+            Value::String(label) => Ok(label.to_str()?.to_string()),
+            value => Err(anyhow!("expected a system label, got a {}", value.type_name())),
+        })
+        .collect()
+}
+
+/// A single condition or a list of them
+fn run_conditions<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+) -> Result<Vec<RunCondition>, anyhow::Error> {
+    match value {
+        Value::Nil => Ok(vec![]),
+        // a single `{resource, variant}`
+        Value::Table(table) if matches!(table.get::<_, Value>(ctx, 2)?, Value::String(_)) => {
+            Ok(vec![run_condition(ctx, value)?])
+        }
+        Value::Table(table) => table
+            .into_iter()
+            .map(|(_, value)| run_condition(ctx, value))
+            .collect(),
+        value => Ok(vec![run_condition(ctx, value)?]),
+    }
+}
+
+fn run_condition<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Result<RunCondition, anyhow::Error> {
+    if let Ok(resource) = value.as_static_user_data::<ComponentType>() {
+        return Ok(RunCondition::Resource(*resource));
+    }
+    match value {
+        Value::Function(function) => Ok(RunCondition::Function(ctx.stash(function))),
+        // `{ game.GameState.ref, "Playing" }`
+        Value::Table(table) => {
+            let resource = *table
+                .get::<_, Value>(ctx, 1)?
+                .as_static_user_data::<ComponentType>()?;
+            let variant = table.get::<_, piccolo::String>(ctx, 2)?;
+            Ok(RunCondition::InState(resource, variant.to_str()?.to_string()))
+        }
+        value => Err(anyhow!(
+            "expected a resource, {{resource, variant}} or a function as run condition, got a {}",
+            value.type_name()
+        )),
+    }
+}
+
+impl RunCondition {
+    /// Checks conditions that don't need lua, function conditions pass here and are called by the runner
+    pub fn check_world(&self, world: &World) -> bool {
+        match self {
+            RunCondition::Resource(resource) => {
+                world.get_resource_by_id(resource_ids(resource).0).is_some()
+            }
+            RunCondition::InState(resource, variant) => in_state(world, resource, variant),
+            RunCondition::Function(_) => true,
+        }
+    }
+}
+
+fn resource_ids(resource: &ComponentType) -> (ComponentId, TypeId) {
+    match *resource {
+        ComponentType::Ref(ids)
+        | ComponentType::Mut(ids)
+        | ComponentType::OptionRef(ids)
+        | ComponentType::OptionMut(ids) => ids,
+    }
+}
+
+fn in_state(world: &World, resource: &ComponentType, variant: &str) -> bool {
+    let (component_id, type_id) = resource_ids(resource);
+    let Some(ptr) = world.get_resource_by_id(component_id) else {
+        return false;
+    };
+    let registry = world.resource::<AppTypeRegistry>().read();
+    let Some(reflect_from_ptr) = registry.get_type_data::<ReflectFromPtr>(type_id) else {
+        return false;
+    };
+    let value = unsafe { reflect_from_ptr.as_reflect(ptr) };
+    // `State<S>` wraps the state
+    let value = match value.reflect_ref() {
+        ReflectRef::TupleStruct(tuple_struct) if tuple_struct.field_len() == 1 => {
+            tuple_struct.field(0).unwrap_or(value.as_partial_reflect())
+        }
+        _ => value.as_partial_reflect(),
+    };
+    match value.reflect_ref() {
+        ReflectRef::Enum(state) => state.variant_name() == variant,
+        _ => false,
+    }
+}
+
+/// The result of `sort_by_labels`
+#[derive(Debug, PartialEq)]
+pub struct LabelOrder<T> {
+    pub order: Vec<T>,
+    /// Systems in a cycle of `before` and `after`, or waiting on one. They run last, in registration order
+    /// where the cycle leaves no other choice
+    pub cycle: Vec<T>,
+    /// `before` and `after` labels that no system has, e.g. typos
+    pub unknown_labels: Vec<String>,
+}
+
+/// Orders systems so that `before` and `after` hold, otherwise keeping the order they were registered in
+pub fn sort_by_labels<T: Copy>(systems: &[(T, &SystemOptions)]) -> LabelOrder<T> {
+    let count = systems.len();
+    let labels = systems
+        .iter()
+        .filter_map(|(_, options)| options.label.as_deref())
+        .collect::<HashSet<_>>();
+    let mut unknown_labels = systems
+        .iter()
+        .flat_map(|(_, options)| options.before.iter().chain(&options.after))
+        .filter(|label| !labels.contains(label.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    unknown_labels.sort();
+    unknown_labels.dedup();
+
+    // runs_before[a] contains b if a has to run before b
+    let mut runs_before = vec![vec![]; count];
+    let mut waiting_on = vec![0; count];
+    for (a, (_, options)) in systems.iter().enumerate() {
+        for (b, (_, other)) in systems.iter().enumerate() {
+            let a_before_b = other
+                .label
+                .as_ref()
+                .is_some_and(|label| options.before.contains(label))
+                || options
+                    .label
+                    .as_ref()
+                    .is_some_and(|label| other.after.contains(label));
+            if a != b && a_before_b {
+                runs_before[a].push(b);
+                waiting_on[b] += 1;
+            }
+        }
+    }
+    let mut done = vec![false; count];
+    let mut order = Vec::with_capacity(count);
+    let mut cycle = vec![];
+    loop {
+        let next = match (0..count).find(|&i| !done[i] && waiting_on[i] == 0) {
+            Some(next) => next,
+            None => match (0..count).find(|&i| !done[i]) {
+                Some(next) => {
+                    // everything left waits on a cycle
+                    if cycle.is_empty() {
+                        cycle = (0..count).filter(|&i| !done[i]).map(|i| systems[i].0).collect();
+                    }
+                    next
+                }
+                None => break,
+            },
+        };
+        done[next] = true;
+        for &b in &runs_before[next] {
+            waiting_on[b] -= 1;
+        }
+        order.push(systems[next].0);
+    }
+    LabelOrder {
+        order,
+        cycle,
+        unknown_labels,
+    }
+}
+
+/// Ordering problems already logged, so they're logged once instead of every frame
+#[derive(Resource, Default)]
+pub(crate) struct OrderWarnings(HashSet<String>);
+
+pub(crate) fn warn_once(world: &mut World, message: String) {
+    if world
+        .get_resource_or_init::<OrderWarnings>()
+        .0
+        .insert(message.clone())
+    {
+        warn!("{message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system(label: Option<&str>, before: &[&str], after: &[&str]) -> SystemOptions {
+        SystemOptions {
+            label: label.map(str::to_string),
+            before: before.iter().map(|label| label.to_string()).collect(),
+            after: after.iter().map(|label| label.to_string()).collect(),
+            ..default()
+        }
+    }
+
+    fn sort(systems: &[SystemOptions]) -> LabelOrder<usize> {
+        let systems = systems.iter().enumerate().collect::<Vec<_>>();
+        sort_by_labels(&systems)
+    }
+
+    #[test]
+    fn keeps_registration_order_without_labels() {
+        let order = sort(&[system(None, &[], &[]), system(None, &[], &[]), system(None, &[], &[])]);
+        assert_eq!(order.order, vec![0, 1, 2]);
+        assert!(order.cycle.is_empty());
+        assert!(order.unknown_labels.is_empty());
+    }
+
+    #[test]
+    fn before_and_after_reorder() {
+        let order = sort(&[
+            system(Some("move"), &[], &["input"]),
+            system(Some("render"), &[], &[]),
+            system(Some("input"), &["render"], &[]),
+        ]);
+        assert_eq!(order.order, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn several_systems_can_share_a_label() {
+        let order = sort(&[
+            system(None, &[], &["physics"]),
+            system(Some("physics"), &[], &[]),
+            system(Some("physics"), &[], &[]),
+        ]);
+        assert_eq!(order.order, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn cycles_run_last() {
+        let order = sort(&[
+            system(Some("a"), &[], &["b"]),
+            system(Some("b"), &[], &["a"]),
+            system(Some("c"), &[], &[]),
+            system(Some("d"), &[], &["a"]),
+        ]);
+        assert_eq!(order.order, vec![2, 0, 1, 3]);
+        assert_eq!(order.cycle, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn reports_unknown_labels() {
+        let order = sort(&[
+            system(Some("move"), &["rnder"], &["input"]),
+            system(Some("input"), &[], &["inptu"]),
+        ]);
+        assert_eq!(order.order, vec![1, 0]);
+        assert_eq!(order.unknown_labels, vec!["inptu".to_string(), "rnder".to_string()]);
+    }
+
+    #[test]
+    fn startup_runs_in_pre_update() {
+        assert_eq!(LuaSchedule::Startup.runner(), LuaSchedule::PreUpdate);
+        assert_eq!(LuaSchedule::Update.runner(), LuaSchedule::Update);
+    }
+}
@@ -0,0 +1,287 @@
+// Runs scripts headless, to test them without a window
+
+use crate::asset_loader::LuaScript;
+use crate::error::ScriptError;
+use crate::instance::with_system_context;
+use crate::sandbox::{self, LuaSandbox};
+use crate::{LuaPlugin, LuaVm};
+use anyhow::anyhow;
+use bevy::asset::io::memory::{Dir, MemoryAssetReader};
+use bevy::asset::io::AssetSourceBuilder;
+use bevy::asset::{AssetMetaCheck, LoadState};
+use bevy::ecs::event::EventCursor;
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use piccolo::{Executor, Value};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Asset source holding the scripts loaded with `ScriptTest::load_str`
+pub const MEMORY_SOURCE: &str = "memory";
+
+/// How long `load` waits for a script and the modules it requires
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An app with `MinimalPlugins`, `AssetPlugin` and `LuaPlugin`, stepped by hand.
+/// Every update advances time by `1 / 60` seconds, so scripts see the same `dt` on every run
+pub struct ScriptTest {
+    /// Add plugins, register types and spawn entities before loading scripts
+    pub app: App,
+    dir: Dir,
+    error_cursor: EventCursor<ScriptError>,
+    /// Read after every update, events only last two
+    errors: Vec<ScriptError>,
+}
+
+impl Default for ScriptTest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScriptTest {
+    pub fn new() -> Self {
+        let dir = Dir::default();
+        let mut app = App::new();
+        let reader_dir = dir.clone();
+        // has to come before `AssetPlugin`
+        app.register_asset_source(
+            MEMORY_SOURCE,
+            AssetSourceBuilder::default().with_reader(move || {
+                Box::new(MemoryAssetReader {
+                    root: reader_dir.clone(),
+                })
+            }),
+        );
+        app.add_plugins(MinimalPlugins)
+            .add_plugins(AssetPlugin {
+                meta_check: AssetMetaCheck::Never,
+                ..default()
+            })
+            .add_plugins(LuaPlugin)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                1.0 / 60.0,
+            )));
+        Self {
+            app,
+            dir,
+            error_cursor: EventCursor::default(),
+            errors: vec![],
+        }
+    }
+
+    /// Loads a script from `source`, as `memory://{name}`. Modules it requires are looked up next to it,
+    /// add them with `add_module`
+    pub fn load_str(
+        &mut self,
+        name: &str,
+        source: &str,
+    ) -> Result<Handle<LuaScript>, anyhow::Error> {
+        self.add_module(name, source);
+        self.load(format!("{MEMORY_SOURCE}://{name}"))
+    }
+
+    /// Adds a file to the `memory://` source without loading it, e.g. a module scripts require
+    pub fn add_module(&mut self, name: &str, source: &str) {
+        self.dir.insert_asset_text(Path::new(name), source);
+    }
+
+    /// Loads a script through the asset server, e.g. from the `assets` folder, and waits until it ran
+    pub fn load(&mut self, path: impl Into<String>) -> Result<Handle<LuaScript>, anyhow::Error> {
+        let path = path.into();
+        let handle = self
+            .app
+            .world()
+            .resource::<AssetServer>()
+            .load::<LuaScript>(path.clone());
+        let started = Instant::now();
+        loop {
+            self.update();
+            match self.app.world().resource::<AssetServer>().load_state(&handle) {
+                LoadState::Loaded => return Ok(handle),
+                LoadState::Failed(err) => return Err(anyhow!("{path} failed to load: {err}")),
+                LoadState::NotLoaded | LoadState::Loading => {}
+            }
+            if started.elapsed() > LOAD_TIMEOUT {
+                return Err(anyhow!("{path} didn't load within {LOAD_TIMEOUT:?}"));
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    pub fn update(&mut self) {
+        self.app.update();
+        let events = self.app.world().resource::<Events<ScriptError>>();
+        self.errors.extend(self.error_cursor.read(events).cloned());
+    }
+
+    /// Runs `updates` frames
+    pub fn run(&mut self, updates: usize) {
+        for _ in 0..updates {
+            self.update();
+        }
+    }
+
+    pub fn world(&self) -> &World {
+        self.app.world()
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        self.app.world_mut()
+    }
+
+    /// The entity's component, panicking if it doesn't have one
+    #[track_caller]
+    pub fn component<C: Component>(&self, entity: Entity) -> &C {
+        self.world().get::<C>(entity).unwrap_or_else(|| {
+            panic!("{entity} has no {}", std::any::type_name::<C>())
+        })
+    }
+
+    /// The entities with a `C`, in spawn order
+    pub fn entities_with<C: Component>(&mut self) -> Vec<Entity> {
+        let world = self.world_mut();
+        let mut entities = world
+            .query_filtered::<Entity, With<C>>()
+            .iter(world)
+            .collect::<Vec<_>>();
+        entities.sort();
+        entities
+    }
+
+    /// Script errors reported since the last call
+    pub fn errors(&mut self) -> Vec<ScriptError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Panics with the script errors reported since the last call, if there are any
+    #[track_caller]
+    pub fn assert_no_errors(&mut self) {
+        let errors = self.errors();
+        if !errors.is_empty() {
+            let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+            panic!("scripts reported errors:\n{}", errors.join("\n"));
+        }
+    }
+
+    /// Calls the script's global `test_*` functions in name order, each with entity handles and
+    /// `Commands` usable like in a system. Commands are applied and an update runs after each test.
+    /// Print the report to see the results
+    pub fn run_tests(&mut self, script: &Handle<LuaScript>) -> TestReport {
+        let names = self.test_names(script);
+        let mut report = TestReport::default();
+        for name in names {
+            let result = self.run_test(script, &name);
+            self.update();
+            report.results.push((name, result.map_err(|err| err.to_string())));
+        }
+        report
+    }
+
+    fn test_names(&mut self, script: &Handle<LuaScript>) -> Vec<String> {
+        let world = self.app.world_mut();
+        let Some(env) = world
+            .resource::<Assets<LuaScript>>()
+            .get(script)
+            .and_then(|lua_script| (*lua_script.env).clone())
+        else {
+            return vec![];
+        };
+        let mut lua = world.non_send_resource_mut::<LuaVm>();
+        let mut names = lua.enter(|ctx| {
+            ctx.fetch(&env)
+                .iter()
+                .filter_map(|(key, value)| match (key, value) {
+                    (Value::String(key), Value::Function(_)) => {
+                        let key = key.to_str().ok()?;
+                        key.starts_with("test_").then(|| key.to_string())
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        });
+        names.sort();
+        names
+    }
+
+    fn run_test(&mut self, script: &Handle<LuaScript>, name: &str) -> Result<(), anyhow::Error> {
+        let world = self.app.world_mut();
+        let env = world
+            .resource::<Assets<LuaScript>>()
+            .get(script)
+            .and_then(|lua_script| (*lua_script.env).clone())
+            .ok_or_else(|| anyhow!("the script isn't loaded"))?;
+        let sandbox = world.get_resource::<LuaSandbox>().cloned().unwrap_or_default();
+        let mut lua = world
+            .remove_non_send_resource::<LuaVm>()
+            .ok_or_else(|| anyhow!("no lua vm"))?;
+        let result = with_system_context(world, &mut lua, |_world, lua| {
+            let exec = lua
+                .try_enter(|ctx| {
+                    let Value::Function(function) = ctx.fetch(&env).get::<_, Value>(ctx, name)?
+                    else {
+                        return Err(anyhow!("{name} isn't a function").into());
+                    };
+                    Ok(ctx.stash(Executor::start(ctx, function, ())))
+                })
+                .map_err(|err| anyhow!("{err}"))?;
+            sandbox::execute::<()>(lua, &exec, &sandbox)
+        });
+        world.insert_non_send_resource(lua);
+        result
+    }
+}
+
+/// The outcome of `ScriptTest::run_tests`, formatted like `cargo test`'s output
+#[derive(Default, Debug, Clone)]
+pub struct TestReport {
+    /// Test names and their errors
+    pub results: Vec<(String, Result<(), String>)>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|(_, result)| result.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    /// Panics with the report if a test failed
+    #[track_caller]
+    pub fn assert_passed(&self) {
+        if self.failed() > 0 {
+            panic!("{self}");
+        }
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "running {} tests", self.results.len())?;
+        for (name, result) in &self.results {
+            let outcome = if result.is_ok() { "ok" } else { "FAILED" };
+            writeln!(f, "test {name} ... {outcome}")?;
+        }
+        let failures = self
+            .results
+            .iter()
+            .filter_map(|(name, result)| Some((name, result.as_ref().err()?)))
+            .collect::<Vec<_>>();
+        if !failures.is_empty() {
+            writeln!(f, "\nfailures:\n")?;
+            for (name, err) in &failures {
+                writeln!(f, "---- {name} ----\n{err}\n")?;
+            }
+        }
+        let status = if failures.is_empty() { "ok" } else { "FAILED" };
+        write!(
+            f,
+            "\ntest result: {status}. {} passed; {} failed",
+            self.passed(),
+            self.failed()
+        )
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::anyhow;
 use piccolo::{
     Callback, CallbackReturn, Context, FromValue, Table, TypeError, UserData, Value,
 };
@@ -42,8 +43,8 @@ where
                 "__index",
                 Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
                     let (this, key): (&Self, Value) = stack.consume(ctx)?;
-                    let s = key.into_string(ctx).unwrap().to_str().unwrap();
-                    stack.push_front(this.lua_index(&ctx, s));
+                    let key = lua_key(ctx, key)?;
+                    stack.push_front(this.lua_index(&ctx, key.to_str()?)?);
 
                     Ok(CallbackReturn::Return)
                 }),
@@ -56,8 +57,8 @@ where
                 "__newindex",
                 Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
                     let (this, key, new_value): (&Self, Value, Value) = stack.consume(ctx)?;
-                    let s = key.into_string(ctx).unwrap().to_str().unwrap();
-                    this.lua_new_index(&ctx, s, new_value);
+                    let key = lua_key(ctx, key)?;
+                    this.lua_new_index(&ctx, key.to_str()?, new_value)?;
 
                     Ok(CallbackReturn::Return)
                 }),
@@ -73,15 +74,27 @@ where
 
     fn lua_to_string(&self) -> String;
 
-    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Value<'gc>;
+    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Result<Value<'gc>, anyhow::Error>;
 
-    fn lua_new_index<'gc>(&self, ctx: &Context<'gc>, key: &str, new_value: Value<'gc>);
+    fn lua_new_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: &str,
+        new_value: Value<'gc>,
+    ) -> Result<(), anyhow::Error>;
 
     fn from_value_2<'gc>(_ctx: Context<'gc>, value: Value<'gc>) -> Result<&'gc Self, TypeError> {
         value.as_static_user_data::<Self>()
     }
 }
 
+/// Keys are passed on as strings, numbers included so that `list[1]` works
+fn lua_key<'gc>(ctx: Context<'gc>, key: Value<'gc>) -> Result<piccolo::String<'gc>, anyhow::Error> {
+    let type_name = key.type_name();
+    key.into_string(ctx)
+        .ok_or_else(|| anyhow!("can't index with a {type_name}"))
+}
+
 pub trait ValueExt<'gc> {
     /// Convert to a static user data type.
     fn as_static_user_data<T: 'static>(&self) -> Result<&'gc T, piccolo::TypeError>;
@@ -0,0 +1,68 @@
+use reality_scripting::persist::{LuaData, ScriptState};
+use reality_scripting::testing::ScriptTest;
+use std::collections::BTreeMap;
+
+const PROGRESS: &str = r#"
+progress = app:persist("progress", { gold = 1 })
+
+function test_gold_was_restored()
+    assert(progress.gold == 5, "gold is " .. tostring(progress.gold))
+end
+"#;
+
+fn gold(gold: i64) -> ScriptState {
+    ScriptState {
+        tables: BTreeMap::from([(
+            "progress".to_string(),
+            LuaData::Table(vec![(LuaData::String("gold".to_string()), LuaData::Integer(gold))]),
+        )]),
+    }
+}
+
+#[test]
+fn captures_persisted_tables_every_update() {
+    let mut test = ScriptTest::new();
+    test.load_str("progress.lua", PROGRESS).unwrap();
+    test.update();
+    test.assert_no_errors();
+    assert_eq!(*test.world().resource::<ScriptState>(), gold(1));
+}
+
+#[test]
+fn restores_tables_when_the_state_is_replaced() {
+    let mut test = ScriptTest::new();
+    let script = test.load_str("progress.lua", PROGRESS).unwrap();
+    // e.g. loading a world snapshot
+    test.world_mut().insert_resource(gold(5));
+    test.update();
+    assert_eq!(*test.world().resource::<ScriptState>(), gold(5));
+    test.run_tests(&script).assert_passed();
+}
+
+#[test]
+fn scripts_loading_later_get_the_saved_data() {
+    let mut test = ScriptTest::new();
+    test.world_mut().insert_resource(gold(5));
+    test.update();
+    let script = test.load_str("progress.lua", PROGRESS).unwrap();
+    test.run_tests(&script).assert_passed();
+}
+
+#[test]
+fn refuses_cycles() {
+    let mut test = ScriptTest::new();
+    test.load_str("cycle.lua", r#"local t = app:persist("cycle", {}) t.self = t"#)
+        .unwrap();
+    let err = ScriptState::capture(test.world_mut()).unwrap_err();
+    assert!(err.to_string().contains("cycle.self contains itself"), "{err}");
+}
+
+#[test]
+fn refuses_values_other_than_plain_data() {
+    let mut test = ScriptTest::new();
+    test.load_str("function.lua", r#"app:persist("callbacks", { on_save = print })"#)
+        .unwrap();
+    let err = ScriptState::capture(test.world_mut()).unwrap_err();
+    assert!(err.to_string().contains("callbacks.on_save"), "{err}");
+    assert!(err.to_string().contains("only plain data can be saved"), "{err}");
+}
@@ -0,0 +1,51 @@
+use reality_scripting::sandbox::LuaSandbox;
+use reality_scripting::testing::ScriptTest;
+
+const RANDOM: &str = r#"
+function test_integer_seeds_repeat()
+    math.randomseed(7)
+    local first = math.random(1, 1000000)
+    math.randomseed(7)
+    assert(math.random(1, 1000000) == first)
+end
+
+function test_float_seeds()
+    math.randomseed(1.5)
+    local first = math.random()
+    math.randomseed(1.5)
+    assert(math.random() == first)
+    math.randomseed(2.0)
+    local float = math.random()
+    math.randomseed(2)
+    assert(math.random() == float, "2.0 should seed like 2")
+end
+
+function test_no_seed_goes_back_to_the_sandbox_seed()
+    math.randomseed()
+    local first = math.random()
+    math.random()
+    math.randomseed()
+    assert(math.random() == first)
+end
+
+function test_zero_gives_any_integer()
+    math.randomseed(3)
+    local first = math.random(0)
+    assert(first % 1 == 0)
+    assert(math.random(0) ~= first)
+    math.randomseed(3)
+    assert(math.random(0) == first)
+end
+
+function test_other_seeds_are_refused()
+    assert(not pcall(math.randomseed, "seed"))
+end
+"#;
+
+#[test]
+fn deterministic_random_seeds() {
+    let mut test = ScriptTest::new();
+    test.app.insert_resource(LuaSandbox::deterministic(42));
+    let script = test.load_str("random.lua", RANDOM).unwrap();
+    test.run_tests(&script).assert_passed();
+}
@@ -0,0 +1,109 @@
+use bevy::prelude::*;
+use reality_scripting::testing::ScriptTest;
+use reality_scripting::BluaScript;
+
+const MOVE: &str = r#"
+function on_update(dt)
+    local transform = self:get(Transform.mut)
+    transform.translation = transform.translation + Vec3.new(dt, 0, 0)
+end
+"#;
+
+const TESTS: &str = r#"
+function test_arithmetic()
+    assert(1 + 1 == 2)
+end
+
+function test_failing()
+    assert(false, "expected to fail")
+end
+
+function helper()
+    error("not a test")
+end
+"#;
+
+fn script_test() -> ScriptTest {
+    let mut test = ScriptTest::new();
+    test.app.register_type::<Transform>();
+    test
+}
+
+#[test]
+fn behaviour_scripts_update_their_entity() {
+    let mut test = script_test();
+    let entity = test.world_mut().spawn(Transform::default()).id();
+    let script = test.load_str("move.lua", MOVE).unwrap();
+    test.world_mut().entity_mut(entity).insert(BluaScript(script));
+    test.run(60);
+    test.assert_no_errors();
+    let x = test.component::<Transform>(entity).translation.x;
+    assert!((0.9..=1.1).contains(&x), "expected about a unit of movement, got {x}");
+}
+
+#[test]
+fn run_tests_reports_each_test_function() {
+    let mut test = script_test();
+    let script = test.load_str("tests.lua", TESTS).unwrap();
+    let report = test.run_tests(&script);
+    let names = report
+        .results
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["test_arithmetic", "test_failing"]);
+    assert_eq!((report.passed(), report.failed()), (1, 1));
+    let err = report.results[1].1.as_ref().unwrap_err();
+    assert!(err.contains("expected to fail"), "{err}");
+    assert!(report.to_string().contains("test test_failing ... FAILED"));
+}
+
+#[test]
+#[should_panic(expected = "test result: FAILED")]
+fn assert_passed_panics_on_failures() {
+    let mut test = script_test();
+    let script = test.load_str("tests.lua", TESTS).unwrap();
+    test.run_tests(&script).assert_passed();
+}
+
+#[test]
+fn script_errors_are_collected() {
+    let mut test = script_test();
+    let entity = test.world_mut().spawn_empty().id();
+    let script = test
+        .load_str("broken.lua", "function on_update(dt) error('broken') end")
+        .unwrap();
+    test.world_mut().entity_mut(entity).insert(BluaScript(script));
+    test.run(2);
+    let errors = test.errors();
+    assert!(!errors.is_empty());
+    assert!(errors[0].to_string().contains("broken"), "{}", errors[0]);
+    assert!(test.errors().is_empty());
+}
+
+#[test]
+fn failing_instances_report_once() {
+    let mut test = script_test();
+    let script = test
+        .load_str(
+            "spawn_fails.lua",
+            "function on_spawn() error('no spawn') end\nfunction on_update(dt) error('updated') end",
+        )
+        .unwrap();
+    let entity = test.world_mut().spawn_empty().id();
+    test.world_mut().entity_mut(entity).insert(BluaScript(script));
+    test.run(5);
+    let errors = test.errors();
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    assert!(errors[0].to_string().contains("no spawn"), "{}", errors[0]);
+
+    // the script runs on its own first, with `self` unset, then once per instance
+    let script = test
+        .load_str("instance_fails.lua", "if self then error('no instance') end")
+        .unwrap();
+    test.assert_no_errors();
+    let entity = test.world_mut().spawn_empty().id();
+    test.world_mut().entity_mut(entity).insert(BluaScript(script));
+    test.run(5);
+    assert_eq!(test.errors().len(), 1);
+}
@@ -1,5 +1,8 @@
 use bevy::prelude::*;
-use ao_module::ArweaveTimestamp;
+use ao_module::{ArweaveTimestamp, Message};
+use reality_player_interface::{GameAction, GameInputEvent, GameInputRequest, InputEventType, PlayerId};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 
 // Note 1: Unsafe access to static mut is allowed because there are no other threads
 static mut APP: Option<App> = None;
@@ -8,6 +11,13 @@ static mut APP: Option<App> = None;
 #[allow(dead_code)] // This will be used by libraries
 struct RealityTime(ArweaveTimestamp);
 
+// The payload of a message carrying a player's input
+#[derive(Deserialize)]
+struct InputPayload<GA> {
+    action: GA,
+    event_type: InputEventType,
+}
+
 // Returns `true` if a new app was created
 pub fn server_create(build_fn: impl FnOnce(&mut App), recreate: bool) -> bool {
     // Clean up old app if it exists
@@ -60,3 +70,20 @@ pub fn server_tick(ts: ArweaveTimestamp) -> bool {
 
     true
 }
+
+// Queues the input in the message's payload as a `GameInputRequest` from its sender,
+// to be checked by the `ActionValidationPlugin` on the next tick
+// Returns `Ok(false)` if there is no app, or it doesn't accept `GameInputRequest<GA>`s
+pub fn server_input<GA>(msg: &Message) -> Result<bool, serde_json::Error> where GA: GameAction + DeserializeOwned {
+    let payload: InputPayload<GA> = serde_json::from_str(&msg.payload)?;
+
+    let Some(app) = (unsafe {
+        #[allow(static_mut_refs)] // See Note 1
+        APP.as_mut()
+    }) else {
+        return Ok(false);
+    };
+
+    let input = GameInputEvent::new(PlayerId::from_address(&msg.sender), payload.action, payload.event_type);
+    Ok(app.world_mut().send_event(GameInputRequest::new(input)).is_some())
+}
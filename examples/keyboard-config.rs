@@ -4,10 +4,13 @@ use reality_core::game_tick::RealityGameTick;
 use reality_kit::bevy::prelude::*;
 use reality_kit::core::game_tick::RealityGameTickPlugin;
 use reality_kit::player_interface::{
-    ActionDescriptor, EventDescriptor, GameInputEvent, InputEventType, KeyboardConfig,
-    PlayerInterfaceManifest, RealityInputPlugin,
+    ActionDescriptor, ActionValidationPlugin, EventDescriptor, GameInputEvent, InputEventType,
+    InputRateLimiter, KeyboardConfig, LatestTextFrame, PlayerInterfaceManifest, RealityInputPlugin,
+    TextFrameGlyph, TextFramePlugin,
+};
+use reality_player_interface::{
+    GameUpdateEventTimed, PlayerInterfaceGameUpdate, RoutedGameUpdate, UpdateInfo,
 };
-use reality_player_interface::{GameUpdateEventTimed, PlayerInterfaceGameUpdate, UpdateInfo};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Event)]
@@ -25,7 +28,7 @@ fn get_all_events() -> Vec<EventDescriptor<MyGameEvents>> {
     ]
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 enum MyGameActions {
     MoveUp,
     MoveDown,
@@ -73,7 +76,12 @@ fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
         .add_plugins(RealityGameTickPlugin::default())
-        .add_plugins(RealityInputPlugin { keyboard_config })
+        .add_plugins(RealityInputPlugin {
+            keyboard_configs: vec![keyboard_config],
+        })
+        // Validates `GameInputRequest`s sent by agents against the manifest
+        .add_plugins(ActionValidationPlugin { manifest })
+        .add_plugins(TextFramePlugin::default())
         .add_event::<MyGameEvents>()
         .add_systems(Startup, setup)
         .add_systems(
@@ -102,6 +110,7 @@ fn setup(
         Camera3d { ..default() },
         Transform::from_xyz(5.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
         RotationState::None,
+        TextFrameGlyph::new('C').layer(1),
     ));
 
     // Light
@@ -124,6 +133,7 @@ fn setup(
         Mesh3d(meshes.add(Cuboid::new(2.0, 2.0, 2.0))),
         MeshMaterial3d(materials.add(Color::srgb(0.9, 0.2, 0.1))),
         Transform::from_xyz(0.0, 0.0, 0.0),
+        TextFrameGlyph::new('#'),
     ));
 }
 
@@ -171,7 +181,13 @@ fn rotate_camera(
     transform.look_at(Vec3::ZERO, Vec3::Y);
 }
 
-fn print_player_updates(mut evr_gie: EventReader<MyGameEvents>, rgt: Res<RealityGameTick>) {
+fn print_player_updates(
+    mut evr_gie: EventReader<MyGameEvents>,
+    rgt: Res<RealityGameTick>,
+    text_frame: Res<LatestTextFrame>,
+    mut rate_limiter: ResMut<InputRateLimiter<MyGameActions>>,
+    mut evw_updates: EventWriter<RoutedGameUpdate<MyGameEvents, MyGameActions>>,
+) {
     for ev in evr_gie.read() {
         // println!("MyGameEvent: {ev:?}");
 
@@ -197,10 +213,20 @@ fn print_player_updates(mut evr_gie: EventReader<MyGameEvents>, rgt: Res<Reality
                 ),
                 hint_text,
                 state_text: None,
-                media_refs: None,
+                // A text rendering of the scene, for agents without a GPU
+                media_refs: text_frame
+                    .0
+                    .as_ref()
+                    .map(|frame| vec![frame.to_media_ref(rgt.tick)]),
             };
+        // There is a single player, so broadcast the update
+        let mut update = RoutedGameUpdate::all(update);
+        // Let agents know if any of their actions were dropped for exceeding the tick rate
+        rate_limiter.annotate_update(&mut update);
 
         // These events should be sent from the client process to subscribing agent
         println!("{update:#?}");
+        // Also keeps the `ActionValidator` in sync with `actions_current`
+        evw_updates.send(update);
     }
 }
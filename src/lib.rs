@@ -4,7 +4,10 @@ pub use reality_core as core;
 #[cfg(feature = "server_ao")]
 pub use reality_server_ao as server;
 
-#[cfg(any(feature = "client_ao", feature = "client_web", feature = "client_local"))]
+#[cfg(feature = "scripting")]
+pub use reality_scripting as scripting;
+
+#[cfg(any(feature = "server_ao", feature = "client_ao", feature = "client_web", feature = "client_local"))]
 pub use reality_player_interface as player_interface;
 
 #[cfg(feature = "client_ao")]